@@ -7,6 +7,8 @@ if [ $? -eq 0 ]; then
   echo "[build.sh] Compile successful. Copying project to the /bin directory."
   cp -r ./target/release/mpvy /bin
   echo "[build.sh] Binary copied to the /bin directory."
+  ln -sf /bin/mpvy /bin/mpvyd # 'mpvyd' starts the daemon
+  echo "[build.sh] 'mpvyd' linked to 'mpvy' for starting the daemon."
   echo "[build.sh] You can run mpvy with 'mpvy' command."
 else
   echo "[build.sh] An error occured. Exiting with code 1."
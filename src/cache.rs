@@ -0,0 +1,428 @@
+use crate::config::Program;
+use crate::log::{self, *};
+use crate::lyrics::Line;
+use crate::runner::CommandRunner;
+use crate::sponsorblock;
+use crate::state::write_atomic;
+use crate::time::parse_duration;
+use crate::yt_dlp::{get_chapters_path, get_download_path};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::process::Command;
+use std::sync::Mutex;
+
+/// Name of the cache index file in the mp3 directory.
+pub const INDEX_FILE: &str = "index.json";
+
+/// Name of the staging directory in the mp3 directory. Downloads are written there and moved
+/// To the cache when they are finished, so the cache never has partial files.
+pub const STAGING_DIR: &str = ".staging";
+
+/// Suffixes of files which are left in the mp3 directory by interrupted downloads of older
+/// Versions (which downloaded into the cache directly), refer to `is_partial_download`.
+/// Complete audios in other formats (like `.m4a`) are kept, they may be added by user.
+const PARTIAL_DOWNLOADS: [&str; 2] = [".part", ".ytdl"];
+
+/// A cached audio which is shorter than this part of its video duration is truncated.
+const MIN_DURATION_RATIO: f64 = 0.9;
+
+/// Information about a cached audio which is stored in the cache index.
+/// Values:
+///
+/// ```txt
+/// title:        Video title, audio files are named by video ID (refer to `audio_path`)
+/// channel:      Video channel (uploader)
+/// duration:     Video duration in this format: HH:MM:SS
+/// loudness:     Measured integrated loudness in LUFS, the gain is calculated from it
+///               With the `normalize_target` at play time (refer to `src/normalize.rs`)
+/// lyrics:       Synced lyrics, empty if no provider has lyrics (refer to `src/lyrics.rs`)
+/// verified:     Size of the audio file when it is verified, so it is not probed again (refer to `verify`)
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loudness: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lyrics: Option<Vec<Line>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verified: Option<u64>,
+}
+
+/// The cache index, video IDs to entries.
+pub type Index = BTreeMap<String, Entry>;
+
+/// Read-modify-write of the index is done while holding this lock.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Returns the path of cache index (`$XDG_CONFIG_HOME/mpvy/mp3/index.json`)
+pub fn index_path() -> String {
+    format!("{}/{}", get_download_path(), INDEX_FILE)
+}
+
+/// Returns `true` for cached audios (`.mp3` files) in the mp3 directory. Other files (like the
+/// Index and `.lrc` lyrics) are not counted or deleted by eviction.
+pub fn is_audio_file(name: &str) -> bool {
+    name.ends_with(".mp3")
+}
+
+/// Returns `true` if the file is left by an interrupted download: it ends with one of
+/// `PARTIAL_DOWNLOADS`, or it is a half-converted audio (`<name>.temp.<ext>`) or a fragment
+/// (`<name>-Frag<N>`).
+fn is_partial_download(name: &str) -> bool {
+    let fragment: bool = name.rsplit_once("-Frag").is_some_and(|(_, number)| {
+        !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())
+    });
+    let converting: bool = name
+        .rsplit_once('.')
+        .is_some_and(|(stem, _)| stem.ends_with(".temp"));
+    fragment || converting || PARTIAL_DOWNLOADS.iter().any(|part| name.ends_with(part))
+}
+
+/// Returns the path of cached audio of the video (`$XDG_CONFIG_HOME/mpvy/mp3/<id>.mp3`).
+/// Files are named by ID only, because titles can have characters which are not safe
+/// In file names (and `yt-dlp` sanitises them in its own way).
+pub fn audio_path(id: &str) -> String {
+    format!("{}/{}.mp3", get_download_path(), id)
+}
+
+/// Returns the staging directory of this process (`$XDG_CONFIG_HOME/mpvy/mp3/.staging/<pid>`).
+/// Every process has its own, so the daemon and a foreground **mpvy** don't remove
+/// Downloads of each other.
+pub fn staging_path() -> String {
+    format!(
+        "{}/{}/{}",
+        get_download_path(),
+        STAGING_DIR,
+        std::process::id()
+    )
+}
+
+/// Move the downloaded audio of the video from the staging directory to the cache.
+/// The rename is atomic, so a cached audio is always complete. Other files of the download
+/// (like the thumbnail) are removed.
+pub fn commit(id: &str) -> Result<(), String> {
+    let staged: String = format!("{}/{}.mp3", staging_path(), id);
+    let result = fs::rename(&staged, audio_path(id))
+        .map_err(|e| format!("Unable to move downloaded audio '{}': {}", staged, e));
+    if let Ok(entries) = fs::read_dir(staging_path()) {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(id) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+    result
+}
+
+/// Remove the staging directory of this process, like when **mpvy** exits while downloading.
+pub fn remove_staging() {
+    let path: String = staging_path();
+    if fs::exists(&path).unwrap_or(false) {
+        info(
+            "Cache Staging",
+            &format!("Removing unfinished downloads in '{}'.", path),
+        );
+        let _ = fs::remove_dir_all(&path);
+    }
+    // Removed only if it is empty (another process may be downloading)
+    let _ = fs::remove_dir(format!("{}/{}", get_download_path(), STAGING_DIR));
+}
+
+/// Remove staging directories of processes which are not running anymore (like killed ones),
+/// And files of interrupted downloads in the mp3 directory which are left by older versions.
+pub fn clean_staging() {
+    let staging: String = format!("{}/{}", get_download_path(), STAGING_DIR);
+    if let Ok(entries) = fs::read_dir(&staging) {
+        for entry in entries.flatten() {
+            let running: bool = entry
+                .file_name()
+                .to_string_lossy()
+                .parse::<libc::pid_t>()
+                .is_ok_and(|pid| unsafe { libc::kill(pid, 0) } == 0);
+            if !running {
+                info(
+                    "Cache Staging",
+                    &format!(
+                        "Removing unfinished downloads in '{}'.",
+                        entry.path().display()
+                    ),
+                );
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+        let _ = fs::remove_dir(&staging);
+    }
+    let Ok(entries) = fs::read_dir(get_download_path()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name: String = entry.file_name().to_string_lossy().to_string();
+        if is_partial_download(&name) && entry.path().is_file() {
+            info(
+                "Cache Staging",
+                &format!("Removing partial download '{}'.", name),
+            );
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Probe the duration of the audio file with `ffprobe`. Returns `None` if the file can't be
+/// Decoded, and an error if `ffprobe` can't be run.
+fn probe(runner: &dyn CommandRunner, path: &str) -> Result<Option<f64>, String> {
+    let mut command: Command = Program::Ffprobe.command();
+    command
+        .arg("-v")
+        .arg("error") // Only print errors
+        .arg("-show_entries")
+        .arg("format=duration")
+        .arg("-of")
+        .arg("default=noprint_wrappers=1:nokey=1") // Print only the value
+        .arg(path)
+        .args(Program::Ffprobe.extra_args()); // Extra arguments from configuration
+    log::command("Cache Probe", &command);
+    let output = runner
+        .output(&mut command)
+        .map_err(|e| format!("Unable to execute 'ffprobe': {}", e))?;
+    if !output.status.success() {
+        log::command_exit(
+            "Cache Probe",
+            Some(output.status),
+            &String::from_utf8_lossy(&output.stderr),
+        );
+        return Ok(None);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|duration| *duration > 0.0))
+}
+
+/// Check cached audios with `ffprobe` and remove the corrupt ones (which can't be decoded, or
+/// Are much shorter than their videos), so they are downloaded again when they are played.
+/// Verified files are not probed again until their size changes. If `ffprobe` can't be run,
+/// Nothing is removed.
+pub fn verify(runner: &dyn CommandRunner) {
+    let Ok(entries) = fs::read_dir(get_download_path()) else {
+        return;
+    };
+    let index: Index = read();
+    // Audios are cut by SponsorBlock, so they are shorter than their videos
+    let cut: bool = !sponsorblock::options().remove.is_empty();
+    for entry in entries.flatten() {
+        let name: String = entry.file_name().to_string_lossy().to_string();
+        let Some(id) = name.strip_suffix(".mp3") else {
+            continue;
+        };
+        let size: u64 = entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        let known: Option<&Entry> = index.get(id);
+        if known.and_then(|entry| entry.verified) == Some(size) {
+            continue;
+        }
+        let duration: Option<f64> = match probe(runner, &audio_path(id)) {
+            Ok(duration) => duration,
+            Err(e) => {
+                warning(
+                    "Cache Verify",
+                    &format!("Unable to verify cached audios: {}", e),
+                );
+                return;
+            }
+        };
+        let expected: u64 = known
+            .and_then(|entry| entry.duration.as_deref())
+            .map(parse_duration)
+            .unwrap_or(0);
+        let valid: bool = duration.is_some_and(|duration| {
+            cut || expected == 0 || duration >= expected as f64 * MIN_DURATION_RATIO
+        });
+        if valid {
+            update(id, |entry| entry.verified = Some(size));
+            continue;
+        }
+        warning(
+            "Cache Verify",
+            &format!(
+                "Cached audio '{}' is corrupt (duration {:?}, expected {} seconds). Removing it, it is downloaded again when it is played.",
+                name, duration, expected
+            ),
+        );
+        let _ = fs::remove_file(entry.path());
+        let _ = fs::remove_dir_all(get_chapters_path(id));
+        remove(&[id]);
+    }
+}
+
+/// Get the video ID from name of a cached audio file (`<id>.mp3`).
+pub fn id_from_file(name: &str) -> Option<&str> {
+    name.rsplit_once('.').map(|(id, _)| id)
+}
+
+/// Split an old cached audio file name (`<title>_<id>.mp3`) into title and ID.
+/// YouTube video IDs are 11 characters of letters, digits, `-` and `_`.
+fn split_old_name(name: &str) -> Option<(&str, &str)> {
+    let stem: &str = name.strip_suffix(".mp3")?;
+    let split: usize = stem.len().checked_sub(12)?;
+    if !stem.is_char_boundary(split) || stem.as_bytes()[split] != b'_' {
+        return None;
+    }
+    let (title, id) = (&stem[..split], &stem[split + 1..]);
+    let valid: bool = id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    (valid && !title.is_empty()).then_some((title, id))
+}
+
+/// Rename cached audios from the old `<title>_<id>.mp3` names to `<id>.mp3`.
+/// Titles are kept in the cache index. Files which are already renamed are not touched,
+/// So it does nothing after the first run.
+pub fn migrate() {
+    let Ok(entries) = fs::read_dir(get_download_path()) else {
+        return;
+    };
+    let names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    for name in names {
+        let Some((title, id)) = split_old_name(&name) else {
+            continue;
+        };
+        let old: String = format!("{}/{}", get_download_path(), name);
+        let new: String = audio_path(id);
+        info(
+            "Cache Migrate",
+            &format!("Renaming '{}' to '{}.mp3'.", name, id),
+        );
+        let result = if fs::exists(&new).unwrap_or(false) {
+            fs::remove_file(&old) // Already downloaded with the new name
+        } else {
+            fs::rename(&old, &new)
+        };
+        match result {
+            Ok(_) => update(id, |entry| {
+                entry.title.get_or_insert(title.to_string());
+            }),
+            Err(e) => error(
+                "Cache Migrate",
+                &format!("Unable to rename '{}': {}", name, e),
+            ),
+        }
+    }
+}
+
+/// Read the cache index. Returns an empty index if there is no index or it is broken.
+pub fn read() -> Index {
+    let Ok(content) = fs::read_to_string(index_path()) else {
+        return Index::new();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        warning(
+            "Cache Read",
+            &format!("Cache index is broken, ignoring it: {}", e),
+        );
+        Index::new()
+    })
+}
+
+fn write(index: &Index) -> Result<(), String> {
+    let content: String = serde_json::to_string_pretty(index).map_err(|e| e.to_string())?;
+    let _ = fs::create_dir_all(get_download_path());
+    write_atomic(&index_path(), &content)
+}
+
+/// Returns the entry of the given video.
+pub fn get(id: &str) -> Option<Entry> {
+    read().get(id).cloned()
+}
+
+/// Find a cached audio for the query without network (for `!local` queue items).
+/// The query can be a video ID, a YouTube URL or words which are all in the title.
+/// Returns the video ID and its entry.
+pub fn find(query: &str) -> Option<(String, Entry)> {
+    let index: Index = read();
+    let query: String = query.trim().to_lowercase();
+    let words: Vec<&str> = query.split_whitespace().collect();
+    index.into_iter().find(|(id, entry)| {
+        let title: String = entry.title.clone().unwrap_or_default().to_lowercase();
+        let matches: bool = query == id.to_lowercase()
+            || query.contains(&format!("v={}", id.to_lowercase()))
+            || (!words.is_empty() && words.iter().all(|word| title.contains(word)));
+        matches && fs::exists(audio_path(id)).unwrap_or(false)
+    })
+}
+
+/// Change the entry of the given video (a new entry is created if there is no entry).
+pub fn update(id: &str, change: impl FnOnce(&mut Entry)) {
+    let _lock = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut index: Index = read();
+    change(index.entry(id.to_string()).or_default());
+    if let Err(e) = write(&index) {
+        error(
+            "Cache Update",
+            &format!("Unable to write cache index: {}", e),
+        );
+    }
+}
+
+/// Remove the entries of the given videos, like when their files are deleted.
+pub fn remove(ids: &[&str]) {
+    let _lock = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut index: Index = read();
+    let length: usize = index.len();
+    index.retain(|id, _| !ids.contains(&id.as_str()));
+    if index.len() != length {
+        if let Err(e) = write(&index) {
+            error(
+                "Cache Remove",
+                &format!("Unable to write cache index: {}", e),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_partial_downloads() {
+        for name in [
+            "abc.webm.part",
+            "abc.ytdl",
+            "abc.temp.mp3",
+            "abc.f251.webm-Frag12",
+            "abc.part-Frag3.part",
+        ] {
+            assert!(is_partial_download(name), "{}", name);
+        }
+        for name in [
+            "abc.mp3",
+            "Best of .webm tricks.mp3",
+            "abc.temperature.mp3",
+            "Temple.mp3",
+            "abc-Fragile.mp3",
+            "abc.lrc",
+            "abc.m4a",
+            "abc.webm",
+        ] {
+            assert!(!is_partial_download(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn counts_only_audio_files() {
+        assert!(is_audio_file("abc.mp3"));
+        assert!(!is_audio_file("index.json"));
+        assert!(!is_audio_file("abc.lrc"));
+        assert!(!is_audio_file("notes.txt"));
+    }
+}
@@ -1,8 +1,9 @@
 use crate::log::*;
-use config::Config;
+use config::{Config, Value, ValueKind};
 use dirs;
 use std::collections::HashMap;
 use std::fs;
+use std::process::Command;
 
 // CONFIGURATION KEYS //
 /// Change max file count for saved audios. Default: `15`
@@ -12,41 +13,207 @@ pub const AUDIO_QUALITY: &str = "audio_quality";
 /// Number of concurrent fragments for `yt-dlp` download. Default `4`
 pub const CONCURRENT_FRAGMENTS: &str = "concurrent_fragments";
 
-/// Get configuration file which is at `$XDG_CONFIG_HOME/mpvy/config.toml`
-/// If there is no file, it will create a blank file.
-pub fn get_config() -> Result<HashMap<String, String>, String> {
-    let path: String = format!("{}/mpvy/config.toml", dirs::config_dir().unwrap().display());
+/// Minimum level of log messages which are written. Default: `info` - Levels: `debug`, `info`, `warning`, `error`
+pub const LOG_LEVEL: &str = "log_level";
+/// Format of log messages. Default: `text` - Formats: `text`, `json` (JSON lines)
+pub const LOG_FORMAT: &str = "log_format";
+/// When log files are rotated. Default: `size` - Values: `size`, `daily`
+pub const LOG_ROTATE: &str = "log_rotate";
+/// Max size of a log file in bytes before it is rotated (with `log_rotate = "size"`). Default: `1048576`
+pub const LOG_MAX_SIZE: &str = "log_max_size";
+/// Number of old log files to keep. Default: `5`
+pub const LOG_KEEP: &str = "log_keep";
+
+/// Loudness normalisation of audios. Default: `off` - Values: `off`, `gain`, `dynamic` (refer to `src/normalize.rs`)
+pub const NORMALIZE: &str = "normalize";
+/// Target loudness in LUFS for `normalize = "gain"`. Default: `-14`
+pub const NORMALIZE_TARGET: &str = "normalize_target";
+
+/// Also save every chapter of downloaded audios as a separate file (`mp3/chapters/<id>`). Default: `false`
+pub const SPLIT_CHAPTERS: &str = "split_chapters";
+
+/// Embed title, artist, date, source URL and thumbnail into downloaded audios. Default: `false`
+pub const EMBED_METADATA: &str = "embed_metadata";
+
+/// Max number of videos which `artist:`, `album:` and `channel:` queries are expanded into. Default: `25`
+pub const SEARCH_LIMIT: &str = "search.limit";
+/// Videos shorter than this (in seconds) are skipped by prefixed queries. Default: `60`
+pub const SEARCH_MIN_DURATION: &str = "search.min_duration";
+/// Videos longer than this (in seconds) are skipped by prefixed queries. Default: `900`
+pub const SEARCH_MAX_DURATION: &str = "search.max_duration";
+/// Videos whose titles have one of these words are skipped by prefixed queries, like `["live", "cover"]`. Default: `[]`
+pub const SEARCH_EXCLUDE: &str = "search.exclude";
+
+/// Keep playing related tracks (YouTube mix of the last audio) when the queue ends, instead of stopping. Default: `false`
+pub const AUTOPLAY: &str = "autoplay";
+
+/// Where synced lyrics come from. Default: `off` - Values: `off`, `local` (`.lrc` files), `auto` (`.lrc` files, then YouTube subtitles)
+pub const LYRICS: &str = "lyrics";
+/// Subtitle languages for lyrics, in `yt-dlp --sub-langs` format. Default: `en.*`
+pub const LYRICS_LANGS: &str = "lyrics_langs";
+
+/// SponsorBlock categories which are cut from audios at download time, like `["sponsor", "music_offtopic"]`. Default: `[]`
+pub const SPONSORBLOCK_REMOVE: &str = "sponsorblock.remove";
+/// SponsorBlock categories which are marked as chapters and skipped live while playing. Default: `[]`
+pub const SPONSORBLOCK_MARK: &str = "sponsorblock.mark";
+/// SponsorBlock API URL. Default: `yt-dlp` default (`https://sponsor.ajay.app`)
+pub const SPONSORBLOCK_API: &str = "sponsorblock.api";
+
+/// Path or name of `mpv` binary. Default: `mpv` (searched in `$PATH`)
+pub const MPV_PATH: &str = "mpv_path";
+/// Path or name of `yt-dlp` binary. Default: `yt-dlp` (searched in `$PATH`)
+pub const YTDLP_PATH: &str = "ytdlp_path";
+/// Path or name of `cava` binary. Default: `cava` (searched in `$PATH`)
+pub const CAVA_PATH: &str = "cava_path";
+/// Path or name of `ffprobe` binary. Default: `ffprobe` (searched in `$PATH`)
+pub const FFPROBE_PATH: &str = "ffprobe_path";
+/// Extra arguments appended to `mpv` commands. Default: `[]`
+pub const MPV_EXTRA_ARGS: &str = "mpv_extra_args";
+/// Extra arguments appended to `yt-dlp` commands. Default: `[]`
+pub const YTDLP_EXTRA_ARGS: &str = "ytdlp_extra_args";
+/// Extra arguments appended to `cava` command. Default: `[]`
+pub const CAVA_EXTRA_ARGS: &str = "cava_extra_args";
+/// Extra arguments appended to `ffprobe` commands. Default: `[]`
+pub const FFPROBE_EXTRA_ARGS: &str = "ffprobe_extra_args";
+
+/// An external program which is run by **mpvy**. Path and extra arguments of each program
+/// Can be changed in configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Program {
+    Mpv,
+    YtDlp,
+    Cava,
+    Ffprobe,
+}
+
+impl Program {
+    /// Default name of the program, which is searched in `$PATH`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Program::Mpv => "mpv",
+            Program::YtDlp => "yt-dlp",
+            Program::Cava => "cava",
+            Program::Ffprobe => "ffprobe",
+        }
+    }
+
+    fn path_key(&self) -> &'static str {
+        match self {
+            Program::Mpv => MPV_PATH,
+            Program::YtDlp => YTDLP_PATH,
+            Program::Cava => CAVA_PATH,
+            Program::Ffprobe => FFPROBE_PATH,
+        }
+    }
+
+    fn extra_args_key(&self) -> &'static str {
+        match self {
+            Program::Mpv => MPV_EXTRA_ARGS,
+            Program::YtDlp => YTDLP_EXTRA_ARGS,
+            Program::Cava => CAVA_EXTRA_ARGS,
+            Program::Ffprobe => FFPROBE_EXTRA_ARGS,
+        }
+    }
+
+    /// Returns the configured path of the program, or its default name.
+    pub fn path(&self) -> String {
+        load()
+            .ok()
+            .and_then(|config| config.get(self.path_key()).cloned())
+            .filter(|path| !path.trim().is_empty())
+            .unwrap_or(self.name().to_string())
+    }
+
+    /// Returns the configured extra arguments of the program.
+    pub fn extra_args(&self) -> Vec<String> {
+        get_array(self.extra_args_key())
+    }
+
+    /// Create a command for the program with the configured path.
+    /// Extra arguments should be appended with `extra_args` after the other arguments.
+    pub fn command(&self) -> Command {
+        Command::new(self.path())
+    }
+}
+
+/// Returns the path of configuration file (`$XDG_CONFIG_HOME/mpvy/config.toml`)
+pub fn config_path() -> String {
+    format!("{}/mpvy/config.toml", dirs::config_dir().unwrap().display())
+}
+
+/// Build configuration from the configuration file and environment values.
+fn build() -> Result<Config, String> {
+    let path: String = config_path();
 
     // Check if config file exists
-    if fs::exists(&path).unwrap_or(false) == false {
+    if !fs::exists(&path).unwrap_or(false) {
         // If there is no configuration file, create a new empty file.
         let result: Result<(), std::io::Error> = fs::write(&path, "");
 
         if result.is_err() {
-            error(
-                "Mpvy Config",
-                "Unable to write empty configuration file. Please create the file manually.",
+            return Err(
+                "Unable to write empty configuration file. Please create the file manually."
+                    .to_string(),
             );
-            return Err("Unable to write empty configuration file.".to_string());
         }
     }
 
-    let config: Config = Config::builder()
+    Config::builder()
         .add_source(config::File::with_name(&path))
         .add_source(config::Environment::with_prefix("MPVY")) // Allow users to pass config values as environment values
         .build()
-        .unwrap();
+        .map_err(|e| format!("Unable to read configuration file: {}", e))
+}
+
+/// Read the configuration file without logging anything.
+/// Used by the logger itself (refer to `src/log.rs`), others should use `get_config`.
+/// Only simple values (strings, numbers, booleans) are included, use `get_array` for arrays.
+pub fn load() -> Result<HashMap<String, String>, String> {
+    let config: Config = build()?;
 
     // Transform config into a HashMap
-    let result = config.try_deserialize::<HashMap<String, String>>();
+    let values: HashMap<String, Value> = config
+        .try_deserialize::<HashMap<String, Value>>()
+        .map_err(|_| "An error occured while converting config into an HashMap.".to_string())?;
+    Ok(values
+        .into_iter()
+        .filter_map(|(key, value)| match value.kind {
+            ValueKind::Array(_) | ValueKind::Table(_) => None,
+            _ => value.into_string().ok().map(|value| (key, value)),
+        })
+        .collect())
+}
+
+/// Returns the value of given key as string, keys in sections can be given like `section.key`.
+pub fn get_string(key: &str) -> Option<String> {
+    build().ok()?.get_string(key).ok()
+}
 
-    if result.is_err() {
-        error(
-            "Mpvy Config",
-            "An error occured while converting config into an HashMap.",
-        );
-        return Err("Unable to convert config into an HashMap.".to_string());
+/// Returns the array value of given key as strings. Returns an empty array if there is no value.
+/// A string value is split by whitespace, so arrays can be given as environment values too
+/// Like `MPVY_MPV_EXTRA_ARGS="--volume=50 --ao=pulse"`.
+pub fn get_array(key: &str) -> Vec<String> {
+    let Ok(config) = build() else {
+        return vec![];
+    };
+    if let Ok(values) = config.get_array(key) {
+        return values
+            .into_iter()
+            .filter_map(|value| value.into_string().ok())
+            .collect();
     }
+    config
+        .get_string(key)
+        .map(|value| value.split_whitespace().map(String::from).collect())
+        .unwrap_or_default()
+}
 
-    return Ok(result.unwrap());
+/// Get configuration file which is at `$XDG_CONFIG_HOME/mpvy/config.toml`
+/// If there is no file, it will create a blank file.
+pub fn get_config() -> Result<HashMap<String, String>, String> {
+    let result = load();
+    if let Err(e) = &result {
+        error("Mpvy Config", e);
+    }
+    result
 }
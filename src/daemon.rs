@@ -0,0 +1,576 @@
+use crate::events;
+use crate::ipc;
+use crate::log::*;
+use crate::lyrics;
+#[cfg(feature = "mpris")]
+use crate::mpris;
+use crate::player::{Player, Status};
+use crate::query::{self, QueueItem};
+use crate::queue::{PlayMode, Queue};
+use crate::runner::SystemRunner;
+use crate::search;
+use crate::service;
+use crate::supervisor;
+use crate::time::format_duration;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{fs, thread};
+
+/// An request which is sent by client (`mpvy add`, `mpvy next`...) to the daemon.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+/// The reply of daemon to a request. `message` is printed by client.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    pub message: String,
+}
+
+/// True in the daemon process. The foreground **mpvy** listens on the same socket (refer to `listen`).
+static DAEMON: AtomicBool = AtomicBool::new(false);
+
+/// Commands which are sent to the daemon instead of playing in the foreground.
+pub const COMMANDS: [&str; 9] = [
+    "add",
+    "next",
+    "next-chapter",
+    "prev-chapter",
+    "status",
+    "queue",
+    "lyrics",
+    "stop",
+    "events",
+];
+
+/// Returns the path of daemon socket (`$XDG_CONFIG_HOME/mpvy/mpvyd.socket`)
+/// This is not the **mpv** IPC socket (refer to `service::ipc_path`), clients talk with
+/// **mpvy** itself over this socket.
+pub fn socket_path() -> String {
+    format!(
+        "{}/mpvy/mpvyd.socket",
+        dirs::config_dir()
+            .expect("Unexpected Error: Unable to get config directory.")
+            .display()
+    )
+}
+
+/// Describe the playing audio and play mode for `mpvy status`.
+/// With `--json`, the whole status (refer to `player::Status`) is returned as JSON.
+fn status(player: &Player, json: bool) -> String {
+    let status: Status = player.status();
+    if json {
+        return serde_json::to_string(&status).unwrap_or_default();
+    }
+    match status.playing {
+        Some(video) => format!(
+            "Playing: {} [{} / {}]{}\nChannel: {}{}\nPlay mode: {}",
+            video.title,
+            format_duration(status.position.unwrap_or(0.0) as u64),
+            video.duration,
+            if status.paused { " (paused)" } else { "" },
+            video.channel,
+            status
+                .chapter
+                .map(|i| format!(
+                    "\nChapter: {}/{} {}",
+                    i + 1,
+                    video.chapters.len(),
+                    video.chapters[i].title
+                ))
+                .unwrap_or_default(),
+            status.mode.describe()
+        ),
+        None => format!("Nothing is playing.\nPlay mode: {}", status.mode.describe()),
+    }
+}
+
+/// List the queries in the queue for `mpvy queue`. The playing audio is marked with `>`.
+/// Chapters of the playing audio are listed under it as sub-tracks.
+pub fn list_queue(status: &Status) -> String {
+    if status.queue.is_empty() {
+        return "The queue is empty.".to_string();
+    }
+    let mut lines: Vec<String> = vec![];
+    for (i, item) in status.queue.iter().enumerate() {
+        let playing: bool = status.index == Some(i);
+        lines.push(format!(
+            "{} {:>3}  {}",
+            if playing { ">" } else { " " },
+            i + 1,
+            item
+        ));
+        let Some(video) = status.playing.as_ref().filter(|_| playing) else {
+            continue;
+        };
+        for (j, chapter) in video.chapters.iter().enumerate() {
+            lines.push(format!(
+                "      {} {:>3}. {}  {}",
+                if status.chapter == Some(j) { ">" } else { " " },
+                j + 1,
+                format_duration(chapter.start_time as u64),
+                chapter.title
+            ));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Returns lyrics of the playing audio for `mpvy lyrics`. The current line is marked with `>`.
+fn lyrics(player: &Player) -> (bool, String) {
+    let Some(video) = player.now_playing() else {
+        return (false, "Nothing is playing.".to_string());
+    };
+    let lines: Vec<lyrics::Line> = lyrics::get(&video.id);
+    if lines.is_empty() {
+        return (false, "The playing audio has no lyrics.".to_string());
+    }
+    let current: Option<usize> =
+        ipc::position().and_then(|position| lyrics::line_at(&lines, position));
+    (true, lyrics::render(&lines, current, None, false))
+}
+
+/// Run a request on the player and returns the response.
+fn handle(player: &Player, request: &Request) -> Response {
+    info(
+        "Daemon Handle",
+        &format!("Received command: '{}'.", request.command),
+    );
+    let (ok, message): (bool, String) = match request.command.as_str() {
+        "add" => {
+            // Queries have the same syntax as the prompt (refer to `src/query.rs`).
+            match query::parse(&request.args.join(" ")) {
+                Err(e) => (false, format!("Invalid query: {}", e)),
+                Ok(items) => {
+                    // Queries like `artist:<name>` are expanded into their videos
+                    let items: Vec<QueueItem> = search::expand(&SystemRunner, items);
+                    if items.is_empty() {
+                        (false, "Usage: mpvy add <query>".to_string())
+                    } else {
+                        let count: usize = items.len();
+                        player.add(items);
+                        (true, format!("Added {} audio(s) to the queue.", count))
+                    }
+                }
+            }
+        }
+        "next" => match player.skip() {
+            Ok(_) => (true, "Skipped to the next audio.".to_string()),
+            Err(e) => (false, format!("Unable to skip: {}", e)),
+        },
+        "status" => (
+            true,
+            status(player, request.args.contains(&"--json".to_string())),
+        ),
+        "next-chapter" | "prev-chapter" => {
+            let offset: isize = if request.command == "next-chapter" {
+                1
+            } else {
+                -1
+            };
+            match player.seek_chapter(offset) {
+                Ok(title) => (true, format!("Playing chapter: {}", title)),
+                Err(e) => (false, format!("Unable to change chapter: {}", e)),
+            }
+        }
+        "queue" => (true, list_queue(&player.status())),
+        "lyrics" => lyrics(player),
+        // Only the daemon is stopped by clients, the foreground is quit in its shell
+        "stop" if DAEMON.load(Ordering::Relaxed) => (true, "mpvyd stopped.".to_string()),
+        "stop" => (
+            false,
+            "mpvyd is not running, a foreground mpvy is playing. Quit it in its shell.".to_string(),
+        ),
+        // Not a client command, `run` asks it to know who listens on the socket
+        "owner" => (
+            true,
+            if DAEMON.load(Ordering::Relaxed) {
+                "daemon"
+            } else {
+                "foreground"
+            }
+            .to_string(),
+        ),
+        other => (false, format!("Unknown command: '{}'.", other)),
+    };
+    Response { ok, message }
+}
+
+/// Write events to the client as newline-delimited JSON until it disconnects.
+fn stream_events(mut stream: &UnixStream) {
+    info("Daemon Events", "Client subscribed to events.");
+    for event in events::subscribe() {
+        let Ok(line) = serde_json::to_string(&event) else {
+            continue;
+        };
+        if writeln!(stream, "{}", line).is_err() {
+            break;
+        }
+    }
+    info("Daemon Events", "Client unsubscribed from events.");
+}
+
+/// Read a request from client connection and write the response.
+fn serve(player: &Player, stream: UnixStream) {
+    let mut reader = BufReader::new(&stream);
+    let mut line: String = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return;
+    }
+    let request: Result<Request, serde_json::Error> = serde_json::from_str::<Request>(&line);
+    if request
+        .as_ref()
+        .is_ok_and(|request| request.command == "events")
+    {
+        stream_events(&stream);
+        return;
+    }
+    let response: Response = match &request {
+        Ok(request) => handle(player, request),
+        Err(e) => Response {
+            ok: false,
+            message: format!("Invalid request: {}", e),
+        },
+    };
+    let mut stream = &stream;
+    if let Ok(response) = serde_json::to_string(&response) {
+        let _ = writeln!(stream, "{}", response);
+    }
+
+    // Exit after the response is sent, so client knows the daemon is stopped.
+    if response.ok && request.is_ok_and(|request| request.command == "stop") {
+        supervisor::shutdown("Stop command received. Exiting.");
+        std::process::exit(0);
+    }
+}
+
+/// Returns who listens on `socket_path`: `"daemon"` or `"foreground"` (or an error message if
+/// It doesn't answer). Returns `None` if nothing is listening.
+pub fn owner() -> Option<String> {
+    UnixStream::connect(socket_path()).ok()?;
+    let request: Request = Request {
+        command: "owner".to_string(),
+        args: vec![],
+    };
+    Some(send(&request).map_or_else(|e| e, |response| response.message))
+}
+
+/// Start the daemon (`mpvy daemon` or `mpvyd`). The daemon owns the player and the queue,
+/// And listens on `socket_path` for commands from clients.
+/// With `--detach`, the daemon is started in background and this process exits.
+pub fn run(args: &[String]) -> i32 {
+    let path: String = socket_path();
+    // Check if there is a daemon (or a foreground player) running already
+    match owner().as_deref() {
+        None => {}
+        Some("foreground") => {
+            println!("A foreground mpvy is playing. Quit it before starting mpvyd.");
+            return 1;
+        }
+        Some(_) => {
+            println!("mpvyd is already running.");
+            return 1;
+        }
+    }
+    let cava: bool = args.contains(&"--cava".to_string());
+
+    if args.contains(&"--detach".to_string()) {
+        if cava {
+            println!("'cava' needs a terminal, use 'mpvy daemon --cava' without '--detach'.");
+            return 1;
+        }
+        let exe =
+            std::env::current_exe().expect("Unexpected Error: Unable to get executable path.");
+        let mut command: Command = Command::new(exe);
+        command
+            .arg("daemon")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        // Start in a new session, so closing the terminal (SIGHUP) doesn't stop the daemon.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let result = command.spawn();
+        return match result {
+            Ok(child) => {
+                println!("mpvyd started in background (pid {}).", child.id());
+                0
+            }
+            Err(e) => {
+                println!("Unable to start mpvyd: {}", e);
+                1
+            }
+        };
+    }
+
+    // Children are terminated and the socket is removed on SIGTERM (refer to `src/supervisor.rs`).
+    supervisor::install();
+    DAEMON.store(true, Ordering::Relaxed);
+    let player: Arc<Player> = Arc::new(Player::new(Queue::new(vec![], PlayMode::default()), None));
+    if let Err(e) = listen(Arc::clone(&player)) {
+        println!("Unable to listen on '{}': {}", path, e);
+        return 1;
+    }
+    println!("mpvyd is listening on '{}'.", path);
+    // Cava is owned by the daemon, it is terminated when the daemon stops
+    if cava {
+        service::cava();
+    }
+
+    #[cfg(feature = "mpris")]
+    let _ = mpris::start(Arc::clone(&player));
+
+    player.run(None, true);
+    0
+}
+
+/// Listen on `socket_path` for client commands in background.
+/// This is used by the daemon, and also by the foreground **mpvy** (if there is no daemon),
+/// So `mpvy status` and `mpvy events` work for both of them.
+pub fn listen(player: Arc<Player>) -> Result<(), String> {
+    let path: String = socket_path();
+    if UnixStream::connect(&path).is_ok() {
+        return Err("Another mpvy is listening on the socket.".to_string());
+    }
+    // Remove the socket which is left by a stopped daemon.
+    if fs::exists(&path).unwrap_or(false) {
+        let _ = fs::remove_file(&path);
+    }
+    let listener: UnixListener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error(
+                "Daemon Listen",
+                &format!("Unable to bind daemon socket: {}", e),
+            );
+            return Err(e.to_string());
+        }
+    };
+    info("Daemon Listen", &format!("Listening on '{}'.", path));
+    supervisor::remove_on_exit(&path);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let player = Arc::clone(&player);
+                    thread::spawn(move || serve(&player, stream));
+                }
+                Err(e) => error("Daemon Listen", &format!("Unable to accept client: {}", e)),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Connect to the daemon and send the request.
+fn connect(request: &Request) -> Result<UnixStream, String> {
+    let stream: UnixStream = UnixStream::connect(socket_path())
+        .map_err(|_| "mpvyd is not running. Start it with 'mpvy daemon --detach'.".to_string())?;
+    let content: String = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    writeln!(&stream, "{}", content).map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+/// Send a request to the daemon and returns its response.
+pub fn send(request: &Request) -> Result<Response, String> {
+    let stream: UnixStream = connect(request)?;
+
+    let mut line: String = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+    serde_json::from_str::<Response>(&line).map_err(|e| format!("Invalid response: {}", e))
+}
+
+/// Print events from the daemon as they happen (`mpvy events`).
+fn print_events(request: &Request) -> i32 {
+    let stream: UnixStream = match connect(request) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    for line in BufReader::new(&stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        println!("{}", line);
+    }
+    0
+}
+
+/// Handle client commands (`mpvy add <query>`, `mpvy next`, `mpvy status [--json]`,
+/// `mpvy queue`, `mpvy lyrics`, `mpvy stop`, `mpvy events`) and returns the exit code.
+pub fn client(command: &str, args: &[String]) -> i32 {
+    let request: Request = Request {
+        command: command.to_string(),
+        args: args.to_vec(),
+    };
+    if command == "events" {
+        return print_events(&request);
+    }
+    match send(&request) {
+        Ok(response) => {
+            println!("{}", response.message);
+            if response.ok {
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+    use crate::queue::Repeat;
+    use crate::runner::mock::sandbox;
+    use crate::yt_dlp::VideoInfo;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    #[test]
+    fn prints_status_json() {
+        let mode: PlayMode = PlayMode {
+            shuffle: true,
+            repeat: Repeat::All,
+        };
+        let player: Player = Player::new(Queue::new(vec![QueueItem::new("song one")], mode), None);
+        let idle: Value = serde_json::from_str(&status(&player, true)).unwrap();
+        assert_eq!(
+            idle,
+            json!({
+                "playing": null,
+                "position": null,
+                "paused": false,
+                "queue": ["song one"],
+                "index": null,
+                "chapter": null,
+                "mode": {"shuffle": true, "repeat": "all"}
+            })
+        );
+
+        let playing: Status = Status {
+            playing: Some(VideoInfo {
+                duration: "00:03:00".to_string(),
+                title: "Song One".to_string(),
+                url: "https://www.youtube.com/watch?v=abc".to_string(),
+                id: "abc".to_string(),
+                channel: "Artist".to_string(),
+                chapters: vec![],
+            }),
+            position: Some(12.5),
+            paused: true,
+            queue: vec!["song one".to_string()],
+            index: Some(0),
+            chapter: None,
+            mode: PlayMode::default(),
+        };
+        assert_eq!(
+            serde_json::to_value(&playing).unwrap(),
+            json!({
+                "playing": {
+                    "duration": "00:03:00",
+                    "title": "Song One",
+                    "url": "https://www.youtube.com/watch?v=abc",
+                    "id": "abc",
+                    "channel": "Artist",
+                    "chapters": []
+                },
+                "position": 12.5,
+                "paused": true,
+                "queue": ["song one"],
+                "index": 0,
+                "chapter": null,
+                "mode": {"shuffle": false, "repeat": "off"}
+            })
+        );
+    }
+
+    #[test]
+    fn streams_events_as_json_lines() {
+        sandbox();
+        let (client, server) = UnixStream::pair().unwrap();
+        thread::spawn(move || stream_events(&server));
+        client
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut reader = BufReader::new(&client);
+        let mut line: String = String::new();
+        // Events are only sent after the client subscribes
+        while reader.read_line(&mut line).is_err() || line.is_empty() {
+            events::emit(Event::Error {
+                message: "ready".to_string(),
+            });
+        }
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        events::emit(Event::TrackStarted {
+            query: "song one".to_string(),
+            id: "daemon-test".to_string(),
+            title: "Song One".to_string(),
+            channel: "Artist".to_string(),
+            duration: "00:03:00".to_string(),
+            url: "https://www.youtube.com/watch?v=abc".to_string(),
+        });
+        events::emit(Event::DownloadProgress {
+            id: "daemon-test".to_string(),
+            percent: 42.5,
+        });
+        events::emit(Event::TrackEnded {
+            id: "daemon-test".to_string(),
+            title: "Song One".to_string(),
+            played: 180,
+        });
+        events::emit(Event::Error {
+            message: "daemon-test".to_string(),
+        });
+
+        // Other tests may emit events too, only the events of this test are checked
+        let mut received: Vec<Value> = vec![];
+        while received.len() < 4 {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            let event: Value = serde_json::from_str(line.trim_end()).unwrap();
+            if event["id"] == "daemon-test" || event["message"] == "daemon-test" {
+                assert!(event["time"].is_u64());
+                received.push(event);
+            }
+        }
+        let names: Vec<&str> = received
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["track-started", "download-progress", "track-ended", "error"]
+        );
+        assert_eq!(received[0]["title"], "Song One");
+        assert_eq!(received[1]["percent"], 42.5);
+        assert_eq!(received[2]["played"], 180);
+    }
+}
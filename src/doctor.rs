@@ -0,0 +1,282 @@
+use crate::config::Program;
+use crate::daemon;
+use crate::log::{self, *};
+use crate::playlist;
+use crate::service;
+use crate::time::{days_from_civil, now};
+use crate::yt_dlp;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// `yt-dlp` releases older than this (in days) are likely broken by YouTube changes.
+const YT_DLP_MAX_AGE: i64 = 90;
+/// Max length of a Unix socket path (`sun_path` is 108 bytes with the NUL byte).
+const MAX_SOCKET_PATH: usize = 107;
+
+/// Result of a single check.
+#[derive(Debug, PartialEq)]
+enum Check {
+    Ok(String),
+    Warning(String), // Not a problem for basic usage (like missing `cava`)
+    Fail(String),
+}
+
+/// Run the given program with a version argument and return the first line of its output.
+fn version(program: &str, arg: &str) -> Result<String, String> {
+    let mut command: Command = Command::new(program);
+    command.arg(arg);
+    version_of(command, program)
+}
+
+/// Run the given command and return the first line of its output.
+fn version_of(mut command: Command, program: &str) -> Result<String, String> {
+    log::command("Doctor Version", &command);
+    let output = command.output().map_err(|e| {
+        format!(
+            "'{}' not found or not executable ({})",
+            command.get_program().to_string_lossy(),
+            e
+        )
+    })?;
+    log::command_exit(
+        "Doctor Version",
+        Some(output.status),
+        &String::from_utf8_lossy(&output.stderr),
+    );
+    if !output.status.success() {
+        return Err(format!("'{}' failed", log::command_line(&command)));
+    }
+    Ok(parse_version(
+        &String::from_utf8_lossy(&output.stdout),
+        program,
+    ))
+}
+
+/// Returns the version from the output of a version command, it is the first line.
+fn parse_version(output: &str, program: &str) -> String {
+    let line: &str = output.lines().next().unwrap_or_default().trim();
+    if line.is_empty() {
+        return format!("{} (unknown version)", program);
+    }
+    line.to_string()
+}
+
+/// Returns the release day (since Unix epoch) of a `yt-dlp` version. Versions are dates like
+/// `2025.01.26`, nightly versions have a build number too (`2025.01.26.232824`).
+fn release_day(version: &str) -> Option<i64> {
+    let parts: Vec<i64> = version
+        .split('.')
+        .take(3)
+        .map(|part| part.parse::<i64>().ok())
+        .collect::<Option<Vec<i64>>>()?;
+    let [year, month, day] = parts.as_slice() else {
+        return None;
+    };
+    Some(days_from_civil(*year, *month, *day))
+}
+
+/// Check the age of the `yt-dlp` version. `today` is the day since Unix epoch.
+fn check_yt_dlp_age(version: &str, today: i64) -> Check {
+    let Some(released) = release_day(version) else {
+        return Check::Warning(format!(
+            "yt-dlp: unable to read release date from version '{}'",
+            version
+        ));
+    };
+    let age: i64 = today - released;
+    if age > YT_DLP_MAX_AGE {
+        Check::Fail(format!(
+            "yt-dlp {}: released {} days ago, YouTube extraction is likely broken. Update it with 'yt-dlp -U' or your package manager",
+            version, age
+        ))
+    } else {
+        Check::Ok(format!("yt-dlp {}", version))
+    }
+}
+
+/// Check `yt-dlp` and its release date. Versions are dates like `2025.01.26`.
+fn check_yt_dlp() -> Check {
+    let mut command: Command = Program::YtDlp.command();
+    command.arg("--version");
+    let version: String = match version_of(command, "yt-dlp") {
+        Ok(version) => version,
+        Err(e) => return Check::Fail(format!("yt-dlp: {}", e)),
+    };
+    check_yt_dlp_age(&version, (now() / 86400) as i64)
+}
+
+/// Check that the directory exists (or can be created) and a file can be written into it.
+fn check_dir(name: &str, dir: &Path) -> Check {
+    let test_file = dir.join(".mpvy-doctor");
+    let result: Result<(), std::io::Error> = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&test_file, ""))
+        .and_then(|_| fs::remove_file(&test_file));
+    match result {
+        Ok(_) => Check::Ok(format!("{} directory is writable: {}", name, dir.display())),
+        Err(e) => Check::Fail(format!(
+            "{} directory is not writable: {} ({})",
+            name,
+            dir.display(),
+            e
+        )),
+    }
+}
+
+/// Returns a failed check if the socket path is longer than `MAX_SOCKET_PATH`.
+fn check_socket_length(name: &str, path: &str) -> Option<Check> {
+    if path.len() <= MAX_SOCKET_PATH {
+        return None;
+    }
+    Some(Check::Fail(format!(
+        "{} socket path is too long ({} bytes, max {}): {}",
+        name,
+        path.len(),
+        MAX_SOCKET_PATH,
+        path
+    )))
+}
+
+/// Check that a socket can be created at the given path.
+fn check_socket(name: &str, path: &str) -> Check {
+    if let Some(check) = check_socket_length(name, path) {
+        return check;
+    }
+    match Path::new(path).parent() {
+        Some(dir) => match check_dir(name, dir) {
+            Check::Ok(_) => Check::Ok(format!("{} socket path is usable: {}", name, path)),
+            other => other,
+        },
+        None => Check::Fail(format!("{} socket path is invalid: {}", name, path)),
+    }
+}
+
+/// Run all checks in order.
+fn checks() -> Vec<Check> {
+    let mut checks: Vec<Check> = vec![];
+    let mut command: Command = Program::Mpv.command();
+    command.arg("--version");
+    checks.push(match version_of(command, "mpv") {
+        Ok(version) => Check::Ok(version),
+        Err(e) => Check::Fail(format!("mpv: {}", e)),
+    });
+    checks.push(check_yt_dlp());
+    checks.push(match version("ffmpeg", "-version") {
+        Ok(version) => Check::Ok(version),
+        Err(e) => Check::Fail(format!(
+            "ffmpeg: {}. It is required by 'yt-dlp -x' to extract audio",
+            e
+        )),
+    });
+    let mut command: Command = Program::Ffprobe.command();
+    command.arg("-version");
+    checks.push(match version_of(command, "ffprobe") {
+        Ok(version) => Check::Ok(version),
+        Err(e) => Check::Warning(format!(
+            "ffprobe: {}. Cached audios are not checked without it",
+            e
+        )),
+    });
+    let mut command: Command = Program::Cava.command();
+    command.arg("-v");
+    checks.push(match version_of(command, "cava") {
+        Ok(version) => Check::Ok(version),
+        Err(e) => Check::Warning(format!("cava: {}. It is only required for '--cava'", e)),
+    });
+
+    let download_path: String = yt_dlp::get_download_path();
+    checks.push(check_dir("Download", Path::new(&download_path)));
+    checks.push(check_dir(
+        "Playlist",
+        Path::new(&playlist::playlists_path()),
+    ));
+    match log::log_dir() {
+        Some(dir) => checks.push(check_dir("Log", &dir)),
+        None => checks.push(Check::Fail(
+            "Log directory: unable to get config directory".to_string(),
+        )),
+    }
+    checks.push(check_socket("IPC", &service::ipc_path()));
+    checks.push(check_socket("Daemon", &daemon::socket_path()));
+    checks
+}
+
+/// Check external programs, directories and socket paths, then print a report.
+/// Returns non-zero exit code if any check fails.
+///
+/// ```txt
+/// mpvy doctor
+/// ```
+pub fn run(_args: &[String]) -> i32 {
+    info("Doctor Run", "Checking dependencies.");
+    let checks: Vec<Check> = checks();
+    let mut failed: usize = 0;
+    for check in &checks {
+        match check {
+            Check::Ok(message) => println!("[  OK  ] {}", message),
+            Check::Warning(message) => println!("[ WARN ] {}", message),
+            Check::Fail(message) => {
+                failed += 1;
+                error("Doctor Run", message);
+                println!("[ FAIL ] {}", message);
+            }
+        }
+    }
+    println!();
+    if failed == 0 {
+        println!("Everything looks fine.");
+        0
+    } else {
+        println!("{} problem(s) found.", failed);
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(
+            parse_version("mpv 0.38.0 Copyright © 2000-2024\nbuilt on ...\n", "mpv"),
+            "mpv 0.38.0 Copyright © 2000-2024"
+        );
+        assert_eq!(parse_version("", "cava"), "cava (unknown version)");
+        assert_eq!(release_day("1970.01.02"), Some(1));
+        assert_eq!(
+            release_day("2025.01.26.232824"),
+            Some(days_from_civil(2025, 1, 26))
+        );
+        assert_eq!(release_day("2025.01"), None);
+        assert_eq!(release_day("nightly"), None);
+    }
+
+    #[test]
+    fn checks_yt_dlp_age() {
+        let released: i64 = days_from_civil(2025, 1, 26);
+        assert_eq!(
+            check_yt_dlp_age("2025.01.26", released + YT_DLP_MAX_AGE),
+            Check::Ok("yt-dlp 2025.01.26".to_string())
+        );
+        assert!(matches!(
+            check_yt_dlp_age("2025.01.26", released + YT_DLP_MAX_AGE + 1),
+            Check::Fail(message) if message.contains("released 91 days ago")
+        ));
+        assert!(matches!(
+            check_yt_dlp_age("stable", released),
+            Check::Warning(_)
+        ));
+    }
+
+    #[test]
+    fn checks_socket_path_length() {
+        let path: String = format!("/tmp/{}", "a".repeat(MAX_SOCKET_PATH - 5));
+        assert_eq!(check_socket_length("IPC", &path), None);
+        let path: String = format!("{}b", path);
+        assert!(matches!(
+            check_socket_length("IPC", &path),
+            Some(Check::Fail(message)) if message.contains("(108 bytes, max 107)")
+        ));
+    }
+}
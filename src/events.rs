@@ -0,0 +1,57 @@
+use crate::time::now;
+use serde::Serialize;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+/// An event which happens while playing. Events are streamed to `mpvy events` clients
+/// As newline-delimited JSON, like `{"time":1738000000,"event":"track-started",...}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    TrackStarted {
+        query: String,
+        id: String,
+        title: String,
+        channel: String,
+        duration: String,
+        url: String,
+    },
+    TrackEnded {
+        id: String,
+        title: String,
+        played: u64,
+    },
+    DownloadProgress {
+        id: String,
+        percent: f64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// An event with the time it happened (seconds since Unix epoch).
+#[derive(Debug, Clone, Serialize)]
+pub struct Timed {
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+static SUBSCRIBERS: Mutex<Vec<Sender<Timed>>> = Mutex::new(Vec::new());
+
+/// Subscribe to events. The receiver gets every event emitted after this call.
+pub fn subscribe() -> Receiver<Timed> {
+    let (sender, receiver) = channel();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+/// Send an event to all subscribers. Subscribers which are gone are removed.
+pub fn emit(event: Event) {
+    let timed: Timed = Timed { time: now(), event };
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|sender| sender.send(timed.clone()).is_ok());
+}
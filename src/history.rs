@@ -0,0 +1,317 @@
+use crate::log::*;
+use crate::query::QueueItem;
+use crate::runner::SystemRunner;
+use crate::service;
+use crate::time::{format_duration, format_timestamp, now};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+/// An played audio in the listening history.
+/// Values:
+///
+/// ```txt
+/// timestamp:    When the audio started playing (seconds since Unix epoch)
+/// query:        The query which is given by user
+/// id:           Video spesific ID
+/// title:        Video title
+/// channel:      Video channel (uploader), used as artist in statistics
+/// played:       How long the audio actually played in seconds
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: u64,
+    pub query: String,
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub channel: String,
+    pub played: u64,
+}
+
+/// Returns the path of history file (`$XDG_CONFIG_HOME/mpvy/history.jsonl`)
+/// Every line of the file is an `Entry` in JSON format.
+/// Unlike log files, this file is never cleaned by **mpvy**.
+pub fn history_path() -> String {
+    format!(
+        "{}/mpvy/history.jsonl",
+        dirs::config_dir()
+            .expect("Unexpected Error: Unable to get config directory for history.")
+            .display()
+    )
+}
+
+/// Append the given entry to the end of history file.
+pub fn record(entry: &Entry) -> Result<(), String> {
+    info(
+        "History Record",
+        &format!(
+            "Recording '{}' (played {} seconds).",
+            entry.title, entry.played
+        ),
+    );
+    let line: String = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path());
+    match file {
+        Ok(mut file) => writeln!(file, "{}", line).map_err(|e| e.to_string()),
+        Err(e) => {
+            error(
+                "History Record",
+                &format!("Unable to open history file: {}", e),
+            );
+            Err(e.to_string())
+        }
+    }
+}
+
+/// Read all entries from the history file, oldest first.
+/// Broken lines (for example, if mpvy is killed while writing) are skipped.
+pub fn read() -> Vec<Entry> {
+    let content: String = fs::read_to_string(history_path()).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+        .collect()
+}
+
+/// Get the value of an option like `--limit 10` from arguments.
+fn option(args: &[String], name: &str) -> Option<u64> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .and_then(|value| value.parse::<u64>().ok())
+}
+
+/// Print entries as a numbered list, most recent first.
+/// The number can be used with `mpvy history replay <number>`.
+fn print_entries(entries: &[(usize, &Entry)]) {
+    if entries.is_empty() {
+        println!("No plays found in history.");
+        return;
+    }
+    for (number, entry) in entries {
+        println!(
+            "{:>4}  {}  [{}]  {} ({})",
+            number,
+            format_timestamp(entry.timestamp),
+            format_duration(entry.played),
+            entry.title,
+            entry.id
+        );
+    }
+}
+
+/// Returns the entries which have the text in their title, query or channel (ignoring case).
+fn search<'a>(entries: Vec<(usize, &'a Entry)>, text: &str) -> Vec<(usize, &'a Entry)> {
+    let text: String = text.to_lowercase();
+    entries
+        .into_iter()
+        .filter(|(_, e)| {
+            e.title.to_lowercase().contains(&text)
+                || e.query.to_lowercase().contains(&text)
+                || e.channel.to_lowercase().contains(&text)
+        })
+        .collect()
+}
+
+/// Most played tracks `(title, plays, seconds)` and artists `(channel, (plays, seconds))`.
+type Top<'a> = (Vec<(&'a str, u64, u64)>, Vec<(&'a str, (u64, u64))>);
+
+/// Count plays of tracks and artists (channels) since the given time. They are sorted by
+/// Plays, then by played seconds, then by name.
+fn top(entries: &[Entry], since: u64) -> Top<'_> {
+    let mut tracks: HashMap<&str, (&str, u64, u64)> = HashMap::new(); // id -> (title, plays, seconds)
+    let mut artists: HashMap<&str, (u64, u64)> = HashMap::new(); // channel -> (plays, seconds)
+
+    for entry in entries.iter().filter(|e| e.timestamp >= since) {
+        let track = tracks.entry(&entry.id).or_insert((&entry.title, 0, 0));
+        track.1 += 1;
+        track.2 += entry.played;
+        if !entry.channel.is_empty() {
+            let artist = artists.entry(&entry.channel).or_insert((0, 0));
+            artist.0 += 1;
+            artist.1 += entry.played;
+        }
+    }
+
+    let mut tracks: Vec<_> = tracks.into_values().collect();
+    tracks.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(b.0)));
+    let mut artists: Vec<_> = artists.into_iter().collect();
+    artists.sort_by(|a, b| {
+        b.1 .0
+            .cmp(&a.1 .0)
+            .then(b.1 .1.cmp(&a.1 .1))
+            .then(a.0.cmp(b.0))
+    });
+    (tracks, artists)
+}
+
+/// Print most played tracks and artists (channels) in the last `days` days.
+fn print_top(entries: &[Entry], days: u64, limit: usize) {
+    let since: u64 = now().saturating_sub(days.saturating_mul(86400));
+    let (tracks, artists) = top(entries, since);
+
+    println!("---Top tracks (last {} days)-------------", days);
+    for (title, plays, seconds) in tracks.iter().take(limit) {
+        println!(
+            " {:>4} plays  [{}]  {}",
+            plays,
+            format_duration(*seconds),
+            title
+        );
+    }
+    println!("---Top artists (last {} days)------------", days);
+    for (channel, (plays, seconds)) in artists.iter().take(limit) {
+        println!(
+            " {:>4} plays  [{}]  {}",
+            plays,
+            format_duration(*seconds),
+            channel
+        );
+    }
+    println!("----------------------------------------");
+}
+
+/// Handle `mpvy history` command and returns the exit code.
+///
+/// ```txt
+/// mpvy history [list] [--limit N]          List recent plays
+/// mpvy history search <text> [--limit N]   Search recent plays by title or query
+/// mpvy history replay <number>             Play an entry again
+/// mpvy history top [--days N] [--limit N]  Show top tracks and artists
+/// ```
+pub fn run(args: &[String]) -> i32 {
+    let entries: Vec<Entry> = read();
+    let limit: usize = option(args, "--limit").unwrap_or(20) as usize;
+    // Number entries from the most recent one (1 = last played)
+    let numbered: Vec<(usize, &Entry)> = entries
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, e)| (i + 1, e))
+        .collect();
+
+    match args.first().map(|arg| arg.as_str()) {
+        None | Some("list") | Some("--limit") => {
+            print_entries(&numbered.into_iter().take(limit).collect::<Vec<_>>());
+            0
+        }
+        Some("search") => {
+            let Some(text) = args.get(1) else {
+                println!("Usage: mpvy history search <text>");
+                return 1;
+            };
+            let found: Vec<(usize, &Entry)> = search(numbered, text);
+            print_entries(&found.into_iter().take(limit).collect::<Vec<_>>());
+            0
+        }
+        Some("replay") => {
+            let entry = args
+                .get(1)
+                .and_then(|n| n.parse::<usize>().ok())
+                .and_then(|n| numbered.iter().find(|(number, _)| *number == n));
+            let Some((_, entry)) = entry else {
+                println!("Usage: mpvy history replay <number> (refer to 'mpvy history list')");
+                return 1;
+            };
+            info(
+                "History Replay",
+                &format!("Replaying '{}' from history.", entry.title),
+            );
+            println!("Replaying: {}", entry.title);
+            match service::play(
+                &SystemRunner,
+                &QueueItem::new(&service::video_url(&entry.id)),
+                None,
+                None,
+            ) {
+                Ok(playback) => {
+                    playback.wait();
+                    0
+                }
+                Err(e) => {
+                    println!("Unable to replay audio: {}", e);
+                    1
+                }
+            }
+        }
+        Some("top") => {
+            let days: u64 = option(args, "--days").unwrap_or(30);
+            print_top(
+                &entries,
+                days,
+                option(args, "--limit").unwrap_or(10) as usize,
+            );
+            0
+        }
+        Some(other) => {
+            println!("Unknown history command: '{}'.", other);
+            println!("Usage: mpvy history [list|search <text>|replay <number>|top] [--limit N] [--days N]");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64, id: &str, title: &str, channel: &str, played: u64) -> Entry {
+        Entry {
+            timestamp,
+            query: format!("{} query", id),
+            id: id.to_string(),
+            title: title.to_string(),
+            channel: channel.to_string(),
+            played,
+        }
+    }
+
+    #[test]
+    fn searches_title_query_and_channel() {
+        let entries: Vec<Entry> = vec![
+            entry(1, "a", "One More Time", "Daft Punk", 300),
+            entry(2, "b", "Hello", "Adele", 200),
+            entry(3, "c", "Around the World", "Daft Punk - Topic", 400),
+        ];
+        let numbered: Vec<(usize, &Entry)> = entries.iter().rev().enumerate().collect();
+        let numbers = |text: &str| -> Vec<usize> {
+            search(numbered.clone(), text)
+                .into_iter()
+                .map(|(number, _)| number)
+                .collect()
+        };
+        assert_eq!(numbers("daft"), [0, 2]);
+        assert_eq!(numbers("HELLO"), [1]);
+        assert_eq!(numbers("b query"), [1]);
+        assert!(numbers("nothing").is_empty());
+    }
+
+    #[test]
+    fn ranks_top_tracks_and_artists() {
+        let entries: Vec<Entry> = vec![
+            entry(50, "old", "Old Song", "Old Band", 999),
+            entry(100, "a", "Song A", "Band", 100),
+            entry(110, "b", "Song B", "Band", 200),
+            entry(120, "a", "Song A", "Band", 100),
+            entry(130, "c", "Song C", "Other", 300),
+            entry(140, "d", "Song D", "", 300),
+        ];
+        let (tracks, artists) = top(&entries, 100);
+        assert_eq!(
+            tracks,
+            [
+                ("Song A", 2, 200),
+                ("Song C", 1, 300),
+                ("Song D", 1, 300),
+                ("Song B", 1, 200)
+            ]
+        );
+        // Entries without channel are not counted as artists
+        assert_eq!(artists, [("Band", (3, 400)), ("Other", (1, 300))]);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::log::*;
+use crate::service::ipc_path;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Send a command to the running `mpv` process over the JSON IPC socket (`service::ipc_path`).
+/// Returns the `data` field of the reply. Events which are sent by `mpv` on the same
+/// Connection are skipped until the reply is found.
+/// Refer to https://mpv.io/manual/stable/#json-ipc for commands.
+pub fn command(args: &[Value]) -> Result<Value, String> {
+    let mut stream: UnixStream = UnixStream::connect(ipc_path())
+        .map_err(|e| format!("Unable to connect to mpv IPC socket: {}", e))?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .map_err(|e| e.to_string())?;
+
+    let request: Value = json!({ "command": args, "request_id": 1 });
+    writeln!(stream, "{}", request).map_err(|e| format!("Unable to send IPC command: {}", e))?;
+
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let line: String = line.map_err(|e| format!("Unable to read IPC reply: {}", e))?;
+        let reply: Value = match serde_json::from_str(&line) {
+            Ok(reply) => reply,
+            Err(_) => continue,
+        };
+        // Skip events (they don't have `request_id`)
+        if reply.get("request_id") != Some(&json!(1)) {
+            continue;
+        }
+        if reply["error"] != "success" {
+            return Err(format!("mpv returned an error: {}", reply["error"]));
+        }
+        return Ok(reply.get("data").cloned().unwrap_or(Value::Null));
+    }
+
+    warning("Ipc Command", "IPC connection closed before the reply.");
+    Err("IPC connection closed before the reply.".to_string())
+}
+
+/// Get a property of the running `mpv` process (like `time-pos`, `pause`).
+pub fn get_property(name: &str) -> Result<Value, String> {
+    command(&[json!("get_property"), json!(name)])
+}
+
+/// Set a property of the running `mpv` process.
+pub fn set_property(name: &str, value: Value) -> Result<(), String> {
+    command(&[json!("set_property"), json!(name), value]).map(|_| ())
+}
+
+/// Get the current playback position in seconds.
+pub fn position() -> Option<f64> {
+    get_property("time-pos").ok()?.as_f64()
+}
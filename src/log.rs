@@ -1,41 +1,487 @@
+use crate::config;
+use crate::time::{format_timestamp, now};
 use dirs;
-use std::fs::OpenOptions;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
 
-/// Function to write a log entry to a log file with a given level, module, and message
-fn write_log(level: &str, module: &str, message: &str) -> Result<()> {
-    if let Some(config_dir) = dirs::config_dir() {
-        let log_dir = config_dir.join("mpvy").join("log");
+/// Level of a log message. Messages below the `log_level` config are not written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+impl Level {
+    pub fn parse(value: &str) -> Option<Level> {
+        match value.trim().to_lowercase().as_str() {
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "warning" | "warn" => Some(Level::Warning),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warning => "WARNING",
+            Level::Error => "ERROR",
+        }
+    }
+}
+
+/// When the log files are rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rotate {
+    Size(u64), // When the file is bigger than given bytes
+    Daily,     // When the day changes
+}
+
+/// The logger of session. The log file is opened once and kept open until **mpvy** exits.
+struct Logger {
+    file: File,
+    path: PathBuf,
+    level: Level,
+    json: bool,
+    rotate: Rotate,
+    keep: usize,
+    size: u64, // Current size of the log file
+    day: u64,  // The day (since Unix epoch) of the last message in the log file
+}
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+/// Verbosity from `-v` (1) and `-vv` (2) arguments. Refer to `set_verbosity`.
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// Set how much is printed to stderr (in addition to the log file):
+///
+/// ```txt
+/// 0 (default):  Nothing
+/// 1 (-v):       Info, warning and error log messages
+/// 2 (-vv):      Also debug messages, external command lines, exit codes and stderr
+/// ```
+pub fn set_verbosity(verbosity: u8) {
+    VERBOSITY.store(verbosity, Ordering::SeqCst);
+}
+
+pub fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::SeqCst)
+}
+
+/// Returns how much the argument increases verbosity: `-v` is 1, `-vv` is 2, `--verbose` is 1.
+/// Returns 0 if the argument is not a verbosity flag.
+fn verbosity_flag(arg: &str) -> usize {
+    match arg {
+        "--verbose" => 1,
+        arg if arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|c| c == 'v') => {
+            arg.len() - 1
+        }
+        _ => 0,
+    }
+}
+
+/// Set verbosity from arguments (`-v`, `-vv` or `-v -v`) and returns the other arguments.
+/// So verbosity flags can be given anywhere, even before subcommands like `mpvy -v history`.
+pub fn take_verbosity(args: Vec<String>) -> Vec<String> {
+    let count: usize = args.iter().map(|arg| verbosity_flag(arg)).sum();
+    set_verbosity(count.min(2) as u8);
+    args.into_iter()
+        .filter(|arg| verbosity_flag(arg) == 0)
+        .collect()
+}
+
+/// Returns the directory of log files (`$XDG_CONFIG_HOME/mpvy/log`)
+pub fn log_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("mpvy").join("log"))
+}
+
+/// Rotate the given log file: `mpvy.log` -> `mpvy.log.1` -> `mpvy.log.2` ...
+/// Only `keep` old files are kept, older ones are deleted.
+pub fn rotate_file(path: &Path, keep: usize) -> Result<()> {
+    let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+    if keep == 0 {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+    if numbered(keep).exists() {
+        fs::remove_file(numbered(keep))?;
+    }
+    for n in (1..keep).rev() {
+        if numbered(n).exists() {
+            fs::rename(numbered(n), numbered(n + 1))?;
+        }
+    }
+    if path.exists() {
+        fs::rename(path, numbered(1))?;
+    }
+    Ok(())
+}
+
+/// Returns the day (since Unix epoch) of the last modification of file.
+fn modified_day(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    let seconds: u64 = modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some(seconds / 86400)
+}
+
+/// Returns the number of old files to keep, from the `log_keep` config.
+fn keep_count(config: &HashMap<String, String>) -> usize {
+    config
+        .get(config::LOG_KEEP)
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(5)
+}
+
+impl Logger {
+    /// Open the log file with the options from configuration.
+    fn open() -> Result<Logger> {
+        let config: HashMap<String, String> = config::load().unwrap_or_default();
+        let level: Level = config
+            .get(config::LOG_LEVEL)
+            .and_then(|value| Level::parse(value))
+            .unwrap_or(Level::Info);
+        let json: bool = config.get(config::LOG_FORMAT).map(|value| value.as_str()) == Some("json");
+        let rotate: Rotate = match config.get(config::LOG_ROTATE).map(|value| value.as_str()) {
+            Some("daily") => Rotate::Daily,
+            _ => Rotate::Size(
+                config
+                    .get(config::LOG_MAX_SIZE)
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or(1024 * 1024),
+            ),
+        };
+
+        let log_dir: PathBuf = log_dir().ok_or(std::io::ErrorKind::NotFound)?;
         if !log_dir.exists() {
-            std::fs::create_dir_all(&log_dir)?;
+            fs::create_dir_all(&log_dir)?;
         }
-        let log_file_path = log_dir.join("mpvy.log");
-        let mut file = OpenOptions::new()
+        let path: PathBuf = log_dir.join("mpvy.log");
+        let day: u64 = modified_day(&path).unwrap_or(now() / 86400);
+        let file: File = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size: u64 = file.metadata()?.len();
+
+        let mut logger: Logger = Logger {
+            file,
+            path,
+            level,
+            json,
+            rotate,
+            keep: keep_count(&config),
+            size,
+            day,
+        };
+        logger.rotate_if_needed()?;
+        Ok(logger)
+    }
+
+    /// Rotate the log file if it is too big or it is from another day.
+    fn rotate_if_needed(&mut self) -> Result<()> {
+        let today: u64 = now() / 86400;
+        let needed: bool = match self.rotate {
+            Rotate::Size(max) => self.size >= max,
+            Rotate::Daily => self.day != today && self.size > 0,
+        };
+        self.day = today;
+        if !needed {
+            return Ok(());
+        }
+        rotate_file(&self.path, self.keep)?;
+        self.file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(log_file_path)?;
-        writeln!(file, "[{}] ({}) --> {}", level, module, message)?;
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, level: Level, module: &str, message: &str) -> Result<()> {
+        if level < self.level {
+            return Ok(());
+        }
+        self.rotate_if_needed()?;
+        let time: String = format_timestamp(now());
+        let line: String = if self.json {
+            json!({
+                "time": time,
+                "level": level.as_str(),
+                "module": module,
+                "message": message,
+            })
+            .to_string()
+        } else {
+            format!("{} [{}] ({}) --> {}", time, level.as_str(), module, message)
+        };
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// Function to write a log entry to the log file with a given level, module, and message.
+/// The log file is opened on the first message.
+/// Also mirrors the message to stderr with `-v` and `-vv`.
+fn write_log(level: Level, module: &str, message: &str) -> Result<()> {
+    let verbosity: u8 = verbosity();
+    if verbosity >= 2 || (verbosity == 1 && level >= Level::Info) {
+        eprintln!("[{}] ({}) --> {}", level.as_str(), module, message);
+    }
+    let mut logger = LOGGER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if logger.is_none() {
+        *logger = Some(Logger::open()?);
+    }
+    logger.as_mut().unwrap().write(level, module, message)
+}
+
+/// Format the command line of a command as a shell would accept it, arguments with spaces are quoted.
+/// Like: `yt-dlp 'ytsearch:some query' --print ...`
+pub fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg: String = arg.to_string_lossy().to_string();
+            if arg.contains('\n') {
+                // Like `$'line\nline'`, so the command line stays on one line
+                format!(
+                    "$'{}'",
+                    arg.replace('\\', "\\\\")
+                        .replace('\'', "\\'")
+                        .replace('\n', "\\n")
+                )
+            } else if arg.is_empty()
+                || arg
+                    .contains(|c: char| c.is_whitespace() || "'\"$\\`()<>|&;*?[]{}~!#".contains(c))
+            {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            } else {
+                arg
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Log the command line of an external command before it runs (`yt-dlp`, `mpv`, `cava`).
+pub fn command(module: &str, command: &Command) {
+    debug(
+        module,
+        &format!("Running command: {}", command_line(command)),
+    );
+}
+
+/// Log the exit code and stderr of an external command after it exits.
+pub fn command_exit(module: &str, status: Option<ExitStatus>, stderr: &str) {
+    let code: String = status
+        .and_then(|status| status.code())
+        .map(|code| code.to_string())
+        .unwrap_or("none (killed by signal)".to_string());
+    debug(module, &format!("Command exited with code: {}", code));
+    if !stderr.trim().is_empty() {
+        debug(module, &format!("Command stderr: {}", stderr.trim()));
+    }
+}
+
+/// Rotate `mpv.log` when **mpvy** starts, because `mpv` overwrites it.
+/// Instead of deleting old logs, they are kept as `mpv.log.1`, `mpv.log.2`...
+pub fn rotate_mpv_log() {
+    let Some(log_dir) = log_dir() else {
+        return;
+    };
+    let path: PathBuf = log_dir.join("mpv.log");
+    if !path.exists() {
+        return;
+    }
+    let keep: usize = keep_count(&config::load().unwrap_or_default());
+    match rotate_file(&path, keep) {
+        Ok(_) => info("Log Rotate", "Old mpv log file rotated."),
+        Err(e) => warning("Log Rotate", &format!("Unable to rotate mpv log: {}", e)),
     }
-    Ok(())
 }
 
 /// Log an error message for a given module
 pub fn error(module: &str, message: &str) {
-    if let Err(e) = write_log("ERROR", &module, &message) {
+    if let Err(e) = write_log(Level::Error, module, message) {
         eprintln!("Failed to write error log: {}", e);
     }
 }
 
 /// Log a warning message for a given module
 pub fn warning(module: &str, message: &str) {
-    if let Err(e) = write_log("WARNING", &module, &message) {
+    if let Err(e) = write_log(Level::Warning, module, message) {
         eprintln!("Failed to write warning log: {}", e);
     }
 }
 
 /// Log an informational message for a given module
 pub fn info(module: &str, message: &str) {
-    if let Err(e) = write_log("INFO", &module, &message) {
+    if let Err(e) = write_log(Level::Info, module, message) {
         eprintln!("Failed to write info log: {}", e);
     }
 }
+
+/// Log a debug message for a given module. Only written with `log_level = "debug"`.
+pub fn debug(module: &str, message: &str) {
+    if let Err(e) = write_log(Level::Debug, module, message) {
+        eprintln!("Failed to write debug log: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    /// Returns an empty directory for the log files of a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("mpvy-log-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn logger(dir: &Path, rotate: Rotate, keep: usize, json: bool) -> Logger {
+        let path: PathBuf = dir.join("mpvy.log");
+        let file: File = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        Logger {
+            size: file.metadata().unwrap().len(),
+            file,
+            path,
+            level: Level::Info,
+            json,
+            rotate,
+            keep,
+            day: now() / 86400,
+        }
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_files() {
+        let dir: PathBuf = temp_dir("size");
+        let mut logger: Logger = logger(&dir, Rotate::Size(100), 2, false);
+        for i in 0..20 {
+            logger
+                .write(Level::Info, "Log Test", &format!("Message {}", i))
+                .unwrap();
+        }
+        assert_eq!(files(&dir), ["mpvy.log", "mpvy.log.1", "mpvy.log.2"]);
+        let last: String = fs::read_to_string(dir.join("mpvy.log")).unwrap();
+        assert!(last.trim_end().ends_with("(Log Test) --> Message 19"));
+        // Debug messages are below the level, they are not written
+        logger.write(Level::Debug, "Log Test", "Hidden").unwrap();
+        assert!(!fs::read_to_string(dir.join("mpvy.log"))
+            .unwrap()
+            .contains("Hidden"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_daily() {
+        let dir: PathBuf = temp_dir("daily");
+        let mut logger: Logger = logger(&dir, Rotate::Daily, 3, false);
+        logger.write(Level::Info, "Log Test", "Today").unwrap();
+        logger
+            .write(Level::Info, "Log Test", "Still today")
+            .unwrap();
+        assert_eq!(files(&dir), ["mpvy.log"]);
+
+        logger.day -= 1; // The messages are from yesterday
+        logger.write(Level::Info, "Log Test", "Tomorrow").unwrap();
+        assert_eq!(files(&dir), ["mpvy.log", "mpvy.log.1"]);
+        assert_eq!(
+            fs::read_to_string(dir.join("mpvy.log.1"))
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+        assert!(fs::read_to_string(dir.join("mpvy.log"))
+            .unwrap()
+            .contains("Tomorrow"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let dir: PathBuf = temp_dir("json");
+        let mut logger: Logger = logger(&dir, Rotate::Size(1024), 1, true);
+        logger
+            .write(Level::Warning, "Log Test", "Quoted \"message\"")
+            .unwrap();
+        let content: String = fs::read_to_string(dir.join("mpvy.log")).unwrap();
+        let line: Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(line["level"], "WARNING");
+        assert_eq!(line["module"], "Log Test");
+        assert_eq!(line["message"], "Quoted \"message\"");
+        assert!(line["time"].is_string());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn takes_verbosity_flags() {
+        assert_eq!(verbosity_flag("-v"), 1);
+        assert_eq!(verbosity_flag("-vv"), 2);
+        assert_eq!(verbosity_flag("--verbose"), 1);
+        assert_eq!(verbosity_flag("-"), 0);
+        assert_eq!(verbosity_flag("-x"), 0);
+        assert_eq!(verbosity_flag("history"), 0);
+
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            take_verbosity(args(&["mpvy", "-v", "history", "-v"])),
+            ["mpvy", "history"]
+        );
+        assert_eq!(verbosity(), 2);
+        assert_eq!(
+            take_verbosity(args(&["mpvy", "-vvv", "--shuffle"])),
+            ["mpvy", "--shuffle"]
+        );
+        assert_eq!(verbosity(), 2); // At most 2
+        assert_eq!(take_verbosity(args(&["mpvy", "--verbose"])), ["mpvy"]);
+        assert_eq!(verbosity(), 1);
+        assert_eq!(
+            take_verbosity(args(&["mpvy", "-", "add"])),
+            ["mpvy", "-", "add"]
+        );
+        assert_eq!(verbosity(), 0);
+    }
+
+    #[test]
+    fn rotate_file_without_keep_removes_file() {
+        let dir: PathBuf = temp_dir("keep");
+        fs::write(dir.join("mpv.log"), "old").unwrap();
+        rotate_file(&dir.join("mpv.log"), 0).unwrap();
+        assert!(files(&dir).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,451 @@
+use crate::cache;
+use crate::config::{self, Program};
+use crate::log::{self, *};
+use crate::runner::CommandRunner;
+use crate::yt_dlp::get_download_path;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// A line of synced lyrics.
+/// Values:
+///
+/// ```txt
+/// time:         When the line starts in seconds
+/// text:         Text of the line
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Line {
+    pub time: f64,
+    pub text: String,
+}
+
+/// Where lyrics come from. Providers are tried in order until one of them finds lyrics
+/// (refer to `providers`), a stand-in provider can be given for testing.
+pub trait Provider {
+    /// Name of the provider for log messages.
+    fn name(&self) -> &'static str;
+    /// Get synced lyrics of the video whose audio is at `path`.
+    /// Returns `None` if the provider has no lyrics for it.
+    fn fetch(
+        &self,
+        runner: &dyn CommandRunner,
+        id: &str,
+        path: &str,
+    ) -> Result<Option<Vec<Line>>, String>;
+}
+
+/// Reads a `.lrc` file which sits next to the audio (like `mp3/<id>.lrc` for `mp3/<id>.mp3`).
+pub struct LrcFile;
+
+/// Downloads subtitles (or automatic captions) of the video with `yt-dlp`.
+pub struct Subtitles;
+
+/// Returns the path of `.lrc` file for the audio at `path`.
+pub fn lrc_path(path: &str) -> String {
+    Path::new(path)
+        .with_extension("lrc")
+        .to_string_lossy()
+        .to_string()
+}
+
+impl Provider for LrcFile {
+    fn name(&self) -> &'static str {
+        "lrc"
+    }
+
+    fn fetch(
+        &self,
+        _runner: &dyn CommandRunner,
+        _id: &str,
+        path: &str,
+    ) -> Result<Option<Vec<Line>>, String> {
+        let path: String = lrc_path(path);
+        if !fs::exists(&path).unwrap_or(false) {
+            return Ok(None);
+        }
+        let content: String =
+            fs::read_to_string(&path).map_err(|e| format!("Unable to read '{}': {}", path, e))?;
+        let lines: Vec<Line> = parse_lrc(&content);
+        Ok((!lines.is_empty()).then_some(lines))
+    }
+}
+
+/// Returns the directory where subtitles are downloaded before they are parsed (`mp3/lyrics`).
+fn subtitles_path() -> String {
+    format!("{}/lyrics", get_download_path())
+}
+
+impl Provider for Subtitles {
+    fn name(&self) -> &'static str {
+        "yt-dlp"
+    }
+
+    fn fetch(
+        &self,
+        runner: &dyn CommandRunner,
+        id: &str,
+        _path: &str,
+    ) -> Result<Option<Vec<Line>>, String> {
+        let directory: String = subtitles_path();
+        let langs: String = config::get_string(config::LYRICS_LANGS).unwrap_or("en.*".to_string());
+        let mut command: Command = Program::YtDlp.command();
+        command
+            .arg(id)
+            .arg("--skip-download") // Only subtitles, the audio is downloaded already
+            .arg("--write-subs") // Subtitles which are uploaded by the channel
+            .arg("--write-auto-subs") // Automatic captions if there are no subtitles
+            .arg("--sub-langs")
+            .arg(langs)
+            .arg("--sub-format") // WebVTT has timestamps for every line
+            .arg("vtt")
+            .arg("--no-playlist")
+            .arg("--output")
+            .arg(format!("{}/%(id)s.%(ext)s", directory)) // Files are named like `<id>.<lang>.vtt`
+            .args(Program::YtDlp.extra_args()); // Extra arguments from configuration
+        log::command("Lyrics Subtitles", &command);
+        let output = runner
+            .output(&mut command)
+            .map_err(|e| format!("Unable to execute 'yt-dlp': {}", e))?;
+        if !output.status.success() {
+            let stderr: String = String::from_utf8_lossy(&output.stderr).to_string();
+            log::command_exit("Lyrics Subtitles", Some(output.status), &stderr);
+            return Err("'yt-dlp' is unable to download subtitles.".to_string());
+        }
+
+        // Subtitles are only needed until they are parsed
+        let mut files: Vec<String> = fs::read_dir(&directory)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path().to_string_lossy().to_string())
+                    .filter(|file| {
+                        file.ends_with(".vtt")
+                            && Path::new(file).file_name().is_some_and(|name| {
+                                name.to_string_lossy().starts_with(&format!("{}.", id))
+                            })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        files.sort();
+        let lines: Vec<Line> = files
+            .first()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .map(|content| parse_vtt(&content))
+            .unwrap_or_default();
+        for file in &files {
+            let _ = fs::remove_file(file);
+        }
+        Ok((!lines.is_empty()).then_some(lines))
+    }
+}
+
+/// Returns the providers from the `lyrics` config.
+///
+/// ```txt
+/// off:      No lyrics (default)
+/// local:    Only `.lrc` files next to audios
+/// auto:     `.lrc` files, then subtitles or automatic captions from YouTube
+/// ```
+pub fn providers() -> Vec<Box<dyn Provider>> {
+    match config::get_string(config::LYRICS).as_deref() {
+        Some("local") => vec![Box::new(LrcFile)],
+        Some("auto") => vec![Box::new(LrcFile), Box::new(Subtitles)],
+        _ => vec![],
+    }
+}
+
+/// Parse a timestamp like `01:02.50` or `00:01:02.500` into seconds.
+fn parse_timestamp(text: &str) -> Option<f64> {
+    text.trim()
+        .split(':')
+        .try_fold(0.0, |total: f64, part: &str| {
+            part.parse::<f64>().ok().map(|value| total * 60.0 + value)
+        })
+        .filter(|_| text.contains(':'))
+}
+
+/// Parse lyrics in LRC format (`[mm:ss.xx] text`). A line can have more than one timestamp
+/// (for repeated lines), and the `[offset:<ms>]` tag shifts every line.
+pub fn parse_lrc(content: &str) -> Vec<Line> {
+    let mut lines: Vec<Line> = vec![];
+    let mut offset: f64 = 0.0;
+    for row in content.lines() {
+        let mut rest: &str = row.trim();
+        let mut times: Vec<f64> = vec![];
+        while let Some((tag, after)) = rest.strip_prefix('[').and_then(|tag| tag.split_once(']')) {
+            if let Some(value) = tag.strip_prefix("offset:") {
+                offset = value.trim().parse::<f64>().unwrap_or(0.0) / 1000.0;
+            } else if let Some(time) = parse_timestamp(tag) {
+                times.push(time);
+            }
+            rest = after;
+        }
+        for time in times {
+            lines.push(Line {
+                time,
+                text: rest.trim().to_string(),
+            });
+        }
+    }
+    // A positive offset shows lyrics sooner
+    for line in &mut lines {
+        line.time = (line.time - offset).max(0.0);
+    }
+    lines.sort_by(|a, b| a.time.total_cmp(&b.time));
+    lines
+}
+
+/// Remove tags (like `<00:00:01.500>`, `<c>`) and HTML entities from a WebVTT text line.
+fn clean_vtt_text(text: &str) -> String {
+    let mut clean: String = String::new();
+    let mut in_tag: bool = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => clean.push(c),
+            _ => {}
+        }
+    }
+    clean
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Parse lyrics from WebVTT subtitles. Automatic captions of YouTube repeat the previous
+/// Line in every cue (rolling captions), so lines which were in the previous cue are skipped.
+/// Sound descriptions like `[Music]` are skipped too.
+pub fn parse_vtt(content: &str) -> Vec<Line> {
+    let mut lines: Vec<Line> = vec![];
+    let mut previous: Vec<String> = vec![];
+    for cue in content.replace("\r\n", "\n").split("\n\n") {
+        let mut rows = cue.lines().skip_while(|row| !row.contains("-->"));
+        let Some(time) = rows
+            .next()
+            .and_then(|row| row.split("-->").next())
+            .and_then(parse_timestamp)
+        else {
+            continue;
+        };
+        let texts: Vec<String> = rows
+            .map(clean_vtt_text)
+            .filter(|text| !text.is_empty())
+            .collect();
+        let new: Vec<&String> = texts
+            .iter()
+            .filter(|text| !previous.contains(text))
+            .filter(|text| !(text.starts_with('[') && text.ends_with(']')))
+            .collect();
+        if !new.is_empty() {
+            lines.push(Line {
+                time,
+                text: new
+                    .iter()
+                    .map(|text| text.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(" "),
+            });
+        }
+        previous = texts;
+    }
+    lines
+}
+
+/// Find lyrics of the video with the given providers and store them in the cache index.
+/// Nothing is done if lyrics are already stored. If no provider has lyrics, an empty list is
+/// Stored so they are not searched again, but a new `.lrc` file is still picked up.
+/// Errors are only logged, the audio is played without lyrics.
+pub fn prepare(runner: &dyn CommandRunner, id: &str, path: &str, providers: &[Box<dyn Provider>]) {
+    if providers.is_empty() {
+        return;
+    }
+    if let Some(lines) = cache::get(id).and_then(|entry| entry.lyrics) {
+        if !lines.is_empty() || !fs::exists(lrc_path(path)).unwrap_or(false) {
+            return;
+        }
+    }
+    let mut found: Vec<Line> = vec![];
+    for provider in providers {
+        match provider.fetch(runner, id, path) {
+            Ok(Some(lines)) => {
+                info(
+                    "Lyrics Prepare",
+                    &format!(
+                        "Found {} lines of lyrics for '{}' with '{}'.",
+                        lines.len(),
+                        id,
+                        provider.name()
+                    ),
+                );
+                found = lines;
+                break;
+            }
+            Ok(None) => debug(
+                "Lyrics Prepare",
+                &format!("'{}' has no lyrics for '{}'.", provider.name(), id),
+            ),
+            Err(e) => warning(
+                "Lyrics Prepare",
+                &format!(
+                    "Unable to get lyrics of '{}' with '{}': {}",
+                    id,
+                    provider.name(),
+                    e
+                ),
+            ),
+        }
+    }
+    cache::update(id, |entry| entry.lyrics = Some(found));
+}
+
+/// Returns the stored lyrics of the video. It is empty if there are no lyrics.
+pub fn get(id: &str) -> Vec<Line> {
+    cache::get(id)
+        .and_then(|entry| entry.lyrics)
+        .unwrap_or_default()
+}
+
+/// Returns the index of the line which is sung at the given position (in seconds).
+pub fn line_at(lines: &[Line], position: f64) -> Option<usize> {
+    lines.iter().rposition(|line| position >= line.time)
+}
+
+/// Render lyrics with the current line marked with `>`. Only `context` lines before and after
+/// The current line are included (all lines if `context` is `None`).
+/// If `bold` is true, the current line is highlighted with terminal escape codes.
+pub fn render(
+    lines: &[Line],
+    current: Option<usize>,
+    context: Option<usize>,
+    bold: bool,
+) -> String {
+    let (first, last) = match (current, context) {
+        (Some(i), Some(context)) => (i.saturating_sub(context), i + context),
+        (None, Some(context)) => (0, context),
+        (_, None) => (0, lines.len()),
+    };
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i >= first && *i <= last)
+        .map(|(i, line)| match current == Some(i) {
+            true if bold => format!("\x1b[1m> {}\x1b[0m", line.text),
+            true => format!("> {}", line.text),
+            false => format!("  {}", line.text),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::{sandbox, Canned, MockRunner};
+
+    /// A stand-in provider which always has the same lyrics.
+    struct Fixed(Vec<Line>);
+
+    impl Provider for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn fetch(
+            &self,
+            _runner: &dyn CommandRunner,
+            _id: &str,
+            _path: &str,
+        ) -> Result<Option<Vec<Line>>, String> {
+            Ok(Some(self.0.clone()))
+        }
+    }
+
+    fn line(time: f64, text: &str) -> Line {
+        Line {
+            time,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_lrc() {
+        let content: &str = "[ar:Artist]\n[offset:500]\n[00:12.00]First line\n[00:05.50][01:02.00]Chorus\nno time\n";
+        assert_eq!(
+            parse_lrc(content),
+            [
+                line(5.0, "Chorus"),
+                line(11.5, "First line"),
+                line(61.5, "Chorus")
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_rolling_vtt() {
+        let content: &str = "WEBVTT\nKind: captions\n\n\
+            00:00:00.000 --> 00:00:02.000\n[Music]\n\n\
+            00:00:02.000 --> 00:00:04.500 align:start position:0%\n \n\
+            hello<00:00:02.500><c> world</c>\n\n\
+            00:00:04.500 --> 00:00:04.510\nhello world\n\n\
+            00:00:04.510 --> 00:00:07.000\nhello world\nhow &amp; why\n";
+        assert_eq!(
+            parse_vtt(content),
+            [line(2.0, "hello world"), line(4.51, "how & why")]
+        );
+    }
+
+    #[test]
+    fn prepares_once() {
+        sandbox();
+        let providers: Vec<Box<dyn Provider>> = vec![Box::new(Fixed(vec![line(1.0, "la")]))];
+        let runner: MockRunner = MockRunner::new(vec![]);
+        prepare(&runner, "lyricsonce", "/tmp/lyricsonce.mp3", &providers);
+        assert_eq!(get("lyricsonce"), [line(1.0, "la")]);
+
+        let providers: Vec<Box<dyn Provider>> = vec![Box::new(Fixed(vec![line(2.0, "other")]))];
+        prepare(&runner, "lyricsonce", "/tmp/lyricsonce.mp3", &providers);
+        assert_eq!(get("lyricsonce"), [line(1.0, "la")]);
+    }
+
+    #[test]
+    fn subtitles_builds_command() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::default()]);
+        assert_eq!(Subtitles.fetch(&runner, "abc", "/tmp/abc.mp3"), Ok(None));
+        assert_eq!(
+            runner.calls(),
+            [[
+                "yt-dlp",
+                "abc",
+                "--skip-download",
+                "--write-subs",
+                "--write-auto-subs",
+                "--sub-langs",
+                "en.*",
+                "--sub-format",
+                "vtt",
+                "--no-playlist",
+                "--output",
+                &format!("{}/%(id)s.%(ext)s", subtitles_path()),
+            ]]
+        );
+    }
+
+    #[test]
+    fn renders_current_line() {
+        let lines: Vec<Line> = vec![line(0.0, "a"), line(5.0, "b"), line(9.0, "c")];
+        assert_eq!(line_at(&lines, 6.0), Some(1));
+        assert_eq!(line_at(&[line(3.0, "a")], 1.0), None);
+        assert_eq!(render(&lines, Some(2), Some(1), false), "  b\n> c");
+    }
+}
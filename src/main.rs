@@ -1,16 +1,40 @@
+pub mod cache;
 pub mod config;
+pub mod daemon;
+pub mod doctor;
+pub mod events;
+pub mod history;
+pub mod ipc;
 pub mod log;
+pub mod lyrics;
+#[cfg(feature = "mpris")]
+pub mod mpris;
+pub mod normalize;
+pub mod player;
 pub mod playlist;
+pub mod prompt;
+pub mod query;
+pub mod queue;
+pub mod radio;
+pub mod runner;
+pub mod search;
 pub mod service;
+pub mod shell;
+pub mod sponsorblock;
+pub mod state;
+pub mod supervisor;
+pub mod time;
 pub mod yt_dlp;
 use crate::log::*;
-use std::fs::File;
-use std::io::{self, Write};
-use std::path::PathBuf;
-use std::process::Command;
-use std::str::Split;
-use std::time::Duration;
-use std::{env, fs};
+use crate::player::Player;
+use crate::prompt::Prompt;
+use crate::query::QueueItem;
+use crate::queue::{PlayMode, Queue, Repeat};
+use crate::runner::SystemRunner;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{env, fs, thread};
 
 /// Clear the console with some unicode char.
 fn clear_console() {
@@ -18,25 +42,12 @@ fn clear_console() {
     io::stdout().flush().unwrap();
 }
 
-/// Convert `yt-dlp` video duration from string to u64 (seconds).
-/// Supports both of **HH:MM** and **HH:MM:SS** formats.
-/// If an unsupported format given, returns 0.
-fn duration_to_seconds(duration: &str) -> u64 {
-    let parts: Vec<&str> = duration.split(':').collect();
-    match parts.len() {
-        2 => {
-            let minutes: u64 = parts[0].parse().unwrap_or(0);
-            let seconds: u64 = parts[1].parse().unwrap_or(0);
-            minutes * 60 + seconds
-        }
-        3 => {
-            let hours: u64 = parts[0].parse().unwrap_or(0);
-            let minutes: u64 = parts[1].parse().unwrap_or(0);
-            let seconds: u64 = parts[2].parse().unwrap_or(0);
-            hours * 3600 + minutes * 60 + seconds
-        }
-        _ => 0,
-    }
+/// Prepare the cache before playing: rename old files, remove unfinished downloads and
+/// Corrupt audios (refer to `src/cache.rs`).
+fn prepare_cache() {
+    cache::migrate();
+    cache::clean_staging();
+    cache::verify(&SystemRunner);
 }
 
 /// If audio file count is more than the **MAX_FILE_COUNT** (refer to `src/config.rs`)
@@ -45,15 +56,11 @@ fn duration_to_seconds(duration: &str) -> u64 {
 fn clean_old_mp3_files() {
     let config = config::get_config();
     let mut count: usize = 15;
-    if config.is_ok()
-        && config
-            .as_ref()
-            .unwrap()
-            .contains_key(config::MAX_FILE_COUNT)
+    if let Some(value) = config
+        .ok()
+        .and_then(|c| c.get(config::MAX_FILE_COUNT).cloned())
     {
-        count = config.unwrap()[config::MAX_FILE_COUNT]
-            .parse::<usize>()
-            .unwrap();
+        count = value.parse::<usize>().unwrap();
     }
     info(
         "Mpvy CleanOldFiles",
@@ -71,6 +78,7 @@ fn clean_old_mp3_files() {
         .unwrap()
         .filter_map(|entry| entry.ok())
         .filter(|e| e.file_type().unwrap().is_file())
+        .filter(|e| cache::is_audio_file(&e.file_name().to_string_lossy()))
         .collect::<Vec<_>>();
     // Sort files by their last modified date
     files.sort_by(|a, b| {
@@ -82,6 +90,20 @@ fn clean_old_mp3_files() {
     });
     if files.len() > count {
         let files_to_remove: usize = files.len() - count;
+        let names: Vec<String> = files
+            .iter()
+            .take(files_to_remove)
+            .map(|file| file.file_name().to_string_lossy().to_string())
+            .collect();
+        // Entries and split chapters of deleted audios are removed too
+        let ids: Vec<&str> = names
+            .iter()
+            .filter_map(|name| cache::id_from_file(name))
+            .collect();
+        cache::remove(&ids);
+        for id in &ids {
+            let _ = fs::remove_dir_all(yt_dlp::get_chapters_path(id));
+        }
         for file in files.iter().take(files_to_remove) {
             info(
                 "Mpvy CleanOldFiles",
@@ -97,66 +119,39 @@ fn clean_old_mp3_files() {
     }
 }
 
-/// Recreate all the log files (`mpv.log` and `mpvy.log`) from scratch
-/// For removing old log messages
-/// When `mpvy` runned newly.
-fn clean_log_files() {
-    let log_dir = dirs::config_dir().unwrap().join("mpvy/log");
-    if !log_dir.exists() {
-        return;
-    }
-    let mpv_log_path = log_dir.join("mpv.log");
-    let mpvy_log_path = log_dir.join("mpvy.log");
-    if mpv_log_path.exists() {
-        File::create(mpv_log_path).unwrap();
-    }
-    if mpvy_log_path.exists() {
-        File::create(mpvy_log_path).unwrap();
-    }
-
-    info("Mpvy CleanLogFiles", "Old log files deleted.");
-}
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let cava_enabled: bool = args.contains(&"--cava".to_string());
-    let save_playlist: Option<String> = args
-        .iter()
-        .position(|arg| arg == "--save-playlist")
-        .and_then(|i| args.get(i + 1).map(|s| s.to_string()));
-
-    clean_log_files();
-    clean_old_mp3_files();
-    clear_console();
+/// Prompt user for queries (or a playlist) and build the queue from them.
+/// Returns the queue and the name of the playing playlist (if any).
+fn read_queue(
+    save_playlist: Option<String>,
+    shuffle: bool,
+    repeat: Option<Repeat>,
+) -> (Queue, Option<String>) {
     info("Mpvy Main", "Getting input for queries.");
 
-    let playlists: Result<Vec<String>, String> = playlist::get_playlists();
-
-    if playlists.is_err() {
-        error(
-            "Mpvy Playlists",
-            "'get_playlists' returned an Err value. Don't showing playlists.",
-        )
-    } else {
+    if let Ok(playlists) = playlist::get_playlists() {
         println!("---Playlists----------------------------");
-        for playlist in playlists.unwrap() {
+        for playlist in playlists {
             println!(" {}", playlist);
         }
         println!("----------------------------------------");
         println!("Write !playlist [playlist_name] to select playlist.");
+    } else {
+        error(
+            "Mpvy Playlists",
+            "'get_playlists' returned an Err value. Don't showing playlists.",
+        )
     }
-    let mut input: String = String::new();
-
-    print!("search (separated by commas) --> ");
-    std::io::stdout().flush().unwrap();
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Unexpected Error: Failed to read user input from terminal.");
+    // The prompt has line editing, history (up/down) and completion (tab), refer to `src/prompt.rs`.
+    let mut prompt: Prompt = Prompt::new(&prompt::COMMANDS)
+        .expect("Unexpected Error: Failed to create prompt for user input.");
+    let input: String = prompt
+        .read("search (separated by commas) --> ")
+        .unwrap_or_default();
 
     // If there is some playlist to save, write it to the file.
-    if let Some(playlist_name) = save_playlist {
+    if let Some(playlist_name) = &save_playlist {
         let result: Result<(), String> =
-            playlist::write_playlist(&playlist_name, input.trim().to_string());
+            playlist::write_playlist(playlist_name, input.trim().to_string());
 
         if result.is_err() {
             error(
@@ -179,77 +174,189 @@ fn main() {
         std::process::exit(0);
     }
 
+    let mut playing_playlist: Option<String> = save_playlist;
+    let mut items: Option<Vec<QueueItem>> = None; // Items of the playlist
     if input.trim().starts_with("!playlist ") {
         info(
             "Mpvy Main",
             "User prefixed input with '!playlist '. Resolving and playing playlist.",
         );
         let playlist: String = input.trim().replace("!playlist ", "");
-        let playlist_content: Result<String, String> = playlist::read_playlist(&playlist);
-        if playlist_content.is_err() {
-            error("Mpvy PlaylistCheck", "Playlist Content returned an Err value. Exiting with code 1 because nothing to play.");
-            println!("Playlist not found (or another error occured). Please check logs for more information.");
+        match playlist::read_items(&playlist) {
+            Ok(playlist_items) => items = Some(playlist_items),
+            Err(_) => {
+                error("Mpvy PlaylistCheck", "Playlist Content returned an Err value. Exiting with code 1 because nothing to play.");
+                println!("Playlist not found (or another error occured). Please check logs for more information.");
+                std::process::exit(1);
+            }
+        }
+        playing_playlist = Some(playlist);
+    }
+
+    // Play mode from arguments overrides the saved play mode of playlist.
+    let mut mode: PlayMode = playing_playlist
+        .as_deref()
+        .and_then(playlist::read_mode)
+        .unwrap_or_default();
+    if shuffle {
+        mode.shuffle = true;
+    }
+    if let Some(repeat) = repeat {
+        mode.repeat = repeat;
+    }
+    if let Some(name) = &playing_playlist {
+        if shuffle || repeat.is_some() {
+            let _ = playlist::write_mode(name, &mode);
+        }
+    }
+
+    // Split queries with commas, refer to `src/query.rs` for quotes and modifiers
+    let items: Vec<QueueItem> = match items.map(Ok).unwrap_or_else(|| query::parse(&input)) {
+        Ok(items) => items,
+        Err(e) => {
+            error("Mpvy Main", &format!("Invalid query: {}", e));
+            println!("Invalid query: {}", e);
             std::process::exit(1);
         }
-        input = playlist_content.unwrap();
+    };
+    // Queries like `artist:<name>` are expanded into their videos
+    let items: Vec<QueueItem> = search::expand(&SystemRunner, items);
+    (Queue::new(items, mode), playing_playlist)
+}
+
+fn main() {
+    let args: Vec<String> = take_verbosity(env::args().collect());
+    let program: Option<&str> = args
+        .first()
+        .and_then(|arg| Path::new(arg).file_name())
+        .and_then(|name| name.to_str());
+    // `mpvyd` can be a symbolic link to `mpvy` for starting the daemon.
+    if program == Some("mpvyd") {
+        rotate_mpv_log();
+        prepare_cache();
+        std::process::exit(daemon::run(&args[1..]));
+    }
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("history") => std::process::exit(history::run(&args[2..])),
+        Some("doctor") => std::process::exit(doctor::run(&args[2..])),
+        Some("daemon") => {
+            rotate_mpv_log();
+            prepare_cache();
+            std::process::exit(daemon::run(&args[2..]));
+        }
+        Some(command) if daemon::COMMANDS.contains(&command) => {
+            std::process::exit(daemon::client(command, &args[2..]))
+        }
+        _ => {}
     }
 
-    // Split querys with commas
-    let titles: Split<'_, &str> = input.trim().split(",");
-    let cava_process: Option<std::process::Child> = if cava_enabled {
-        info("Mpvy Cava", "Cava is enabled. Starting child process.");
-        Some(Command::new("cava").spawn().expect(
-            "Unexpected Error: Failed to start 'cava' process. Maybe 'cava' is not installed?",
-        ))
+    let cava_enabled: bool = args.contains(&"--cava".to_string());
+    let resume: bool = args.contains(&"--resume".to_string());
+    let save_playlist: Option<String> = args
+        .iter()
+        .position(|arg| arg == "--save-playlist")
+        .and_then(|i| args.get(i + 1).map(|s| s.to_string()));
+    let shuffle: bool = args.contains(&"--shuffle".to_string());
+    let autoplay: bool = args.contains(&"--autoplay".to_string());
+    let repeat: Option<Repeat> = if args.contains(&"--loop".to_string()) {
+        Some(Repeat::All)
     } else {
-        None
+        args.iter()
+            .position(|arg| arg == "--repeat")
+            .and_then(|i| args.get(i + 1))
+            .map(|value| {
+                Repeat::parse(value).unwrap_or_else(|| {
+                    println!(
+                        "Invalid repeat mode: '{}'. Use 'off', 'one' or 'all'.",
+                        value
+                    );
+                    std::process::exit(1);
+                })
+            })
     };
 
-    let mut wait_duration: u64 = 0;
-    let mut index = 0;
-    let mut is_single_audio = false; // Flag for single audio control.
-
-    for (i, title) in titles.clone().enumerate() {
-        // If it is single audio, set flag as `true` 
-        if i == 0 && titles.clone().count() == 1 {
-            is_single_audio = true;
+    // Two players would share the `mpv` IPC socket (refer to `src/service.rs`), so the foreground
+    // Doesn't play next to the daemon or another foreground.
+    match daemon::owner().as_deref() {
+        None => {}
+        Some("daemon") => {
+            println!("mpvyd is playing. Add queries with 'mpvy add <query>', or stop it with 'mpvy stop'.");
+            std::process::exit(1);
         }
+        Some(_) => {
+            println!("Another mpvy is playing. Quit it before starting a new one.");
+            std::process::exit(1);
+        }
+    }
+
+    // Children are terminated and the state is saved on Ctrl-C (refer to `src/supervisor.rs`).
+    supervisor::install();
+    rotate_mpv_log();
+    prepare_cache();
+    clean_old_mp3_files();
+    clear_console();
 
+    let mut start: Option<u64> = None; // Position of the first audio (when resuming)
+    let (queue, playing_playlist): (Queue, Option<String>) = if resume {
+        let Some(state) = state::load() else {
+            info("Mpvy Resume", "There is no saved session to resume.");
+            println!("There is no session to resume. Exiting with code 1.");
+            std::process::exit(1);
+        };
         info(
-            "Mpvy TitleLoop",
-            &format!("Reached query in loop: '{}'.", title.trim()),
+            "Mpvy Resume",
+            &format!(
+                "Resuming '{}' from {} seconds.",
+                state.title, state.position as u64
+            ),
+        );
+        println!(
+            "Resuming '{}' from {}.",
+            state.title,
+            time::format_duration(state.position as u64)
         );
+        start = Some(state.position as u64);
+        let mut queue: Queue = state.queue;
+        queue.resume();
+        (queue, state.playlist)
+    } else {
+        read_queue(save_playlist, shuffle, repeat)
+    };
 
-        // If it is first audio or single audio
-        if is_single_audio || index == 0 {
-            let video_info = service::play(title.trim(), wait_duration).unwrap();
-            let duration_in_seconds = duration_to_seconds(&video_info.duration);
-
-            if is_single_audio {
-                // If it is single audio, wait in main_thread because of Cava.
-                std::thread::sleep(Duration::from_secs(duration_in_seconds));
-            } else {
-                // If it is first audio, wait in main_thread just for first audio. 
-                wait_duration = duration_in_seconds;
-            }
-        } else {
-            // Play other audios normally
-            let video_info = service::play(title.trim(), wait_duration).unwrap();
-            let duration_in_seconds = duration_to_seconds(&video_info.duration);
-            wait_duration = duration_in_seconds;
-        }
+    let player: Arc<Player> = Arc::new(Player::new(queue, playing_playlist));
+    if autoplay {
+        player.set_autoplay(true);
+    }
+    // Cava is terminated by the supervisor on exit
+    let cava_process: Option<std::process::Child> =
+        if cava_enabled { service::cava() } else { None };
 
-        index += 1;
+    // The shell stays open during playback, and the player waits for new audios at the end of
+    // The queue until user quits (refer to `src/shell.rs`). Cava uses the terminal, so there is
+    // No shell with it.
+    let interactive: bool = cava_process.is_none() && io::stdin().is_terminal();
+    if cava_process.is_none() {
+        let controls = Arc::clone(&player);
+        let lyrics: bool = !lyrics::providers().is_empty();
+        thread::spawn(move || shell::run(controls, interactive, lyrics));
     }
 
-    // If there is a Cava process, kill it.
-    if let Some(mut cava) = cava_process {
-        if let Err(e) = cava.kill() {
-            error("Mpvy Cava", &format!("Failed to kill 'cava': {}", e));
-        } else {
-            info("Mpvy Cava", "Cava process terminated.");
-        }
+    // Let `mpvy status` and `mpvy events` see the foreground player too. Nothing listens on the
+    // Socket (checked above), so the player works without it if it can't be created.
+    if let Err(e) = daemon::listen(Arc::clone(&player)) {
+        warning(
+            "Mpvy Main",
+            &format!("Unable to listen on the daemon socket: {}", e),
+        );
     }
 
+    #[cfg(feature = "mpris")]
+    let _ = mpris::start(Arc::clone(&player));
+
+    player.run(start, interactive);
+
+    // Terminate Cava (and anything else which is still running), remove the sockets.
+    supervisor::shutdown("Playback ended. Exiting.");
+
     info("Mpvy Main", "Reached end of file.");
 }
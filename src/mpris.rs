@@ -0,0 +1,436 @@
+use crate::ipc;
+use crate::log::*;
+use crate::player::Player;
+use crate::time::parse_duration;
+use crate::yt_dlp::{artist_and_track, VideoInfo};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use zbus::blocking::connection;
+use zbus::interface;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+
+/// Bus name of **mpvy** on the session bus.
+pub const BUS_NAME: &str = "org.mpris.MediaPlayer2.mpvy";
+/// Object path which is required by MPRIS2 specification.
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+/// `org.mpris.MediaPlayer2` interface. Only describes **mpvy**, there is nothing to control.
+struct Root;
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> String {
+        "mpvy".to_string()
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec![]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// `org.mpris.MediaPlayer2.Player` interface. Routes media keys into the `Player`.
+struct MediaPlayer {
+    player: Arc<Player>,
+}
+
+/// Convert a value into `OwnedValue` for metadata map.
+fn owned<'a>(value: impl Into<Value<'a>>) -> OwnedValue {
+    value
+        .into()
+        .try_to_owned()
+        .expect("Unexpected Error: Unable to convert metadata value.")
+}
+
+/// Track ID of the video for MPRIS. It must be a valid D-Bus object path.
+fn track_id(video: &VideoInfo) -> ObjectPath<'static> {
+    let id: String = video
+        .id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    ObjectPath::try_from(format!("/org/mpvy/track/{}", id))
+        .unwrap_or_else(|_| ObjectPath::from_static_str_unchecked("/org/mpvy/track/unknown"))
+}
+
+/// Build MPRIS metadata from video information.
+fn metadata(video: &VideoInfo) -> HashMap<String, OwnedValue> {
+    let mut metadata: HashMap<String, OwnedValue> = HashMap::new();
+    metadata.insert("mpris:trackid".to_string(), owned(track_id(video)));
+    // Like the embedded tags, "Artist - Track" titles are split (refer to `yt_dlp::artist_and_track`)
+    let (artist, track) = artist_and_track(video);
+    metadata.insert("xesam:title".to_string(), owned(track));
+    metadata.insert("xesam:artist".to_string(), owned(vec![artist]));
+    metadata.insert(
+        "mpris:length".to_string(),
+        owned(parse_duration(&video.duration) as i64 * 1_000_000),
+    );
+    metadata.insert(
+        "mpris:artUrl".to_string(),
+        owned(format!("https://i.ytimg.com/vi/{}/hqdefault.jpg", video.id)),
+    );
+    metadata.insert("xesam:url".to_string(), owned(video.url.as_str()));
+    metadata
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MediaPlayer {
+    fn next(&self) {
+        let _ = self.player.skip();
+    }
+
+    fn previous(&self) {
+        let _ = self.player.previous();
+    }
+
+    fn pause(&self) {
+        let _ = self.player.set_paused(true);
+    }
+
+    /// Unpause the audio, or play it from the beginning if the playback is stopped.
+    fn play(&self) {
+        if !self.player.resume() {
+            let _ = self.player.set_paused(false);
+        }
+    }
+
+    fn play_pause(&self) {
+        if !self.player.resume() {
+            let _ = self.player.set_paused(!self.player.is_paused());
+        }
+    }
+
+    /// Stop the playback, `Play` starts the audio again (refer to `Player::halt`).
+    fn stop(&self) {
+        let _ = self.player.halt();
+    }
+
+    /// Seek forward or backward (offset is in microseconds).
+    fn seek(&self, offset: i64) {
+        let _ = self.player.seek(offset as f64 / 1_000_000.0, true);
+    }
+
+    /// Seek to the given position (in microseconds) if the track is still playing.
+    fn set_position(&self, track: ObjectPath<'_>, position: i64) {
+        let Some(video) = self.player.now_playing() else {
+            return;
+        };
+        if track_id(&video) == track {
+            let _ = self.player.seek(position as f64 / 1_000_000.0, false);
+        }
+    }
+
+    /// Sent when the position changes other than by playing, like seeks and chapter jumps.
+    /// The position is in microseconds. It is emitted by the watcher in `start`.
+    #[zbus(signal)]
+    async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        if self.player.now_playing().is_none() {
+            "Stopped".to_string()
+        } else if self.player.is_paused() {
+            "Paused".to_string()
+        } else {
+            "Playing".to_string()
+        }
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        self.player
+            .now_playing()
+            .map(|video| metadata(&video))
+            .unwrap_or_default()
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        (ipc::position().unwrap_or(0.0) * 1_000_000.0) as i64
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// Returns `true` if the position is changed by a seek, not by playing. While playing, the
+/// Position should move forward as much as the time which is passed since the last reading.
+fn is_seek(last: f64, position: f64, elapsed: f64, paused: bool) -> bool {
+    let expected: f64 = if paused { last } else { last + elapsed };
+    (position - expected).abs() > 1.0
+}
+
+/// Start the MPRIS2 server on the session bus, so media keys, `playerctl` and desktop widgets
+/// Can see and control **mpvy**. Changes of the playing audio are checked every second
+/// And published with `PropertiesChanged` signals, and seeks with `Seeked` signals.
+pub fn start(player: Arc<Player>) -> Result<(), String> {
+    let connection = connection::Builder::session()
+        .and_then(|builder| builder.name(BUS_NAME))
+        .and_then(|builder| builder.serve_at(OBJECT_PATH, Root))
+        .and_then(|builder| {
+            builder.serve_at(
+                OBJECT_PATH,
+                MediaPlayer {
+                    player: Arc::clone(&player),
+                },
+            )
+        })
+        .and_then(|builder| builder.build())
+        .map_err(|e| {
+            error(
+                "Mpris Start",
+                &format!("Unable to start MPRIS server: {}", e),
+            );
+            e.to_string()
+        })?;
+    info(
+        "Mpris Start",
+        &format!("MPRIS server started as '{}'.", BUS_NAME),
+    );
+
+    thread::spawn(move || {
+        let iface = match connection
+            .object_server()
+            .interface::<_, MediaPlayer>(OBJECT_PATH)
+        {
+            Ok(iface) => iface,
+            Err(e) => {
+                error("Mpris Watch", &format!("Unable to get interface: {}", e));
+                return;
+            }
+        };
+        let emitter: &SignalEmitter<'static> = iface.signal_emitter();
+        let mut last_track: Option<String> = None;
+        let mut last_status: String = String::new();
+        let mut last_position: Option<f64> = None;
+        let mut last_check: Instant = Instant::now();
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let track: Option<String> = player.now_playing().map(|video| video.id);
+            let media_player = iface.get();
+            let status: String = media_player.playback_status();
+            let position: Option<f64> = track.as_ref().and_then(|_| ipc::position());
+            if track != last_track {
+                let _ = zbus::block_on(media_player.metadata_changed(emitter));
+                last_track = track;
+            } else if let Some((last, position)) = last_position.zip(position) {
+                let elapsed: f64 = last_check.elapsed().as_secs_f64();
+                if is_seek(last, position, elapsed, status == "Paused") {
+                    let microseconds: i64 = (position * 1_000_000.0) as i64;
+                    let _ = zbus::block_on(MediaPlayer::seeked(emitter, microseconds));
+                }
+            }
+            last_position = position;
+            last_check = Instant::now();
+            if status != last_status {
+                let _ = zbus::block_on(media_player.playback_status_changed(emitter));
+                last_status = status;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{PlayMode, Queue};
+    use crate::runner::mock::sandbox;
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+    use zbus::blocking::{Connection, Proxy};
+
+    /// Configuration of a private session bus, so the test doesn't use the desktop bus.
+    const BUS_CONFIG: &str = r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:tmpdir={dir}</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>"#;
+
+    #[test]
+    fn builds_metadata() {
+        let video: VideoInfo = VideoInfo {
+            duration: "00:03:25".to_string(),
+            title: "The Beatles - Hello, Goodbye (Official Video)".to_string(),
+            url: "https://www.youtube.com/watch?v=ab-C_1".to_string(),
+            id: "ab-C_1".to_string(),
+            channel: "The Beatles".to_string(),
+            chapters: vec![],
+        };
+        let metadata: HashMap<String, OwnedValue> = metadata(&video);
+        let text = |key: &str| String::try_from(metadata[key].try_clone().unwrap()).unwrap();
+        assert_eq!(text("xesam:title"), "Hello, Goodbye");
+        assert_eq!(
+            Vec::<String>::try_from(metadata["xesam:artist"].try_clone().unwrap()).unwrap(),
+            ["The Beatles"]
+        );
+        assert_eq!(
+            i64::try_from(metadata["mpris:length"].try_clone().unwrap()).unwrap(),
+            205_000_000
+        );
+        assert_eq!(
+            ObjectPath::try_from(metadata["mpris:trackid"].try_clone().unwrap())
+                .unwrap()
+                .as_str(),
+            "/org/mpvy/track/ab_C_1"
+        );
+        assert_eq!(
+            text("mpris:artUrl"),
+            "https://i.ytimg.com/vi/ab-C_1/hqdefault.jpg"
+        );
+        assert_eq!(text("xesam:url"), video.url);
+    }
+
+    #[test]
+    fn detects_seeks() {
+        assert!(!is_seek(10.0, 11.0, 1.0, false));
+        assert!(is_seek(10.0, 40.0, 1.0, false));
+        assert!(is_seek(10.0, 2.0, 1.0, false));
+        assert!(!is_seek(10.0, 10.0, 1.0, true));
+        assert!(is_seek(10.0, 12.5, 1.0, true));
+    }
+
+    #[test]
+    fn serves_on_session_bus() {
+        sandbox();
+        let dir: PathBuf = std::env::temp_dir().join(format!("mpvy-bus-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config: PathBuf = dir.join("bus.conf");
+        fs::write(
+            &config,
+            BUS_CONFIG.replace("{dir}", &dir.display().to_string()),
+        )
+        .unwrap();
+        let Ok(mut bus) = Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .args(["--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            eprintln!("dbus-daemon is not found, skipping the test.");
+            return;
+        };
+        let mut address: String = String::new();
+        BufReader::new(bus.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+
+        let player: Arc<Player> =
+            Arc::new(Player::new(Queue::new(vec![], PlayMode::default()), None));
+        start(player).unwrap();
+        let connection: Connection = Connection::session().unwrap();
+        let root: Proxy =
+            Proxy::new(&connection, BUS_NAME, OBJECT_PATH, "org.mpris.MediaPlayer2").unwrap();
+        let media_player: Proxy = Proxy::new(
+            &connection,
+            BUS_NAME,
+            OBJECT_PATH,
+            "org.mpris.MediaPlayer2.Player",
+        )
+        .unwrap();
+        assert_eq!(root.get_property::<String>("Identity").unwrap(), "mpvy");
+        assert_eq!(
+            media_player
+                .get_property::<String>("PlaybackStatus")
+                .unwrap(),
+            "Stopped"
+        );
+        assert!(media_player
+            .get_property::<HashMap<String, OwnedValue>>("Metadata")
+            .unwrap()
+            .is_empty());
+        // Stopping while nothing is playing does nothing
+        media_player.call_method("Stop", &()).unwrap();
+        assert!(media_player.introspect().unwrap().contains("\"Seeked\""));
+
+        let _ = bus.kill();
+        let _ = bus.wait();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
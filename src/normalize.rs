@@ -0,0 +1,149 @@
+use crate::cache;
+use crate::config;
+use crate::log::{self, *};
+use crate::runner::CommandRunner;
+use serde_json::Value;
+use std::process::Command;
+
+/// Gain is limited to this (in dB), so silent or broken measurements don't blow up the volume.
+const MAX_GAIN: f64 = 20.0;
+
+/// How the loudness of audios is levelled. Refer to the `normalize` config.
+///
+/// ```txt
+/// Off:      Audios are played as they are (default)
+/// Gain:     Loudness is measured once after download and a fixed gain is applied
+///           (like ReplayGain). Audios without measurement use `Dynamic`
+/// Dynamic:  Loudness is levelled live with `dynaudnorm` filter of `mpv`
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Off,
+    Gain,
+    Dynamic,
+}
+
+/// Returns the normalisation mode and target loudness (in LUFS) from configuration.
+fn options() -> (Mode, f64) {
+    let config = config::load().unwrap_or_default();
+    let mode: Mode = match config.get(config::NORMALIZE).map(|value| value.as_str()) {
+        Some("gain") => Mode::Gain,
+        Some("dynamic") => Mode::Dynamic,
+        _ => Mode::Off,
+    };
+    let target: f64 = config
+        .get(config::NORMALIZE_TARGET)
+        .and_then(|value| value.parse::<f64>().ok())
+        .unwrap_or(-14.0);
+    (mode, target)
+}
+
+/// Get integrated loudness (`input_i`) from the JSON which `loudnorm` prints at the end of stderr.
+fn parse_loudness(stderr: &str) -> Option<f64> {
+    let json: &str = &stderr[stderr.rfind('{')?..=stderr.rfind('}')?];
+    let value: Value = serde_json::from_str(json).ok()?;
+    value["input_i"].as_str()?.parse::<f64>().ok()
+}
+
+/// Measure integrated loudness of the audio file (in LUFS) with `ffmpeg`'s `loudnorm` filter.
+pub fn measure(runner: &dyn CommandRunner, path: &str) -> Result<f64, String> {
+    let mut command: Command = Command::new("ffmpeg");
+    command
+        .arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(path)
+        .arg("-af") // Only analyse, the measurement is printed as JSON
+        .arg("loudnorm=print_format=json")
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    log::command("Normalize Measure", &command);
+    let output = runner
+        .output(&mut command)
+        .map_err(|e| format!("Unable to execute 'ffmpeg': {}", e))?;
+    let stderr: String = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() {
+        log::command_exit("Normalize Measure", Some(output.status), &stderr);
+        return Err("'ffmpeg' is unable to measure loudness.".to_string());
+    }
+    parse_loudness(&stderr).ok_or("Unable to read loudness from 'ffmpeg' output.".to_string())
+}
+
+/// Measure the loudness of the audio and store it in the cache index, if `normalize` is `gain`
+/// And it is not measured before. Errors are only logged, the audio is levelled live instead.
+pub fn prepare(runner: &dyn CommandRunner, id: &str, path: &str) {
+    if options().0 != Mode::Gain || cache::get(id).is_some_and(|entry| entry.loudness.is_some()) {
+        return;
+    }
+    match measure(runner, path) {
+        Ok(loudness) => {
+            info(
+                "Normalize Prepare",
+                &format!("Measured loudness of '{}': {} LUFS.", id, loudness),
+            );
+            cache::update(id, |entry| entry.loudness = Some(loudness));
+        }
+        Err(e) => warning(
+            "Normalize Prepare",
+            &format!("Unable to measure loudness of '{}': {}", id, e),
+        ),
+    }
+}
+
+/// Returns the `mpv` audio filter (for `--af`) which levels the loudness of the video.
+pub fn filter(id: &str) -> Option<String> {
+    let (mode, target) = options();
+    let loudness: Option<f64> = cache::get(id).and_then(|entry| entry.loudness);
+    match (mode, loudness) {
+        (Mode::Off, _) => None,
+        (Mode::Gain, Some(loudness)) => {
+            let gain: f64 = (target - loudness).clamp(-MAX_GAIN, MAX_GAIN);
+            Some(format!("lavfi=[volume={:.2}dB]", gain))
+        }
+        (Mode::Gain, None) | (Mode::Dynamic, _) => Some("lavfi=[dynaudnorm]".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::{sandbox, Canned, MockRunner};
+
+    const STDERR: &str = r#"[Parsed_loudnorm_0 @ 0x5581] 
+{
+	"input_i" : "-8.54",
+	"input_tp" : "0.42",
+	"input_lra" : "5.90",
+	"input_thresh" : "-18.71",
+	"output_i" : "-24.26",
+	"target_offset" : "0.26"
+}
+"#;
+
+    #[test]
+    fn measures_loudness() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned {
+            stderr: STDERR.to_string(),
+            ..Canned::default()
+        }]);
+        assert_eq!(measure(&runner, "/tmp/a.mp3"), Ok(-8.54));
+        assert_eq!(
+            runner.calls()[0],
+            [
+                "ffmpeg",
+                "-hide_banner",
+                "-nostats",
+                "-i",
+                "/tmp/a.mp3",
+                "-af",
+                "loudnorm=print_format=json",
+                "-f",
+                "null",
+                "-"
+            ]
+        );
+        assert_eq!(parse_loudness("no json"), None);
+    }
+}
@@ -0,0 +1,447 @@
+use crate::events::{self, Event};
+use crate::ipc;
+use crate::log::*;
+use crate::playlist;
+use crate::query::QueueItem;
+use crate::queue::{PlayMode, Queue, Repeat};
+use crate::radio;
+use crate::runner::SystemRunner;
+use crate::service::{self, Playback};
+use crate::state;
+use crate::yt_dlp::VideoInfo;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// A snapshot of the player for `mpvy status --json`.
+/// Values:
+///
+/// ```txt
+/// playing:      Information of the playing audio
+/// position:     Playback position of the playing audio in seconds
+/// paused:       Whether the playing audio is paused
+/// queue:        Items in the queue, in query syntax (refer to `src/query.rs`)
+/// index:        Index of the playing audio in the queue
+/// chapter:      Index of the playing chapter in `playing.chapters`
+/// mode:         Play mode (shuffle, repeat)
+/// ```
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub playing: Option<VideoInfo>,
+    pub position: Option<f64>,
+    pub paused: bool,
+    pub queue: Vec<String>,
+    pub index: Option<usize>,
+    pub chapter: Option<usize>,
+    pub mode: PlayMode,
+}
+
+/// Plays the queue one audio after another. It is shared between the thread which plays
+/// Audios (`Player::run`) and the threads which control it (terminal controls, daemon clients).
+pub struct Player {
+    queue: Mutex<Queue>,
+    playlist: Option<String>,
+    now_playing: Mutex<Option<(usize, VideoInfo)>>, // Index in the queue and information of the playing audio
+    history: Mutex<Vec<usize>>, // Indexes of played audios, used for going to previous audio
+    jump: Mutex<Option<usize>>, // The audio which will be played instead of the next one
+    wakeup: Condvar,            // Notified when new audios are added to the queue
+    autoplay: AtomicBool,       // Add related tracks when the queue is about to end
+    stopped: AtomicBool, // Stop playing, even if the queue is not ended (refer to `Player::stop`)
+    halted: AtomicBool,  // Playback is stopped until it is resumed (refer to `Player::halt`)
+}
+
+impl Player {
+    pub fn new(queue: Queue, playlist: Option<String>) -> Player {
+        Player {
+            queue: Mutex::new(queue),
+            playlist,
+            now_playing: Mutex::new(None),
+            history: Mutex::new(vec![]),
+            jump: Mutex::new(None),
+            wakeup: Condvar::new(),
+            autoplay: AtomicBool::new(radio::enabled()),
+            stopped: AtomicBool::new(false),
+            halted: AtomicBool::new(false),
+        }
+    }
+
+    /// Enable or disable autoplay (refer to `src/radio.rs`). The `autoplay` config is used by default.
+    pub fn set_autoplay(&self, enabled: bool) {
+        self.autoplay.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns a copy of the queue.
+    pub fn queue(&self) -> Queue {
+        self.queue.lock().unwrap().clone()
+    }
+
+    /// Returns the information of the playing audio.
+    pub fn now_playing(&self) -> Option<VideoInfo> {
+        self.now_playing
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, video)| video.clone())
+    }
+
+    /// Returns a snapshot of the player. Position and pause are read from `mpv` over IPC.
+    pub fn status(&self) -> Status {
+        let queue: Queue = self.queue();
+        let playing: Option<VideoInfo> = self.now_playing();
+        let (position, paused) = if playing.is_some() {
+            (ipc::position(), self.is_paused())
+        } else {
+            (None, false)
+        };
+        let chapter: Option<usize> = playing
+            .as_ref()
+            .zip(position)
+            .and_then(|(video, position)| video.chapter_at(position));
+        Status {
+            playing,
+            position,
+            paused,
+            queue: queue.items().iter().map(|item| item.to_string()).collect(),
+            index: self.playing_index(),
+            chapter,
+            mode: queue.mode(),
+        }
+    }
+
+    /// Returns the index of the playing audio in the queue.
+    /// This can be different from `Queue::current`, because the next audio is selected
+    /// (and downloaded) while the current one is playing.
+    pub fn playing_index(&self) -> Option<usize> {
+        self.now_playing.lock().unwrap().as_ref().map(|(i, _)| *i)
+    }
+
+    /// Add items to the end of queue. If the player is idle, it starts playing them.
+    pub fn add(&self, items: Vec<QueueItem>) {
+        let mut queue = self.queue.lock().unwrap();
+        for item in items {
+            info("Player Add", &format!("Adding '{}' to the queue.", item));
+            queue.push(item);
+        }
+        self.wakeup.notify_all();
+    }
+
+    /// Change the play mode. If a playlist is playing, the play mode is saved for the playlist.
+    pub fn set_mode(&self, mode: PlayMode) {
+        self.queue.lock().unwrap().set_mode(mode);
+        if let Some(name) = &self.playlist {
+            let _ = playlist::write_mode(name, &mode);
+        }
+    }
+
+    /// Skip the playing audio. The next audio in the queue starts playing.
+    /// With `Repeat::One`, the next audio is selected too, instead of the same audio again.
+    pub fn skip(&self) -> Result<(), String> {
+        info("Player Skip", "Skipping the current audio.");
+        {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.mode().repeat == Repeat::One {
+                if let Some(target) = queue.skip() {
+                    *self.jump.lock().unwrap() = Some(target);
+                    self.wakeup.notify_all();
+                }
+            }
+        }
+        self.quit()
+    }
+
+    /// Close the playing `mpv`, so `Player::run` continues with the next (or jumped) audio.
+    fn quit(&self) -> Result<(), String> {
+        ipc::command(&[json!("quit")]).map(|_| ())
+    }
+
+    /// Go back to the previously played audio. If there is no previous audio,
+    /// The current audio is played from the beginning.
+    pub fn previous(&self) -> Result<(), String> {
+        let target: Option<usize> = {
+            let mut history = self.history.lock().unwrap();
+            if history.len() >= 2 {
+                history.pop(); // The current audio
+                history.pop() // It is pushed again when it starts playing
+            } else {
+                history.pop()
+            }
+        };
+        let Some(target) = target else {
+            return Err("There is no previous audio.".to_string());
+        };
+        info(
+            "Player Previous",
+            &format!("Going back to audio at index {}.", target),
+        );
+        *self.jump.lock().unwrap() = Some(target);
+        self.wakeup.notify_all();
+        // The player may be waiting for the current audio or new audios
+        if self.now_playing().is_some() {
+            self.quit()?;
+        }
+        Ok(())
+    }
+
+    /// Stop the player, like when user quits the shell. The playing audio is stopped and
+    /// `Player::run` returns. The session state is kept, so it can be resumed with `--resume`.
+    pub fn stop(&self) {
+        info("Player Stop", "Stopping the player.");
+        self.stopped.store(true, Ordering::Relaxed);
+        {
+            // The player may be waiting for new audios
+            let _queue = self.queue.lock().unwrap();
+            self.wakeup.notify_all();
+        }
+        if self.now_playing().is_some() {
+            let _ = self.quit();
+        }
+    }
+
+    /// Stop the playback without stopping the player, like MPRIS `Stop`. The playing audio is
+    /// Selected again, so `Player::resume` plays it from the beginning.
+    pub fn halt(&self) -> Result<(), String> {
+        let Some(index) = self.playing_index() else {
+            return Ok(()); // Already stopped
+        };
+        info("Player Halt", "Stopping the playback.");
+        self.halted.store(true, Ordering::Relaxed);
+        *self.jump.lock().unwrap() = Some(index);
+        self.quit()
+    }
+
+    /// Continue the playback after `Player::halt`. Returns `false` if the playback is not stopped.
+    pub fn resume(&self) -> bool {
+        if !self.halted.swap(false, Ordering::Relaxed) {
+            return false;
+        }
+        info("Player Resume", "Resuming the playback.");
+        let _queue = self.queue.lock().unwrap();
+        self.wakeup.notify_all();
+        true
+    }
+
+    fn is_halted(&self) -> bool {
+        self.halted.load(Ordering::Relaxed)
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Change the volume (0-100) of the playing audio and next audios.
+    pub fn set_volume(&self, volume: u64) -> Result<(), String> {
+        service::set_volume(volume);
+        if self.now_playing().is_none() {
+            return Ok(());
+        }
+        ipc::set_property("volume", json!(volume.min(100)))
+    }
+
+    /// Returns the volume of the playing audio, or the volume of next audios if nothing is playing.
+    pub fn volume(&self) -> Option<u64> {
+        ipc::get_property("volume")
+            .ok()
+            .and_then(|value| value.as_f64())
+            .map(|volume| volume.round() as u64)
+            .or_else(service::volume)
+    }
+
+    /// Pause or unpause the playing audio.
+    pub fn set_paused(&self, paused: bool) -> Result<(), String> {
+        ipc::set_property("pause", json!(paused))
+    }
+
+    /// Returns `true` if the playing audio is paused.
+    pub fn is_paused(&self) -> bool {
+        ipc::get_property("pause")
+            .ok()
+            .and_then(|value| value.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// Seek the playing audio. If `relative` is true, `seconds` is added to the position.
+    pub fn seek(&self, seconds: f64, relative: bool) -> Result<(), String> {
+        let mode: &str = if relative { "relative" } else { "absolute" };
+        ipc::command(&[json!("seek"), json!(seconds), json!(mode)]).map(|_| ())
+    }
+
+    /// Go to the next (`offset` is 1) or previous (`offset` is -1) chapter of the playing audio.
+    /// Returns the title of the chapter.
+    pub fn seek_chapter(&self, offset: isize) -> Result<String, String> {
+        let video: VideoInfo = self.now_playing().ok_or("Nothing is playing.")?;
+        if video.chapters.is_empty() {
+            return Err("The playing audio has no chapters.".to_string());
+        }
+        let position: f64 = ipc::position().ok_or("Unable to get playback position.")?;
+        let current: usize = video.chapter_at(position).unwrap_or(0);
+        let target: usize = current
+            .saturating_add_signed(offset)
+            .min(video.chapters.len() - 1);
+        let chapter = &video.chapters[target];
+        info(
+            "Player Chapter",
+            &format!("Going to chapter {}: '{}'.", target + 1, chapter.title),
+        );
+        self.seek(chapter.start_time, false)?;
+        Ok(chapter.title.clone())
+    }
+
+    /// Add tracks which are related to the `seed` video to the queue. Tracks in the queue and
+    /// Recently played tracks (refer to `radio::recent_ids`) are not added again.
+    fn autoplay(&self, seed: &VideoInfo) {
+        let mut exclude: HashSet<String> = radio::recent_ids();
+        for item in self.queue().items() {
+            if let Some(id) = radio::video_id(&item.query) {
+                exclude.insert(id.to_string());
+            }
+        }
+        let tracks: Vec<radio::Track> = radio::related(&SystemRunner, seed, &exclude);
+        if tracks.is_empty() {
+            info(
+                "Player Autoplay",
+                "No related tracks found. The queue will end.",
+            );
+            return;
+        }
+        for (_, title) in &tracks {
+            info(
+                "Player Autoplay",
+                &format!("Adding related track '{}'.", title),
+            );
+        }
+        let items: Vec<QueueItem> = tracks
+            .iter()
+            .map(|(id, _)| QueueItem::new(&service::video_url(id)))
+            .collect();
+        self.add(items);
+    }
+
+    /// If a jump is requested (refer to `Player::previous`), select that audio in the queue.
+    /// Returns `true` if there was a jump.
+    fn take_jump(&self) -> bool {
+        match self.jump.lock().unwrap().take() {
+            Some(target) => {
+                self.queue.lock().unwrap().jump(target);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Play the queue until it ends.
+    /// If `idle` is true, the player doesn't stop at the end of the queue and waits for new audios.
+    /// If `start` is given, the first audio starts from that position (in seconds).
+    pub fn run(&self, mut start: Option<u64>, idle: bool) {
+        state::start_autosave();
+        let mut current: Option<Playback> = None;
+        let mut failures: usize = 0; // Stop if every query in the queue fails
+
+        loop {
+            if self.is_stopped() {
+                if let Some(playback) = current.take() {
+                    playback.stop();
+                }
+                *self.now_playing.lock().unwrap() = None;
+                info("Player Run", "Player is stopped.");
+                return;
+            }
+            if self.is_halted() {
+                if let Some(playback) = current.take() {
+                    playback.wait();
+                }
+                *self.now_playing.lock().unwrap() = None;
+                info("Player Run", "Playback is stopped. Waiting for play.");
+                let queue = self.queue.lock().unwrap();
+                let _queue = self
+                    .wakeup
+                    .wait_while(queue, |_| self.is_halted() && !self.is_stopped())
+                    .unwrap();
+                continue;
+            }
+            let next: Option<(usize, QueueItem)> = {
+                let mut queue = self.queue.lock().unwrap();
+                if !queue.is_empty() && failures >= queue.len() {
+                    error("Player Run", "All queries failed. Stopping the queue.");
+                    None
+                } else {
+                    queue
+                        .advance()
+                        .and_then(|i| queue.get(i).map(|item| (i, item.clone())))
+                }
+            };
+
+            let Some((index, item)) = next else {
+                // Wait for the last audio to end.
+                if let Some(playback) = current.take() {
+                    playback.wait();
+                    *self.now_playing.lock().unwrap() = None;
+                }
+                if self.take_jump() {
+                    continue;
+                }
+                if !idle {
+                    break;
+                }
+                // The queue is ended, there is nothing to resume.
+                state::clear();
+                info("Player Run", "Queue ended. Waiting for new audios.");
+                let queue = self.queue.lock().unwrap();
+                let length: usize = queue.len();
+                let _queue = self
+                    .wakeup
+                    .wait_while(queue, |queue| {
+                        queue.len() == length
+                            && self.jump.lock().unwrap().is_none()
+                            && !self.is_stopped()
+                    })
+                    .unwrap();
+                drop(_queue);
+                self.take_jump();
+                failures = 0;
+                continue;
+            };
+
+            info("Player Run", &format!("Reached query in loop: '{}'.", item));
+
+            // The next audio is fetched while the current one is playing.
+            // A resumed position overrides the start position of the item
+            let position: Option<u64> = start.take().or(item.start);
+            match service::play(&SystemRunner, &item, position, current.take()) {
+                Ok(playback) => {
+                    failures = 0;
+                    // Going back (or stopping) is requested while waiting for the previous audio
+                    if self.is_stopped() || self.take_jump() {
+                        playback.stop();
+                        continue;
+                    }
+                    self.history.lock().unwrap().push(index);
+                    state::set_queue(
+                        &self.queue.lock().unwrap(),
+                        self.playlist.clone(),
+                        &playback.video.title,
+                    );
+                    *self.now_playing.lock().unwrap() = Some((index, playback.video.clone()));
+                    // Related tracks are added while the last audio of the queue is playing
+                    let last: bool = !self.queue.lock().unwrap().has_next();
+                    if last && self.autoplay.load(Ordering::Relaxed) {
+                        self.autoplay(&playback.video);
+                    }
+                    current = Some(playback);
+                }
+                Err(e) => {
+                    failures += 1;
+                    *self.now_playing.lock().unwrap() = None;
+                    error("Player Run", &format!("Unable to play '{}': {}", item, e));
+                    events::emit(Event::Error {
+                        message: format!("Unable to play '{}': {}", item, e),
+                    });
+                }
+            }
+        }
+
+        // The queue is ended, there is nothing to resume.
+        state::clear();
+    }
+}
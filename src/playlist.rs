@@ -1,26 +1,58 @@
 use crate::log::*;
+use crate::query::{self, QueueItem};
+use crate::queue::PlayMode;
 use dirs;
 use std::fs;
 
 /// Returns the path of all playlists directory
 pub fn playlists_path() -> String {
-    return format!(
+    format!(
         "{}/mpvy/playlist/",
         dirs::config_dir()
             .expect("Unexpected Error: Unable to get config directory for playlists.")
             .display()
-    );
+    )
 }
 
 /// Returns the path of given playlist file
 pub fn playlist_path(name: &str) -> String {
-    return format!(
+    format!(
         "{}/mpvy/playlist/{}.txt",
         dirs::config_dir()
             .expect("Unexpected Error: Unable to get config directory for playlists.")
             .display(),
         name
-    );
+    )
+}
+
+/// Returns the path of the file which keeps play mode of given playlist
+pub fn mode_path(name: &str) -> String {
+    format!(
+        "{}/mpvy/playlist/{}.mode",
+        dirs::config_dir()
+            .expect("Unexpected Error: Unable to get config directory for playlists.")
+            .display(),
+        name
+    )
+}
+
+/// Read the saved play mode (shuffle, repeat) of given playlist.
+/// Returns `None` if there is no saved play mode for the playlist.
+pub fn read_mode(name: &str) -> Option<PlayMode> {
+    let content: String = fs::read_to_string(mode_path(name)).ok()?;
+    Some(PlayMode::parse(&content))
+}
+
+/// Remember the play mode of given playlist, so next time it is played with same mode.
+pub fn write_mode(name: &str, mode: &PlayMode) -> Result<(), String> {
+    if fs::write(mode_path(name), mode.serialize()).is_err() {
+        error(
+            "Playlist Mode",
+            &format!("An error occured while saving play mode of '{}'.", name),
+        );
+        return Err("Unable to write play mode of playlist".to_string());
+    }
+    Ok(())
 }
 
 /// Read the given querys from the playlist file (`$XDG_CONFIG_HOME/mpvy/playlist/{name}.txt`)
@@ -29,7 +61,7 @@ pub fn playlist_path(name: &str) -> String {
 pub fn read_playlist(name: &str) -> Result<String, String> {
     let path: String = playlist_path(name);
     // Check if the playlist file exists
-    if fs::exists(&path).unwrap_or(false) == false {
+    if !fs::exists(&path).unwrap_or(false) {
         error(
             "Playlist Read",
             &format!("Unable to find playlist with name: '{}'", name),
@@ -47,14 +79,27 @@ pub fn read_playlist(name: &str) -> Result<String, String> {
         );
         return Err("Unexpected error while reading file contents.".to_string());
     }
-    return Ok(content.unwrap());
+    Ok(content.unwrap())
+}
+
+/// Read the playlist and parse it into queue items. Playlist files have the same syntax as
+/// The prompt, and can have one query per line and `#` comments (refer to `src/query.rs`).
+pub fn read_items(name: &str) -> Result<Vec<QueueItem>, String> {
+    let content: String = read_playlist(name)?;
+    query::parse(&content).inspect_err(|e| {
+        error(
+            "Playlist Read",
+            &format!("Unable to parse playlist '{}': {}", name, e),
+        );
+    })
 }
 
 /// Write the given content to the playlist file (`$XDG_CONFIG_HOME/mpvy/playlist/{name}.txt`)
 /// It has error handling too, so using this instead of directly using **std::fs** will be more safe
 /// And will be more informative for users
-pub fn write_playlist(name: &String, content: String) -> Result<(), String> {
-    let path: String = playlist_path(&name);
+pub fn write_playlist(name: &str, content: String) -> Result<(), String> {
+    let path: String = playlist_path(name);
+    let _ = fs::create_dir_all(playlists_path());
     let result: Result<(), std::io::Error> = fs::write(path, content);
     if result.is_err() {
         error(
@@ -63,7 +108,7 @@ pub fn write_playlist(name: &String, content: String) -> Result<(), String> {
         );
         return Err("Unable to write content to playlist file".to_string());
     }
-    return Ok(());
+    Ok(())
 }
 
 /// Get all avaliable playlists in the directory of `$XDG_CONFIG_HOME/mpvy/playlist`
@@ -74,7 +119,7 @@ pub fn get_playlists() -> Result<Vec<String>, String> {
     // Create an Vec for playlist titles.
     let mut entries = Vec::new();
 
-    if fs::exists(&path).unwrap_or(false) == false {
+    if !fs::exists(&path).unwrap_or(false) {
         info(
             "Playlist List",
             "The playlist directory doesn't exists. Creating a new one.",
@@ -90,27 +135,31 @@ pub fn get_playlists() -> Result<Vec<String>, String> {
         return Ok(vec![]);
     }
 
-    // Create an index for logging.
-    let mut index: i32 = 0;
     // Read the directory `$XDG_CONFIG_HOME/mpvy/playlist` to get playlist files.
-    for entry in fs::read_dir(path).expect("Unexpected Error: Unable to read playlists directory.")
+    // The index is only used for logging.
+    for (index, entry) in fs::read_dir(path)
+        .expect("Unexpected Error: Unable to read playlists directory.")
+        .enumerate()
     {
-        info(
+        debug(
             "Playlist List",
             &format!("Reached index {} in loop.", index),
         );
         let entry = entry.expect("Unexpected Error: Unable to get entry in loop.");
         let path = entry.path();
+        // Skip the files which are not playlists (like `.mode` files).
+        if path.extension().and_then(|e| e.to_str()) != Some("txt") {
+            continue;
+        }
         // Remove the extension from file (playlist.txt --> playlist).
         let title = path.file_stem()
             .expect("Unexpected Error: Unable to get file stem of playlist. Maybe the 'file' is a directory?")
             .to_str()
         .unwrap_or("Error");
 
-        info("Playlist List", "Pushing playlist into the Vec.");
+        debug("Playlist List", "Pushing playlist into the Vec.");
         entries.push(String::from(title));
-        index = index + 1;
     }
 
-    return Ok(entries);
+    Ok(entries)
 }
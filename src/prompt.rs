@@ -0,0 +1,294 @@
+use crate::cache;
+use crate::history;
+use crate::log::*;
+use crate::playlist;
+use crate::query::QueueItem;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Config, Context, Editor, ExternalPrinter, Helper};
+use std::collections::HashSet;
+
+/// Max number of inputs which are kept in the prompt history.
+const HISTORY_SIZE: usize = 1000;
+
+/// Commands which are written with `!` in the query prompt.
+pub const COMMANDS: [&str; 1] = ["!playlist"];
+
+/// Commands which take a playlist name, their argument is completed with playlist names.
+const PLAYLIST_COMMANDS: [&str; 3] = ["!playlist", "playlist", "save"];
+
+/// Commands which take queries (refer to `src/shell.rs`), their argument is completed with titles.
+const QUERY_COMMANDS: [&str; 1] = ["add"];
+
+/// Returns the path of prompt history file (`$XDG_CONFIG_HOME/mpvy/prompt_history`)
+/// Every line of the file is an input which is given to the prompt.
+pub fn history_path() -> String {
+    format!(
+        "{}/mpvy/prompt_history",
+        dirs::config_dir()
+            .expect("Unexpected Error: Unable to get config directory for prompt history.")
+            .display()
+    )
+}
+
+/// Returns titles of cached audios, recently played ones first (refer to `src/history.rs`).
+pub fn cached_titles() -> Vec<String> {
+    let index: cache::Index = cache::read();
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut titles: Vec<String> = vec![];
+    let played: Vec<history::Entry> = history::read();
+    let ids = played
+        .iter()
+        .rev()
+        .map(|entry| entry.id.as_str())
+        .chain(index.keys().map(String::as_str));
+    for id in ids {
+        if !seen.insert(id) {
+            continue;
+        }
+        if let Some(title) = index.get(id).and_then(|entry| entry.title.clone()) {
+            titles.push(title);
+        }
+    }
+    titles
+}
+
+/// Returns the start of the item which the cursor is in (after the last comma which is not
+/// Quoted or escaped), skipping whitespace.
+fn item_start(line: &str) -> usize {
+    let mut start: usize = 0;
+    let mut quoted: bool = false;
+    let mut escaped: bool = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ',' | '\n' if !quoted => start = i + 1,
+            _ => {}
+        }
+    }
+    start + line[start..].len() - line[start..].trim_start().len()
+}
+
+/// Complete the line before the cursor. Returns the start of the replaced text and candidates:
+///
+/// ```txt
+/// <command>           Commands which start with the word (like `!playlist`, `add`)
+/// playlist <name>     Names of playlists (refer to `PLAYLIST_COMMANDS`)
+/// add <words>         Cached titles which have all the words in the last item (quoted if needed)
+/// <words>             Same as `add`, lines which are not commands are queries
+/// ```
+pub fn complete(
+    line: &str,
+    commands: &[&str],
+    playlists: &[String],
+    titles: &[String],
+) -> (usize, Vec<String>) {
+    // Only the given commands are known, `save` is a query in the query prompt
+    let command: Option<(&str, &str)> = line
+        .split_once(' ')
+        .filter(|(command, _)| commands.contains(command));
+    let mut offset: usize = 0; // Start of the queries in the line
+    match command {
+        None if !line.contains(' ') => {
+            let candidates: Vec<String> = commands
+                .iter()
+                .filter(|command| !line.is_empty() && command.starts_with(line))
+                .map(|command| format!("{} ", command))
+                .collect();
+            if !candidates.is_empty() {
+                return (0, candidates);
+            }
+        }
+        Some((command, name)) if PLAYLIST_COMMANDS.contains(&command) => {
+            let name: &str = name.trim_start();
+            let candidates: Vec<String> = playlists
+                .iter()
+                .filter(|playlist| playlist.starts_with(name))
+                .cloned()
+                .collect();
+            return (line.len() - name.len(), candidates);
+        }
+        Some((command, _)) if QUERY_COMMANDS.contains(&command) => offset = command.len() + 1,
+        Some(_) => return (line.len(), vec![]),
+        None => {}
+    }
+    let start: usize = offset + item_start(&line[offset..]);
+    let item: &str = &line[start..];
+    let words: Vec<String> = item
+        .to_lowercase()
+        .split_whitespace()
+        .map(|word| word.trim_matches('"').to_string())
+        .collect();
+    let candidates: Vec<String> = titles
+        .iter()
+        .filter(|title| {
+            let title: String = title.to_lowercase();
+            words.iter().all(|word| title.contains(word))
+        })
+        .map(|title| QueueItem::new(title).to_string())
+        .collect();
+    (start, candidates)
+}
+
+/// Completion for the prompt. Playlists and titles are read once, when the prompt is created.
+struct PromptHelper {
+    commands: &'static [&'static str],
+    playlists: Vec<String>,
+    titles: Vec<String>,
+}
+
+impl Completer for PromptHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, candidates) =
+            complete(&line[..pos], self.commands, &self.playlists, &self.titles);
+        Ok((
+            start,
+            candidates
+                .into_iter()
+                .map(|candidate| Pair {
+                    display: candidate.clone(),
+                    replacement: candidate,
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Hinter for PromptHelper {
+    type Hint = String;
+}
+
+impl Highlighter for PromptHelper {}
+
+impl Validator for PromptHelper {}
+
+impl Helper for PromptHelper {}
+
+/// A line-editing prompt with persistent history and tab completion.
+pub struct Prompt {
+    editor: Editor<PromptHelper, DefaultHistory>,
+}
+
+impl Prompt {
+    /// Create the prompt and load its history. `commands` are completed at the start of line
+    /// (like `COMMANDS` for the query prompt).
+    pub fn new(commands: &'static [&'static str]) -> Result<Prompt, String> {
+        let config: Config = Config::builder()
+            .max_history_size(HISTORY_SIZE)
+            .map_err(|e| e.to_string())?
+            .completion_type(CompletionType::List)
+            .auto_add_history(false)
+            .build();
+        let mut editor: Editor<PromptHelper, DefaultHistory> =
+            Editor::with_config(config).map_err(|e| e.to_string())?;
+        editor.set_helper(Some(PromptHelper {
+            commands,
+            playlists: playlist::get_playlists().unwrap_or_default(),
+            titles: cached_titles(),
+        }));
+        if let Err(e) = editor.load_history(&history_path()) {
+            // There is no history at first run
+            debug(
+                "Prompt New",
+                &format!("Unable to load prompt history: {}", e),
+            );
+        }
+        Ok(Prompt { editor })
+    }
+
+    /// Create a printer which prints above the prompt without breaking the edited line.
+    /// Returns `None` if the input or output is not a terminal.
+    pub fn printer(&mut self) -> Option<Box<dyn ExternalPrinter + Send>> {
+        match self.editor.create_external_printer() {
+            Ok(printer) => Some(Box::new(printer)),
+            Err(e) => {
+                debug(
+                    "Prompt Printer",
+                    &format!("Unable to create printer: {}", e),
+                );
+                None
+            }
+        }
+    }
+
+    /// Read a line of input. Returns `None` if the input is closed (Ctrl-D) or interrupted (Ctrl-C).
+    /// Non-empty inputs are saved to the history.
+    pub fn read(&mut self, prompt: &str) -> Option<String> {
+        match self.editor.readline(prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = self.editor.add_history_entry(line.trim());
+                    if let Err(e) = self.editor.save_history(&history_path()) {
+                        error(
+                            "Prompt Read",
+                            &format!("Unable to save prompt history: {}", e),
+                        );
+                    }
+                }
+                Some(line)
+            }
+            Err(ReadlineError::Eof | ReadlineError::Interrupted) => None,
+            Err(e) => {
+                error("Prompt Read", &format!("Unable to read input: {}", e));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_playlists_and_commands() {
+        let playlists: Vec<String> = vec!["morning".to_string(), "night".to_string()];
+        assert_eq!(
+            complete("!playlist mo", &COMMANDS, &playlists, &[]),
+            (10, vec!["morning".to_string()])
+        );
+        assert_eq!(
+            complete("!pl", &COMMANDS, &playlists, &[]),
+            (0, vec!["!playlist ".to_string()])
+        );
+        assert_eq!(
+            complete("save ", &["save", "skip"], &playlists, &[]).1,
+            playlists
+        );
+        assert_eq!(
+            complete("skip now", &["save", "skip"], &playlists, &[]),
+            (8, vec![])
+        );
+    }
+
+    #[test]
+    fn completes_titles_in_last_item() {
+        let titles: Vec<String> = vec![
+            "The Beatles - Hello, Goodbye".to_string(),
+            "Daft Punk - One More Time".to_string(),
+        ];
+        let line: &str = "\"a, b\" x2, beatles hel";
+        assert_eq!(
+            complete(line, &COMMANDS, &[], &titles),
+            (11, vec!["\"The Beatles - Hello, Goodbye\"".to_string()])
+        );
+        assert_eq!(complete("daft, one more", &COMMANDS, &[], &titles).0, 6);
+        assert_eq!(
+            complete("add da", &["add"], &[], &titles),
+            (4, vec!["Daft Punk - One More Time".to_string()])
+        );
+    }
+}
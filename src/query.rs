@@ -0,0 +1,386 @@
+use crate::time::format_duration;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Where the audio of a queue item is played from.
+///
+/// ```txt
+/// Auto:       Cached audio if it is downloaded, otherwise download it (default)
+/// Local:      Only cached audios, found by title in the cache index without network (`!local`)
+/// YouTube:    Always download from YouTube, even if it is cached (`!yt`)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Hint {
+    #[default]
+    Auto,
+    Local,
+    YouTube,
+}
+
+/// An item of the queue, a query with its options.
+/// Values:
+///
+/// ```txt
+/// query:        Search query or URL of the audio
+/// start:        Position to start the audio from in seconds (`@1:30`)
+/// hint:         Where the audio is played from (`!local`, `!yt`)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Stored")]
+pub struct QueueItem {
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_auto")]
+    pub hint: Hint,
+}
+
+fn is_auto(hint: &Hint) -> bool {
+    *hint == Hint::Auto
+}
+
+/// Queue items are saved in the session state (refer to `src/state.rs`). Old states have
+/// Plain strings instead of items, so both are read.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Query(String),
+    Item {
+        query: String,
+        #[serde(default)]
+        start: Option<u64>,
+        #[serde(default)]
+        hint: Hint,
+    },
+}
+
+impl From<Stored> for QueueItem {
+    fn from(stored: Stored) -> QueueItem {
+        match stored {
+            Stored::Query(query) => QueueItem::new(&query),
+            Stored::Item { query, start, hint } => QueueItem { query, start, hint },
+        }
+    }
+}
+
+impl QueueItem {
+    /// Create an item without options.
+    pub fn new(query: &str) -> QueueItem {
+        QueueItem {
+            query: query.to_string(),
+            start: None,
+            hint: Hint::Auto,
+        }
+    }
+}
+
+/// Write the item back in query syntax, so it can be parsed again.
+impl fmt::Display for QueueItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let words: Vec<Word> = split_words(&self.query);
+        let plain: bool = !self.query.is_empty()
+            && self.query == self.query.trim()
+            && !self.query.contains(['"', '\\', ',', '\n'])
+            && words
+                .iter()
+                .all(|word| modifier(word).is_none() && !word.text.starts_with('#'));
+        if plain {
+            write!(f, "{}", self.query)?;
+        } else {
+            write!(
+                f,
+                "\"{}\"",
+                self.query.replace('\\', "\\\\").replace('"', "\\\"")
+            )?;
+        }
+        if let Some(start) = self.start {
+            write!(f, " @{}", format_duration(start))?;
+        }
+        match self.hint {
+            Hint::Auto => Ok(()),
+            Hint::Local => write!(f, " !local"),
+            Hint::YouTube => write!(f, " !yt"),
+        }
+    }
+}
+
+/// A word of a queue item. `literal` is true if a part of it is quoted or escaped,
+/// So it is never read as a modifier.
+#[derive(Debug, Default)]
+struct Word {
+    text: String,
+    literal: bool,
+}
+
+/// A per-item modifier, refer to `parse`.
+enum Modifier {
+    Start(u64),
+    Times(usize),
+    Hint(Hint),
+}
+
+/// Parse a position like `90`, `1:30` or `1:02:03` into seconds.
+fn parse_position(text: &str) -> Option<u64> {
+    let parts: Vec<&str> = text.split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    parts.into_iter().try_fold(0, |total: u64, part: &str| {
+        part.parse::<u64>().ok().map(|value| total * 60 + value)
+    })
+}
+
+/// Read the word as a modifier. Returns `None` if it is a normal word.
+fn modifier(word: &Word) -> Option<Modifier> {
+    if word.literal {
+        return None;
+    }
+    let text: &str = word.text.as_str();
+    if let Some(position) = text.strip_prefix('@') {
+        return parse_position(position).map(Modifier::Start);
+    }
+    if let Some(times) = text.strip_prefix('x') {
+        return times.parse::<usize>().ok().map(Modifier::Times);
+    }
+    match text {
+        "!local" => Some(Modifier::Hint(Hint::Local)),
+        "!yt" => Some(Modifier::Hint(Hint::YouTube)),
+        _ => None,
+    }
+}
+
+/// Split text into words by whitespace. Used for writing items back (refer to `Display`).
+fn split_words(text: &str) -> Vec<Word> {
+    text.split_whitespace()
+        .map(|word| Word {
+            text: word.to_string(),
+            literal: false,
+        })
+        .collect()
+}
+
+/// Split input into items (lists of words). Items are separated by commas and lines.
+/// Quotes (`"..."`) and backslashes (`\,`) keep commas, `#` and modifiers as text.
+/// A `#` at the start of a line comments the line, elsewhere it is text (like `song #1`).
+fn split_items(input: &str) -> Result<Vec<Vec<Word>>, String> {
+    let mut items: Vec<Vec<Word>> = vec![];
+    let mut words: Vec<Word> = vec![];
+    let mut word: Word = Word::default();
+    let mut started: bool = false; // The word has text (or empty quotes)
+    let mut line_start: bool = true; // Only whitespace since the start of line
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            line_start = true;
+        } else if !c.is_whitespace() && c != '#' {
+            line_start = false;
+        }
+        match c {
+            '"' => {
+                started = true;
+                word.literal = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.text.extend(chars.next()),
+                        Some(c) => word.text.push(c),
+                        None => return Err(format!("Unclosed quote in '{}'.", input.trim())),
+                    }
+                }
+            }
+            '\\' => {
+                started = true;
+                word.literal = true;
+                word.text.push(chars.next().unwrap_or('\\'));
+            }
+            '#' if line_start => {
+                // Comment until the end of line, the line ends the item too
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                items.push(std::mem::take(&mut words));
+            }
+            ',' | '\n' => {
+                if started {
+                    words.push(std::mem::take(&mut word));
+                }
+                started = false;
+                items.push(std::mem::take(&mut words));
+            }
+            c if c.is_whitespace() => {
+                if started {
+                    words.push(std::mem::take(&mut word));
+                }
+                started = false;
+            }
+            c => {
+                started = true;
+                word.text.push(c);
+            }
+        }
+    }
+    if started {
+        words.push(word);
+    }
+    items.push(words);
+    Ok(items
+        .into_iter()
+        .filter(|words| !words.is_empty())
+        .collect())
+}
+
+/// Parse user input (or a playlist file) into queue items.
+/// Items are separated by commas (or lines), and can have modifiers after the query (modifiers
+/// Between query words are a part of the query):
+///
+/// ```txt
+/// @1:30         Start the audio from 1:30 (`@90` and `@1:02:03` work too)
+/// x3            Add the item three times
+/// !local        Only play the cached audio (refer to `Hint`)
+/// !yt           Download the audio again even if it is cached
+/// "a, b"        Quotes keep commas, `#` and modifiers in the query, `\` escapes a character
+/// # text        Comments the line (for playlist files), only at the start of a line
+/// ```
+pub fn parse(input: &str) -> Result<Vec<QueueItem>, String> {
+    let mut items: Vec<QueueItem> = vec![];
+    for words in split_items(input)? {
+        let mut query: Vec<String> = vec![];
+        let mut item: QueueItem = QueueItem::new("");
+        let mut times: usize = 1;
+        // Modifiers are only read after the last query word, so "x2" in
+        // "Rolling Stones x2 live" and "@10" in "meet me @10 tonight" stay in the query
+        let query_end: usize = words
+            .iter()
+            .rposition(|word| modifier(word).is_none())
+            .map_or(0, |index| index + 1);
+        for (index, word) in words.iter().enumerate() {
+            match modifier(word) {
+                _ if index < query_end => query.push(word.text.clone()),
+                Some(Modifier::Start(start)) => item.start = Some(start),
+                Some(Modifier::Times(count)) => times = count,
+                Some(Modifier::Hint(hint)) => item.hint = hint,
+                None => query.push(word.text.clone()),
+            }
+        }
+        let text: String = words
+            .iter()
+            .map(|word| word.text.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        if query.is_empty() {
+            return Err(format!("'{}' has no query.", text));
+        }
+        if times == 0 {
+            return Err(format!("'{}' is repeated zero times.", text));
+        }
+        item.query = query.join(" ");
+        items.extend(std::iter::repeat_n(item, times));
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quotes_and_escapes() {
+        let items: Vec<QueueItem> =
+            parse(r#" "Hello, Goodbye" beatles, AC\,DC \#1,  "say \"hi\"" "#).unwrap();
+        let queries: Vec<&str> = items.iter().map(|item| item.query.as_str()).collect();
+        assert_eq!(
+            queries,
+            ["Hello, Goodbye beatles", "AC,DC #1", "say \"hi\""]
+        );
+        assert!(parse("\"unclosed, song").is_err());
+    }
+
+    #[test]
+    fn parses_modifiers() {
+        let items: Vec<QueueItem> =
+            parse("song one @1:30 x2, song two !local, \"x3\" !yt").unwrap();
+        assert_eq!(
+            items,
+            [
+                QueueItem {
+                    query: "song one".to_string(),
+                    start: Some(90),
+                    hint: Hint::Auto
+                },
+                QueueItem {
+                    query: "song one".to_string(),
+                    start: Some(90),
+                    hint: Hint::Auto
+                },
+                QueueItem {
+                    query: "song two".to_string(),
+                    start: None,
+                    hint: Hint::Local
+                },
+                QueueItem {
+                    query: "x3".to_string(),
+                    start: None,
+                    hint: Hint::YouTube
+                },
+            ]
+        );
+        assert!(parse("@1:30").is_err());
+        assert!(parse("song x0").is_err());
+        let items: Vec<QueueItem> = parse("Rolling Stones x2 live, Rolling Stones x2").unwrap();
+        let queries: Vec<&str> = items.iter().map(|item| item.query.as_str()).collect();
+        assert_eq!(
+            queries,
+            ["Rolling Stones x2 live", "Rolling Stones", "Rolling Stones"]
+        );
+    }
+
+    #[test]
+    fn keeps_modifiers_between_query_words() {
+        let items: Vec<QueueItem> =
+            parse("meet me @10 tonight, songs about !yt today, live !local @0:30").unwrap();
+        assert_eq!(
+            items,
+            [
+                QueueItem::new("meet me @10 tonight"),
+                QueueItem::new("songs about !yt today"),
+                QueueItem {
+                    query: "live".to_string(),
+                    start: Some(30),
+                    hint: Hint::Local
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_comments_and_lines() {
+        let content: &str =
+            "# Morning mix\nsong #1\n  # Indented comment\nsong two, song three # live\n\n";
+        let queries: Vec<String> = parse(content)
+            .unwrap()
+            .into_iter()
+            .map(|item| item.query)
+            .collect();
+        assert_eq!(queries, ["song #1", "song two", "song three # live"]);
+    }
+
+    #[test]
+    fn writes_items_back() {
+        for input in ["song one @01:30 !local", "\"Hello, Goodbye\"", "\"x3\" !yt"] {
+            let item: QueueItem = parse(input).unwrap().remove(0);
+            assert_eq!(item.to_string(), input);
+        }
+    }
+
+    #[test]
+    fn reads_old_state() {
+        let items: Vec<QueueItem> =
+            serde_json::from_str(r#"["song one", {"query": "song two", "start": 30}]"#).unwrap();
+        assert_eq!(items[0], QueueItem::new("song one"));
+        assert_eq!(items[1].start, Some(30));
+    }
+}
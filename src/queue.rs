@@ -0,0 +1,347 @@
+use crate::log::*;
+use crate::query::QueueItem;
+use serde::{Deserialize, Serialize};
+
+/// How the queue behaves when the current audio ends.
+///
+/// ```txt
+/// Off:    Play every audio once and stop at the end of the queue
+/// One:    Play the current audio again and again
+/// All:    Start the queue from scratch when it ends (loop playlist)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Repeat {
+    Off,
+    One,
+    All,
+}
+
+impl Repeat {
+    /// Parse repeat mode from user input (`off`, `one` or `all`).
+    pub fn parse(value: &str) -> Option<Repeat> {
+        match value.trim().to_lowercase().as_str() {
+            "off" | "none" => Some(Repeat::Off),
+            "one" | "single" => Some(Repeat::One),
+            "all" | "loop" => Some(Repeat::All),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Repeat::Off => "off",
+            Repeat::One => "one",
+            Repeat::All => "all",
+        }
+    }
+
+    /// Returns the next repeat mode for toggling during playback (off -> all -> one -> off).
+    pub fn cycle(self) -> Repeat {
+        match self {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Off,
+        }
+    }
+}
+
+/// Play mode of the queue. It can be saved per playlist (refer to `src/playlist.rs`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayMode {
+    pub shuffle: bool,
+    pub repeat: Repeat,
+}
+
+impl Default for PlayMode {
+    fn default() -> Self {
+        PlayMode {
+            shuffle: false,
+            repeat: Repeat::Off,
+        }
+    }
+}
+
+impl PlayMode {
+    /// Parse play mode from `key=value` lines. Unknown keys are ignored.
+    pub fn parse(content: &str) -> PlayMode {
+        let mut mode: PlayMode = PlayMode::default();
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key.trim() {
+                "shuffle" => mode.shuffle = value.trim() == "true",
+                "repeat" => mode.repeat = Repeat::parse(value).unwrap_or(Repeat::Off),
+                _ => {}
+            }
+        }
+        mode
+    }
+
+    /// Convert play mode into `key=value` lines, the reverse of `PlayMode::parse`.
+    pub fn serialize(&self) -> String {
+        format!(
+            "shuffle={}\nrepeat={}\n",
+            self.shuffle,
+            self.repeat.as_str()
+        )
+    }
+
+    /// Human readable description of play mode, shown to user when it changes.
+    pub fn describe(&self) -> String {
+        format!(
+            "shuffle: {}, repeat: {}",
+            if self.shuffle { "on" } else { "off" },
+            self.repeat.as_str()
+        )
+    }
+}
+
+/// Queue of items to play (refer to `src/query.rs`). Decides which audio will be played next
+/// With the `PlayMode`.
+/// Shuffle doesn't pick an audio again until all audios in the queue are played.
+/// The queue is saved in the session state (refer to `src/state.rs`) to resume it later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Queue {
+    items: Vec<QueueItem>,
+    mode: PlayMode,
+    current: Option<usize>,
+    played: Vec<bool>, // Audios played in the current round
+    #[serde(skip)]
+    replay_current: bool, // Return the current audio again from `advance` (used when resuming)
+}
+
+impl Queue {
+    pub fn new(items: Vec<QueueItem>, mode: PlayMode) -> Queue {
+        let played: Vec<bool> = vec![false; items.len()];
+        Queue {
+            items,
+            mode,
+            current: None,
+            played,
+            replay_current: false,
+        }
+    }
+
+    /// Make the next `advance` call return the current audio again.
+    /// Used to continue a resumed queue from the audio that was playing.
+    pub fn resume(&mut self) {
+        self.replay_current = self.current.is_some();
+    }
+
+    /// Check the queue which is read from a file (refer to `src/state.rs`). Returns an error if
+    /// `played` doesn't match the items or the current audio is not in the queue.
+    pub fn check(&self) -> Result<(), String> {
+        if self.played.len() != self.items.len() {
+            return Err(format!(
+                "Queue has {} items but {} played marks.",
+                self.items.len(),
+                self.played.len()
+            ));
+        }
+        match self.current {
+            Some(current) if current >= self.items.len() => Err(format!(
+                "Current audio {} is not in the queue of {} items.",
+                current,
+                self.items.len()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make the audio at given index current, the next `advance` call returns it.
+    pub fn jump(&mut self, index: usize) {
+        if index < self.items.len() {
+            self.current = Some(index);
+            self.played[index] = true;
+            self.replay_current = true;
+        }
+    }
+
+    /// Returns the index of the current audio.
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn items(&self) -> &[QueueItem] {
+        &self.items
+    }
+
+    /// Add an item to the end of queue.
+    pub fn push(&mut self, item: QueueItem) {
+        self.items.push(item);
+        self.played.push(false);
+    }
+
+    pub fn get(&self, index: usize) -> Option<&QueueItem> {
+        self.items.get(index)
+    }
+
+    pub fn mode(&self) -> PlayMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: PlayMode) {
+        info(
+            "Queue Mode",
+            &format!("Play mode changed to ({}).", mode.describe()),
+        );
+        self.mode = mode;
+    }
+
+    /// Returns `true` if `advance` will select another audio, `false` if the queue ends after
+    /// The current audio.
+    pub fn has_next(&self) -> bool {
+        match self.mode.repeat {
+            Repeat::One | Repeat::All => !self.items.is_empty(),
+            Repeat::Off if self.mode.shuffle => !self.unplayed().is_empty(),
+            Repeat::Off => self.current.map(|i| i + 1).unwrap_or(0) < self.items.len(),
+        }
+    }
+
+    /// Select the next audio and returns its index.
+    /// Returns `None` if the queue is ended.
+    pub fn advance(&mut self) -> Option<usize> {
+        if self.items.is_empty() {
+            return None;
+        }
+
+        if self.replay_current {
+            self.replay_current = false;
+            return self.current;
+        }
+
+        if self.mode.repeat == Repeat::One && self.current.is_some() {
+            return self.current;
+        }
+
+        let next: Option<usize> = if self.mode.shuffle {
+            self.next_shuffled()
+        } else {
+            self.next_in_order()
+        };
+
+        if let Some(index) = next {
+            self.played[index] = true;
+            self.current = Some(index);
+        }
+        next
+    }
+
+    /// Select the next audio like `advance`, but never the current audio again with
+    /// `Repeat::One` (it wraps to the start of the queue instead). Used when user skips the audio.
+    pub fn skip(&mut self) -> Option<usize> {
+        let repeat: Repeat = self.mode.repeat;
+        if repeat == Repeat::One {
+            self.mode.repeat = Repeat::All;
+        }
+        self.replay_current = false;
+        let next: Option<usize> = self.advance();
+        self.mode.repeat = repeat;
+        next
+    }
+
+    fn next_in_order(&mut self) -> Option<usize> {
+        let next: usize = self.current.map(|i| i + 1).unwrap_or(0);
+        if next < self.items.len() {
+            return Some(next);
+        }
+        if self.mode.repeat == Repeat::All {
+            info("Queue Next", "Reached end of the queue. Starting again.");
+            self.new_round();
+            return Some(0);
+        }
+        None
+    }
+
+    fn next_shuffled(&mut self) -> Option<usize> {
+        let mut candidates: Vec<usize> = self.unplayed();
+        if candidates.is_empty() {
+            if self.mode.repeat != Repeat::All {
+                return None;
+            }
+            info("Queue Next", "All audios are played. Starting a new round.");
+            self.new_round();
+            candidates = self.unplayed();
+            // Don't play the last audio again at the start of the new round
+            if candidates.len() > 1 {
+                candidates.retain(|i| Some(*i) != self.current);
+            }
+        }
+        Some(candidates[fastrand::usize(..candidates.len())])
+    }
+
+    fn unplayed(&self) -> Vec<usize> {
+        (0..self.items.len()).filter(|i| !self.played[*i]).collect()
+    }
+
+    fn new_round(&mut self) {
+        self.played = vec![false; self.items.len()];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_queue(length: usize, shuffle: bool, repeat: Repeat) -> Queue {
+        let items: Vec<QueueItem> = (0..length)
+            .map(|i| QueueItem::new(&format!("song {}", i)))
+            .collect();
+        Queue::new(items, PlayMode { shuffle, repeat })
+    }
+
+    #[test]
+    fn shuffle_plays_every_audio_once_per_round() {
+        let mut queue: Queue = new_queue(5, true, Repeat::All);
+        for _ in 0..3 {
+            let mut round: Vec<usize> = (0..5).filter_map(|_| queue.advance()).collect();
+            round.sort();
+            assert_eq!(round, [0, 1, 2, 3, 4]);
+        }
+
+        let mut queue: Queue = new_queue(3, true, Repeat::Off);
+        let played: Vec<Option<usize>> = (0..4).map(|_| queue.advance()).collect();
+        assert_eq!(played.iter().flatten().count(), 3);
+        assert_eq!(played[3], None);
+    }
+
+    #[test]
+    fn repeat_all_wraps_and_off_stops() {
+        let mut queue: Queue = new_queue(2, false, Repeat::All);
+        let played: Vec<Option<usize>> = (0..5).map(|_| queue.advance()).collect();
+        assert_eq!(played, [Some(0), Some(1), Some(0), Some(1), Some(0)]);
+        assert!(queue.has_next());
+
+        let mut queue: Queue = new_queue(2, false, Repeat::Off);
+        assert!(queue.has_next());
+        assert_eq!(queue.advance(), Some(0));
+        assert!(queue.has_next());
+        assert_eq!(queue.advance(), Some(1));
+        assert!(!queue.has_next());
+        assert_eq!(queue.advance(), None);
+        assert!(!Queue::new(vec![], PlayMode::default()).has_next());
+    }
+
+    #[test]
+    fn repeat_one_replays_until_skipped() {
+        let mut queue: Queue = new_queue(3, false, Repeat::One);
+        assert_eq!(queue.advance(), Some(0));
+        assert_eq!(queue.advance(), Some(0));
+        assert!(queue.has_next());
+        assert_eq!(queue.skip(), Some(1));
+        assert_eq!(queue.advance(), Some(1));
+        assert_eq!(queue.mode().repeat, Repeat::One);
+        queue.skip();
+        assert_eq!(queue.skip(), Some(0)); // Wraps to the start
+    }
+}
@@ -0,0 +1,153 @@
+use crate::config;
+use crate::history;
+use crate::log::*;
+use crate::runner::CommandRunner;
+use crate::yt_dlp::{self, artist_and_track, PlaylistEntry, VideoInfo};
+use std::collections::HashSet;
+
+/// Number of related tracks which are added to the queue at once.
+const BATCH: usize = 5;
+
+/// Number of recent plays in the listening history which are not played again by autoplay.
+const HISTORY_WINDOW: usize = 500;
+
+/// A related track, video ID and title.
+pub type Track = (String, String);
+
+/// Returns `true` if the `autoplay` config is enabled.
+pub fn enabled() -> bool {
+    config::get_string(config::AUTOPLAY).as_deref() == Some("true")
+}
+
+/// Returns the URL of YouTube's mix playlist (`RD<id>`) which is made from the given video.
+pub fn mix_url(id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={}&list=RD{}", id, id)
+}
+
+/// Get the video ID from a YouTube URL (like queries which are added by autoplay).
+pub fn video_id(query: &str) -> Option<&str> {
+    let (_, rest) = query.split_once("v=")?;
+    rest.split('&').next().filter(|id| !id.is_empty())
+}
+
+/// Returns IDs of recently played videos from the listening history (refer to `src/history.rs`).
+pub fn recent_ids() -> HashSet<String> {
+    let entries: Vec<history::Entry> = history::read();
+    entries
+        .iter()
+        .rev()
+        .take(HISTORY_WINDOW)
+        .map(|entry| entry.id.clone())
+        .collect()
+}
+
+/// Find tracks which are related to the `seed` video. YouTube's mix playlist of the video is
+/// Tried first, then a search for the artist. Videos in `exclude` (like recently played ones
+/// And the queue) are skipped. Returns an empty list if nothing new is found.
+pub fn related(
+    runner: &dyn CommandRunner,
+    seed: &VideoInfo,
+    exclude: &HashSet<String>,
+) -> Vec<Track> {
+    let (artist, _) = artist_and_track(seed);
+    let sources: [String; 2] = [
+        mix_url(&seed.id),
+        format!("ytsearch{}:{}", BATCH * 4, artist),
+    ];
+    let mut seen: HashSet<String> = exclude.clone();
+    seen.insert(seed.id.clone());
+    let mut tracks: Vec<Track> = vec![];
+    for source in sources {
+        let entries: Vec<PlaylistEntry> = match yt_dlp::list(runner, &source, BATCH * 10) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warning("Radio Related", &e);
+                continue;
+            }
+        };
+        for entry in entries {
+            if tracks.len() < BATCH && seen.insert(entry.id.clone()) {
+                tracks.push((entry.id, entry.title));
+            }
+        }
+        if !tracks.is_empty() {
+            break;
+        }
+        info(
+            "Radio Related",
+            &format!("No new tracks in '{}', trying the next source.", source),
+        );
+    }
+    tracks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::{sandbox, Canned, MockRunner};
+
+    fn video(id: &str, title: &str) -> VideoInfo {
+        VideoInfo {
+            duration: "00:03:00".to_string(),
+            title: title.to_string(),
+            url: format!("https://www.youtube.com/watch?v={}", id),
+            id: id.to_string(),
+            channel: "Channel".to_string(),
+            chapters: vec![],
+        }
+    }
+
+    #[test]
+    fn related_skips_seed_and_excluded() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::ok(
+            "seed\t200\tNA\tSeed Song\nplayed\t200\tNA\tPlayed Song\nnew1\t200\tNA\tNew One\nNA\tNA\tNA\tNA\nnew1\t200\tNA\tNew One\nnew2\tNA\tNA\tNew Two\n",
+        )]);
+        let exclude: HashSet<String> = HashSet::from(["played".to_string()]);
+        let tracks: Vec<Track> = related(&runner, &video("seed", "Artist - Seed Song"), &exclude);
+        assert_eq!(
+            tracks,
+            [
+                ("new1".to_string(), "New One".to_string()),
+                ("new2".to_string(), "New Two".to_string())
+            ]
+        );
+        assert_eq!(
+            runner.calls(),
+            [[
+                "yt-dlp",
+                "https://www.youtube.com/watch?v=seed&list=RDseed",
+                "--flat-playlist",
+                "--playlist-end",
+                "50",
+                "--print",
+                "%(id)s\t%(duration)s\t%(url)s\t%(title)s",
+            ]]
+        );
+    }
+
+    #[test]
+    fn related_searches_artist_without_mix() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![
+            Canned::fail(1, "ERROR: The playlist does not exist"),
+            Canned::ok("other\t180\tNA\tOther Song\n"),
+        ]);
+        let tracks: Vec<Track> = related(
+            &runner,
+            &video("seed", "Artist - Seed Song"),
+            &HashSet::new(),
+        );
+        assert_eq!(tracks, [("other".to_string(), "Other Song".to_string())]);
+        assert_eq!(runner.calls()[1][1], "ytsearch20:Artist");
+    }
+
+    #[test]
+    fn gets_video_id_from_url() {
+        assert_eq!(
+            video_id("https://www.youtube.com/watch?v=abc&list=RDabc"),
+            Some("abc")
+        );
+        assert_eq!(video_id("some song"), None);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::config::Program;
+use crate::supervisor;
+use std::io::{BufRead, BufReader, Read, Result};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+
+/// A running external process (like `mpv`). Implemented by `std::process::Child`,
+/// Mocked processes of tests implement it too.
+pub trait Process: Send {
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>>;
+    fn kill(&mut self) -> Result<()>;
+    fn wait(&mut self) -> Result<ExitStatus>;
+}
+
+impl Process for Child {
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        Child::try_wait(self)
+    }
+
+    fn kill(&mut self) -> Result<()> {
+        Child::kill(self)
+    }
+
+    fn wait(&mut self) -> Result<ExitStatus> {
+        Child::wait(self)
+    }
+}
+
+/// Runs external commands (`yt-dlp`, `mpv`). `yt_dlp` and `service` take a runner instead of
+/// Running commands directly, so tests can check the built commands and give canned outputs.
+pub trait CommandRunner {
+    /// Start the command of the program without waiting for it.
+    fn spawn(&self, program: Program, command: &mut Command) -> Result<Box<dyn Process>>;
+
+    /// Run the command and wait for its output.
+    fn output(&self, command: &mut Command) -> Result<Output>;
+
+    /// Run the command and call `on_line` for every line of its stdout while it runs.
+    /// Returns the exit status and stderr of the command.
+    fn stream(
+        &self,
+        command: &mut Command,
+        on_line: &mut dyn FnMut(&str),
+    ) -> Result<(ExitStatus, String)>;
+}
+
+/// Runs commands for real with `std::process`. Processes are tracked by the supervisor,
+/// So they are terminated when **mpvy** exits (refer to `src/supervisor.rs`).
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn spawn(&self, program: Program, command: &mut Command) -> Result<Box<dyn Process>> {
+        Ok(Box::new(supervisor::spawn_supervised(program, command)?))
+    }
+
+    fn output(&self, command: &mut Command) -> Result<Output> {
+        let child: Child = supervisor::spawn(
+            None,
+            command
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )?;
+        let pid: u32 = child.id();
+        let output: Result<Output> = child.wait_with_output();
+        supervisor::release(pid);
+        output
+    }
+
+    fn stream(
+        &self,
+        command: &mut Command,
+        on_line: &mut dyn FnMut(&str),
+    ) -> Result<(ExitStatus, String)> {
+        let mut child: Child =
+            supervisor::spawn(None, command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        // Read stderr in another thread, so a full stderr pipe doesn't block the command
+        let stderr = child.stderr.take().map(|mut pipe| {
+            std::thread::spawn(move || {
+                let mut stderr: String = String::new();
+                let _ = pipe.read_to_string(&mut stderr);
+                stderr
+            })
+        });
+        if let Some(stdout) = child.stdout.take() {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                on_line(&line);
+            }
+        }
+        let status: Result<ExitStatus> = child.wait();
+        supervisor::release(child.id());
+        let status: ExitStatus = status?;
+        let stderr: String = stderr
+            .and_then(|thread| thread.join().ok())
+            .unwrap_or_default();
+        Ok((status, stderr))
+    }
+}
+
+/// A runner for tests which records commands and returns canned outputs instead of running them.
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::{Mutex, Once};
+
+    static SANDBOX: Once = Once::new();
+
+    /// Use a temporary directory as `$XDG_CONFIG_HOME`, so tests don't read the configuration
+    /// Or write logs of the user. Call it at the start of every test which runs commands.
+    pub fn sandbox() {
+        SANDBOX.call_once(|| {
+            let dir = std::env::temp_dir().join(format!("mpvy-unit-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("mpvy")).unwrap();
+            std::env::set_var("XDG_CONFIG_HOME", &dir);
+        });
+    }
+
+    /// Canned result of a command.
+    #[derive(Debug, Clone, Default)]
+    pub struct Canned {
+        pub code: i32,
+        pub stdout: String,
+        pub stderr: String,
+    }
+
+    impl Canned {
+        pub fn ok(stdout: &str) -> Canned {
+            Canned {
+                stdout: stdout.to_string(),
+                ..Canned::default()
+            }
+        }
+
+        pub fn fail(code: i32, stderr: &str) -> Canned {
+            Canned {
+                code,
+                stderr: stderr.to_string(),
+                ..Canned::default()
+            }
+        }
+
+        fn status(&self) -> ExitStatus {
+            ExitStatus::from_raw(self.code << 8)
+        }
+    }
+
+    /// A process which already exited with the canned exit code.
+    struct MockProcess(ExitStatus);
+
+    impl Process for MockProcess {
+        fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+            Ok(Some(self.0))
+        }
+
+        fn kill(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn wait(&mut self) -> Result<ExitStatus> {
+            Ok(self.0)
+        }
+    }
+
+    /// Records the argument vectors (with the program first) of every command.
+    /// Canned results are returned in order, a successful empty result when they run out.
+    #[derive(Default)]
+    pub struct MockRunner {
+        calls: Mutex<Vec<Vec<String>>>,
+        results: Mutex<VecDeque<Canned>>,
+    }
+
+    impl MockRunner {
+        pub fn new(results: Vec<Canned>) -> MockRunner {
+            MockRunner {
+                calls: Mutex::new(vec![]),
+                results: Mutex::new(results.into()),
+            }
+        }
+
+        pub fn calls(&self) -> Vec<Vec<String>> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn record(&self, command: &Command) -> Canned {
+            let argv: Vec<String> = std::iter::once(command.get_program())
+                .chain(command.get_args())
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect();
+            self.calls.lock().unwrap().push(argv);
+            self.results.lock().unwrap().pop_front().unwrap_or_default()
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn spawn(&self, _program: Program, command: &mut Command) -> Result<Box<dyn Process>> {
+            Ok(Box::new(MockProcess(self.record(command).status())))
+        }
+
+        fn output(&self, command: &mut Command) -> Result<Output> {
+            let canned: Canned = self.record(command);
+            Ok(Output {
+                status: canned.status(),
+                stdout: canned.stdout.into_bytes(),
+                stderr: canned.stderr.into_bytes(),
+            })
+        }
+
+        fn stream(
+            &self,
+            command: &mut Command,
+            on_line: &mut dyn FnMut(&str),
+        ) -> Result<(ExitStatus, String)> {
+            let canned: Canned = self.record(command);
+            canned.stdout.lines().for_each(on_line);
+            Ok((canned.status(), canned.stderr))
+        }
+    }
+}
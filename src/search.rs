@@ -0,0 +1,305 @@
+use crate::config;
+use crate::log::*;
+use crate::query::QueueItem;
+use crate::runner::CommandRunner;
+use crate::service;
+use crate::yt_dlp::{self, PlaylistEntry};
+
+/// What a query searches for. Plain queries are searched as a single video, prefixed queries
+/// Are expanded into many videos (refer to `expand`).
+///
+/// ```txt
+/// Video:      `<query>`            A single video (default)
+/// Artist:     `artist:<name>`      Songs of the artist on YouTube Music
+/// Album:      `album:<name>`       Tracks of the first album found on YouTube Music
+/// Channel:    `channel:<name>`     Uploads of the channel (`@handle`, name or URL)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Video(String),
+    Artist(String),
+    Album(String),
+    Channel(String),
+}
+
+impl Source {
+    /// Parse the prefix of a query. Queries without a known prefix (or with an empty name)
+    /// Are videos.
+    pub fn parse(query: &str) -> Source {
+        let query: &str = query.trim();
+        let Some((prefix, name)) = query.split_once(':') else {
+            return Source::Video(query.to_string());
+        };
+        let name: String = name.trim().to_string();
+        if name.is_empty() {
+            return Source::Video(query.to_string());
+        }
+        match prefix.trim().to_lowercase().as_str() {
+            "artist" => Source::Artist(name),
+            "album" => Source::Album(name),
+            "channel" => Source::Channel(name),
+            _ => Source::Video(query.to_string()),
+        }
+    }
+}
+
+/// Filters for videos which are found by prefixed queries, from the `[search]` section of
+/// Configuration. Videos without a listed duration are not filtered by duration.
+/// Values:
+///
+/// ```txt
+/// limit:          Max number of videos for a query
+/// min_duration:   Shorter videos are skipped (in seconds, like intros and shorts)
+/// max_duration:   Longer videos are skipped (in seconds, like live streams and full mixes)
+/// exclude:        Videos whose titles have one of these words are skipped (like `live`, `cover`)
+/// ```
+#[derive(Debug, Clone)]
+pub struct Filters {
+    pub limit: usize,
+    pub min_duration: f64,
+    pub max_duration: f64,
+    pub exclude: Vec<String>,
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Filters {
+            limit: 25,
+            min_duration: 60.0,
+            max_duration: 900.0,
+            exclude: vec![],
+        }
+    }
+}
+
+impl Filters {
+    /// Returns `true` if the entry passes the filters.
+    pub fn matches(&self, entry: &PlaylistEntry) -> bool {
+        if let Some(duration) = entry.duration {
+            if duration < self.min_duration || duration > self.max_duration {
+                return false;
+            }
+        }
+        let title: String = entry.title.to_lowercase();
+        !self
+            .exclude
+            .iter()
+            .any(|word| title.contains(&word.to_lowercase()))
+    }
+}
+
+/// Read filters from configuration.
+pub fn filters() -> Filters {
+    let default: Filters = Filters::default();
+    let number = |key: &str| config::get_string(key).and_then(|value| value.parse::<f64>().ok());
+    Filters {
+        limit: number(config::SEARCH_LIMIT)
+            .map(|limit| limit as usize)
+            .unwrap_or(default.limit),
+        min_duration: number(config::SEARCH_MIN_DURATION).unwrap_or(default.min_duration),
+        max_duration: number(config::SEARCH_MAX_DURATION).unwrap_or(default.max_duration),
+        exclude: config::get_array(config::SEARCH_EXCLUDE),
+    }
+}
+
+/// Encode text for a URL query (`q=`) parameter.
+fn encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            b' ' => "+".to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Returns the URL of a YouTube Music search in the given section (`songs`, `albums`).
+fn music_search_url(query: &str, section: &str) -> String {
+    format!(
+        "https://music.youtube.com/search?q={}#{}",
+        encode(query),
+        section
+    )
+}
+
+/// Returns the URL of uploads of a channel. `name` can be a handle (`@name`), a channel name
+/// (which is used as handle without spaces) or an URL.
+fn channel_url(name: &str) -> String {
+    if name.starts_with("https://") || name.starts_with("http://") {
+        return format!("{}/videos", name.trim_end_matches('/'));
+    }
+    let handle: String = name.trim_start_matches('@').replace(' ', "");
+    format!("https://www.youtube.com/@{}/videos", handle)
+}
+
+/// List the videos of a prefixed query which pass the filters.
+fn resolve(
+    runner: &dyn CommandRunner,
+    source: &Source,
+    filters: &Filters,
+) -> Result<Vec<PlaylistEntry>, String> {
+    // More entries are listed than the limit, because some of them are filtered
+    let limit: usize = filters.limit * 2;
+    let entries: Vec<PlaylistEntry> = match source {
+        Source::Video(query) => return Err(format!("'{}' is not a prefixed query.", query)),
+        Source::Artist(name) => yt_dlp::list(runner, &music_search_url(name, "songs"), limit)?,
+        Source::Channel(name) => yt_dlp::list(runner, &channel_url(name), limit)?,
+        Source::Album(name) => {
+            let albums: Vec<PlaylistEntry> =
+                yt_dlp::list(runner, &music_search_url(name, "albums"), 1)?;
+            let album: &PlaylistEntry = albums
+                .first()
+                .ok_or(format!("No album found for '{}'.", name))?;
+            info(
+                "Search Album",
+                &format!("Found album '{}' for '{}'.", album.title, name),
+            );
+            yt_dlp::list(runner, &album.url, limit)?
+        }
+    };
+    Ok(entries
+        .into_iter()
+        .filter(|entry| filters.matches(entry))
+        .take(filters.limit)
+        .collect())
+}
+
+/// Expand items with prefixed queries (like `artist:<name>`) into items of their videos, so
+/// They can be added to the queue. Other items are returned as they are. Items which can't be
+/// Expanded are logged and skipped. Expanded items keep the source hint of the item.
+pub fn expand(runner: &dyn CommandRunner, items: Vec<QueueItem>) -> Vec<QueueItem> {
+    let filters: Filters = filters();
+    let mut expanded: Vec<QueueItem> = vec![];
+    for item in items {
+        let query: &str = &item.query;
+        let source: Source = Source::parse(query);
+        if let Source::Video(_) = source {
+            expanded.push(item);
+            continue;
+        }
+        match resolve(runner, &source, &filters) {
+            Ok(entries) if !entries.is_empty() => {
+                info(
+                    "Search Expand",
+                    &format!("Expanded '{}' into {} videos.", query, entries.len()),
+                );
+                expanded.extend(entries.iter().map(|entry| QueueItem {
+                    query: service::video_url(&entry.id),
+                    start: None,
+                    hint: item.hint,
+                }));
+            }
+            Ok(_) => warning(
+                "Search Expand",
+                &format!("No videos found for '{}'.", query),
+            ),
+            Err(e) => error(
+                "Search Expand",
+                &format!("Unable to expand '{}': {}", query, e),
+            ),
+        }
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::{sandbox, Canned, MockRunner};
+
+    #[test]
+    fn parses_prefixes() {
+        assert_eq!(
+            Source::parse("Artist: Daft Punk"),
+            Source::Artist("Daft Punk".to_string())
+        );
+        assert_eq!(
+            Source::parse("album:Discovery"),
+            Source::Album("Discovery".to_string())
+        );
+        assert_eq!(
+            Source::parse("channel:@lofigirl"),
+            Source::Channel("@lofigirl".to_string())
+        );
+        assert_eq!(
+            Source::parse("artist:"),
+            Source::Video("artist:".to_string())
+        );
+        assert_eq!(
+            Source::parse("re: stacks"),
+            Source::Video("re: stacks".to_string())
+        );
+    }
+
+    #[test]
+    fn expands_artist_with_filters() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::ok(
+            "a1\t200\tNA\tOne More Time\na2\t30\tNA\tIntro\na3\t1800\tNA\tFull Concert\na4\t240\tNA\tAround the World (Live)\na5\tNA\tNA\tAerodynamic\n",
+        )]);
+        let filters: Filters = Filters {
+            exclude: vec!["live".to_string()],
+            ..Filters::default()
+        };
+        let entries: Vec<PlaylistEntry> =
+            resolve(&runner, &Source::Artist("Daft Punk".to_string()), &filters).unwrap();
+        let ids: Vec<&str> = entries.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(ids, ["a1", "a5"]);
+        assert_eq!(
+            runner.calls()[0][1],
+            "https://music.youtube.com/search?q=Daft+Punk#songs"
+        );
+        assert_eq!(runner.calls()[0][4], "50");
+    }
+
+    #[test]
+    fn expands_album_tracks() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![
+            Canned::ok("MPREb_1\tNA\thttps://music.youtube.com/browse/MPREb_1\tDiscovery\n"),
+            Canned::ok("t1\t320\tNA\tOne More Time\nt2\t212\tNA\tAerodynamic\n"),
+        ]);
+        let expanded: Vec<String> = expand(
+            &runner,
+            vec![
+                QueueItem::new("album:Discovery"),
+                QueueItem::new("some song"),
+            ],
+        )
+        .into_iter()
+        .map(|item| item.query)
+        .collect();
+        assert_eq!(
+            expanded,
+            [
+                "https://www.youtube.com/watch?v=t1",
+                "https://www.youtube.com/watch?v=t2",
+                "some song"
+            ]
+        );
+        let calls: Vec<Vec<String>> = runner.calls();
+        assert_eq!(
+            calls[0][1],
+            "https://music.youtube.com/search?q=Discovery#albums"
+        );
+        assert_eq!(calls[1][1], "https://music.youtube.com/browse/MPREb_1");
+    }
+
+    #[test]
+    fn builds_channel_urls() {
+        assert_eq!(
+            channel_url("@lofigirl"),
+            "https://www.youtube.com/@lofigirl/videos"
+        );
+        assert_eq!(
+            channel_url("Lofi Girl"),
+            "https://www.youtube.com/@LofiGirl/videos"
+        );
+        assert_eq!(
+            channel_url("https://www.youtube.com/channel/UC123/"),
+            "https://www.youtube.com/channel/UC123/videos"
+        );
+    }
+}
@@ -1,10 +1,25 @@
-use crate::log::*;
+use crate::cache;
+use crate::config::Program;
+use crate::events::{self, Event};
+use crate::history;
+use crate::ipc;
+use crate::log::{self, *};
+use crate::lyrics;
+use crate::normalize;
+use crate::query::{Hint, QueueItem};
+use crate::runner::{CommandRunner, Process};
+use crate::sponsorblock;
+use crate::state;
+use crate::supervisor;
+use crate::time::now;
 use crate::yt_dlp;
 use crate::yt_dlp::VideoInfo;
 use dirs;
-use std::process::Command;
-use std::thread::sleep;
-use std::time::Duration;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
 
 /// Returns the path of Mpv IPC file.
 /// `mpvy` don't use this directly but giving this as argument to **mpv**
@@ -18,12 +33,48 @@ pub fn ipc_path() -> String {
     )
 }
 
+/// Volume of played audios, changed from the shell (refer to `src/shell.rs`).
+/// `None` uses the default volume of **mpv**.
+static VOLUME: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Returns the volume which is given to new `mpv` processes.
+pub fn volume() -> Option<u64> {
+    *VOLUME.lock().unwrap()
+}
+
+/// Change the volume of next audios (0-100). The playing audio is changed over IPC by the caller.
+pub fn set_volume(volume: u64) {
+    *VOLUME.lock().unwrap() = Some(volume.min(100));
+}
+
+/// Returns the YouTube URL of video with given ID.
+pub fn video_url(id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={}", id)
+}
+
 /// Spawns `mpv` command with some default arguments to prevent issues.
 /// We are using **local files** because we are want to play audio **downloaded (or cached)**
 /// Even user is offline. Also you can copy your musics to anywhere.
-fn mpv(path: &str) -> Result<(), String> {
+/// If `start` is given, the audio starts from that position (in seconds).
+/// If `filter` is given, it is used as audio filter (like loudness normalisation).
+fn mpv(
+    runner: &dyn CommandRunner,
+    path: &str,
+    start: Option<u64>,
+    filter: Option<String>,
+) -> Result<Box<dyn Process>, String> {
     info("Service Mpv", "Playing audio with 'mpv'");
-    let command = Command::new("mpv")
+    let mut command: Command = Program::Mpv.command();
+    if let Some(start) = start {
+        command.arg(format!("--start={}", start)); // Start from the given position
+    }
+    if let Some(filter) = filter {
+        command.arg(format!("--af={}", filter)); // Audio filter, refer to `src/normalize.rs`
+    }
+    if let Some(volume) = volume() {
+        command.arg(format!("--volume={}", volume)); // Volume which is changed in the shell
+    }
+    command
         .arg("--no-terminal") // Prevent terminal output from mpv
         .arg(format!(
             "--log-file={}/mpvy/log/mpv.log",
@@ -33,76 +84,313 @@ fn mpv(path: &str) -> Result<(), String> {
         )) // Change log file path to 'mpvy' log directory
         .arg(format!("--input-ipc-server={}", ipc_path())) // Set up IPC server for controlling mpv
         .arg(path) // Path to the video file to be played
-        .spawn(); // Start the mpv process
+        .args(Program::Mpv.extra_args()); // Extra arguments from configuration
+    log::command("Service Mpv", &command);
 
-    if command.is_err() {
-        error(
-            "Service Mpv",
-            "Unable to play audio with mpv. Maybe mpv is not downloaded?",
+    match runner.spawn(Program::Mpv, &mut command) {
+        Ok(child) => Ok(child),
+        Err(e) => {
+            error(
+                "Service Mpv",
+                &format!(
+                    "Unable to play audio with mpv: {}. Run 'mpvy doctor' to check dependencies.",
+                    e
+                ),
+            );
+            Err(e.to_string())
+        }
+    }
+}
+
+/// Start `cava` (an audio visualizer) in the terminal. It shows the audio of every `mpv`, so
+/// It runs until **mpvy** exits and it is terminated by the supervisor (refer to `src/supervisor.rs`).
+/// Returns `None` if `cava` can't be started.
+pub fn cava() -> Option<Child> {
+    info("Service Cava", "Cava is enabled. Starting child process.");
+    let mut command: Command = Program::Cava.command();
+    command.args(Program::Cava.extra_args());
+    log::command("Service Cava", &command);
+    match supervisor::spawn(Some(Program::Cava), &mut command) {
+        Ok(child) => Some(child),
+        Err(e) => {
+            error("Service Cava", &format!("Failed to start 'cava': {}", e));
+            println!("Unable to start 'cava', continuing without it. Run 'mpvy doctor' to check dependencies.");
+            None
+        }
+    }
+}
+
+/// Reads the playback position of a running `mpv` over IPC in its own thread, from the moment
+/// `mpv` is started. So it keeps working while the next audio is fetched (refer to `play`).
+/// While polling, the position is saved to the session state and marked SponsorBlock segments
+/// Are skipped. The thread returns how long the audio actually played, counted from `start`
+/// (the position which `mpv` starts from), so the time before the first reading is not lost.
+struct Poller {
+    done: Arc<AtomicBool>,
+    handle: JoinHandle<f64>,
+}
+
+impl Poller {
+    fn start(start: Option<u64>) -> Poller {
+        let done: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+        let finished: Arc<AtomicBool> = Arc::clone(&done);
+        let handle: JoinHandle<f64> = thread::spawn(move || {
+            let interval: Duration = Duration::from_millis(500);
+            let mut played: f64 = 0.0;
+            let mut last_position: f64 = start.unwrap_or(0) as f64;
+            let mut last_check: Instant = Instant::now();
+            // Marked SponsorBlock segments, read once when `mpv` is ready
+            let mut segments: Option<Vec<(f64, f64)>> = None;
+            let skip_segments: bool = !sponsorblock::options().mark.is_empty();
+
+            while !finished.load(Ordering::SeqCst) {
+                if let Some(position) = ipc::position() {
+                    // Only count the time that position really moved forward. So pauses and seeks
+                    // Don't change the played duration.
+                    let elapsed: f64 = last_check.elapsed().as_secs_f64();
+                    if position > last_position {
+                        played += (position - last_position).min(elapsed + 0.1);
+                    }
+                    last_position = position;
+                    last_check = Instant::now();
+                    state::set_position(position);
+
+                    if skip_segments && segments.is_none() {
+                        segments = sponsorblock::segments();
+                    }
+                    if let Some(segments) = &segments {
+                        sponsorblock::skip(segments, position);
+                    }
+                }
+                sleep(interval);
+            }
+            played
+        });
+        Poller { done, handle }
+    }
+
+    /// Stop polling, after `mpv` exits. Returns how long the audio played in seconds.
+    fn finish(self) -> f64 {
+        self.done.store(true, Ordering::SeqCst);
+        self.handle.join().unwrap_or(0.0)
+    }
+}
+
+/// An audio which is played by a running `mpv` process.
+pub struct Playback {
+    pub video: VideoInfo,
+    pub query: String,
+    started: u64, // When the audio started playing (seconds since Unix epoch)
+    child: Box<dyn Process>,
+    poller: Poller,
+}
+
+impl Playback {
+    /// Stop the audio without recording it to the listening history.
+    pub fn stop(mut self) {
+        info("Service Stop", &format!("Stopping '{}'.", self.video.title));
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        self.poller.finish();
+    }
+
+    /// Wait for the `mpv` process to exit (audio ended or closed by user).
+    /// Then the play is recorded to the listening history with the duration which is
+    /// Counted by the poller (refer to `Poller`).
+    pub fn wait(mut self) -> VideoInfo {
+        info(
+            "Service Wait",
+            &format!("Waiting for '{}' to end.", self.video.title),
         );
-        return Err(command.unwrap_err().to_string());
+        match self.child.wait() {
+            Ok(status) => log::command_exit("Service Wait", Some(status), ""),
+            Err(e) => error(
+                "Service Wait",
+                &format!("Unable to wait for 'mpv' process: {}", e),
+            ),
+        }
+        let played: f64 = self.poller.finish();
+
+        events::emit(Event::TrackEnded {
+            id: self.video.id.clone(),
+            title: self.video.title.clone(),
+            played: played.round() as u64,
+        });
+        let _ = history::record(&history::Entry {
+            timestamp: self.started,
+            query: self.query.clone(),
+            id: self.video.id.clone(),
+            title: self.video.title.clone(),
+            channel: self.video.channel.clone(),
+            played: played.round() as u64,
+        });
+        self.video
     }
-    Ok(())
 }
 
-/// An function which merges `yt-dlp` APIs for easy usage in coding.
-/// Also uses some optimizations for better user experience.
-/// And logs informative messages
-pub fn play(title: &str, duration: u64) -> Result<yt_dlp::VideoInfo, String> {
+/// Store title, channel and duration of the video in the cache index,
+/// So `!local` items can be played without network.
+fn remember(video: &VideoInfo) {
+    cache::update(&video.id, |entry| {
+        entry.title = Some(video.title.clone());
+        entry.channel = Some(video.channel.clone());
+        entry.duration = Some(video.duration.clone());
+    });
+}
+
+/// Find the cached audio of a `!local` item in the cache index, without network.
+fn fetch_local(query: &str) -> Result<(VideoInfo, String), String> {
+    let Some((id, entry)) = cache::find(query) else {
+        error(
+            "Service Play",
+            &format!("'{}' is not found in the cached audios.", query),
+        );
+        return Err(format!("'{}' is not in the cache.", query));
+    };
     info(
         "Service Play",
-        &format!("Trying to play audio with query: '{}'.", title),
+        &format!("Found cached audio '{}' for '{}'.", id, query),
     );
+    let video: VideoInfo = VideoInfo {
+        duration: entry.duration.unwrap_or("NA".to_string()),
+        title: entry.title.unwrap_or(id.clone()),
+        url: video_url(&id),
+        id: id.clone(),
+        channel: entry.channel.unwrap_or_default(),
+        chapters: vec![],
+    };
+    Ok((video, cache::audio_path(&id)))
+}
 
-    let video_result: Result<yt_dlp::VideoInfo, String> = yt_dlp::get_info(title); // Get video information (such as duration, title, id)
-    let video: VideoInfo = match video_result {
-        Ok(info) => info,
+/// Get information about audio and download it if it is not in the mp3 directory.
+/// Returns the video information and the path of audio file.
+/// `!local` items are only played from the cache, `!yt` items are downloaded again (refer to `query::Hint`).
+fn fetch(runner: &dyn CommandRunner, item: &QueueItem) -> Result<(VideoInfo, String), String> {
+    if item.hint == Hint::Local {
+        return fetch_local(&item.query);
+    }
+    let video: VideoInfo = match yt_dlp::get_info(runner, &item.query) {
+        Ok(info) => info, // Get video information (such as duration, title, id)
         Err(err) => {
             error("Service Play", "Unable to get audio info.");
-            return Err(err.to_string());
+            return Err(err);
         }
     };
 
-    let path: String = format!(
-        "{}/{}_{}.mp3", // $HOME/.config/mpvy/mp3/<video_title>_<video_id>.mp3
-        yt_dlp::get_download_path(),
-        video.title,
-        video.id
-    );
+    let path: String = cache::audio_path(&video.id); // $HOME/.config/mpvy/mp3/<video_id>.mp3
+    let cached: bool = std::fs::exists(&path).unwrap_or(false);
 
     // If the audio is already downloaded, play it directly
-    if std::fs::exists(&path).unwrap_or(false) {
+    if cached && item.hint != Hint::YouTube {
         info(
             "Service Play",
             "Audio found in the mp3 directory, skipping download.",
         );
-        let seconds: Duration = Duration::from_secs(duration);
-        // Wait for previous audio to end
-        info("Service Play", "Waiting for previous audio to end.");
-        sleep(seconds);
-        info(
-            "Service Play",
-            &format!("Previous audio ended, now playing '{}'.", video.title),
-        );
-        let result: Result<(), String> = mpv(&path);
-        if result.is_err() {
-            return Err(String::from(result.unwrap_err()));
+        if cache::get(&video.id).is_none_or(|entry| entry.channel.is_none()) {
+            remember(&video); // Cached by an older version
         }
-        return Ok(video);
+        normalize::prepare(runner, &video.id, &path);
+        lyrics::prepare(runner, &video.id, &path, &lyrics::providers());
+        return Ok((video, path));
+    }
+
+    // `yt-dlp` doesn't download again if the file exists
+    if cached {
+        info("Service Play", "Downloading cached audio again ('!yt').");
+        let _ = std::fs::remove_file(&path);
     }
 
     // If the audio is not downloaded, download it first
     info("Service Play", "Downloading audio.");
-    let output: Result<(), String> = yt_dlp::download(&video.id);
-    if output.is_err() {
+    if let Err(err) = yt_dlp::download(runner, &video) {
         error("Service Play", "Unable to download audio.");
-        return Err(output.unwrap_err().to_string());
+        return Err(err);
     }
-    info("Service Play", "Video downloaded successfuly, now playing.");
-    let result: Result<(), String> = mpv(&path);
-    if result.is_err() {
-        return Err(String::from(result.unwrap_err()));
+    info("Service Play", "Video downloaded successfuly.");
+    remember(&video);
+    normalize::prepare(runner, &video.id, &path);
+    lyrics::prepare(runner, &video.id, &path, &lyrics::providers());
+    Ok((video, path))
+}
+
+/// An function which merges `yt-dlp` APIs for easy usage in coding.
+/// The audio is fetched (and downloaded if needed) while the `previous` audio is still playing,
+/// So the next audio starts right after the previous one ends.
+/// If `start` is given, the audio starts from that position (in seconds).
+/// And logs informative messages. The commands are run with the given runner (refer to `src/runner.rs`).
+pub fn play(
+    runner: &dyn CommandRunner,
+    item: &QueueItem,
+    start: Option<u64>,
+    previous: Option<Playback>,
+) -> Result<Playback, String> {
+    info(
+        "Service Play",
+        &format!("Trying to play audio with query: '{}'.", item),
+    );
+
+    let fetched: Result<(VideoInfo, String), String> = fetch(runner, item);
+
+    // Wait for previous audio to end
+    if let Some(previous) = previous {
+        info("Service Play", "Waiting for previous audio to end.");
+        previous.wait();
     }
 
-    Ok(video)
+    let (video, path) = fetched?;
+    info(
+        "Service Play",
+        &format!("Previous audio ended, now playing '{}'.", video.title),
+    );
+    let child: Box<dyn Process> = mpv(runner, &path, start, normalize::filter(&video.id))?;
+    events::emit(Event::TrackStarted {
+        query: item.query.clone(),
+        id: video.id.clone(),
+        title: video.title.clone(),
+        channel: video.channel.clone(),
+        duration: video.duration.clone(),
+        url: video.url.clone(),
+    });
+    Ok(Playback {
+        video,
+        query: item.query.clone(),
+        started: now(),
+        child,
+        poller: Poller::start(start),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::{sandbox, Canned, MockRunner};
+
+    #[test]
+    fn mpv_builds_command() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::default()]);
+        assert!(mpv(
+            &runner,
+            "/tmp/a b.mp3",
+            Some(30),
+            Some("lavfi=[dynaudnorm]".to_string())
+        )
+        .is_ok());
+        let log_file: String = format!(
+            "--log-file={}/mpvy/log/mpv.log",
+            dirs::config_dir().unwrap().display()
+        );
+        assert_eq!(
+            runner.calls(),
+            [[
+                "mpv",
+                "--start=30",
+                "--af=lavfi=[dynaudnorm]",
+                "--no-terminal",
+                &log_file,
+                &format!("--input-ipc-server={}", ipc_path()),
+                "/tmp/a b.mp3",
+            ]]
+        );
+    }
 }
@@ -0,0 +1,322 @@
+use crate::daemon;
+use crate::ipc;
+use crate::log::*;
+use crate::lyrics;
+use crate::player::Player;
+use crate::playlist;
+use crate::prompt::Prompt;
+use crate::query::{self, QueueItem};
+use crate::queue::{PlayMode, Queue};
+use crate::runner::SystemRunner;
+use crate::search;
+use crate::time::format_duration;
+use rustyline::ExternalPrinter;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Commands of the shell, they are completed with Tab at the start of line.
+pub const COMMANDS: [&str; 13] = [
+    "add", "playlist", "skip", "list", "save", "volume", "shuffle", "repeat", "chapters", "n", "p",
+    "help", "quit",
+];
+
+const HELP: &str = "Commands:
+  add <query>           Add queries to the queue (lines which are not commands are added too)
+  playlist <name>       Add a saved playlist to the queue
+  skip                  Skip the playing audio
+  list                  List the queue (or 'queue', 'l')
+  save <name>           Save the queue as a playlist
+  volume [0-100|+N|-N]  Show or change the volume
+  shuffle, repeat       Toggle shuffle, change repeat mode (or 's', 'r')
+  chapters, n, p        List chapters, go to next / previous chapter (or 'c')
+  quit                  Stop playing and exit (or 'q', 'exit', Ctrl-D)";
+
+/// What the shell does after a command.
+pub enum Reply {
+    Message(String),
+    Quit,
+}
+
+/// Add the items to the queue. Queries like `artist:<name>` are expanded into their videos.
+fn add_items(player: &Player, items: Vec<QueueItem>) -> String {
+    let items: Vec<QueueItem> = search::expand(&SystemRunner, items);
+    if items.is_empty() {
+        return "Nothing is added to the queue.".to_string();
+    }
+    let count: usize = items.len();
+    player.add(items);
+    format!("Added {} audio(s) to the queue.", count)
+}
+
+/// Save the queue as a playlist (one item per line, in query syntax) with its play mode.
+fn save(player: &Player, name: &str) -> String {
+    if name.is_empty() || name.contains('/') {
+        return "Usage: save <name>".to_string();
+    }
+    let queue: Queue = player.queue();
+    if queue.is_empty() {
+        return "The queue is empty.".to_string();
+    }
+    let content: String = queue
+        .items()
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+    if let Err(e) = playlist::write_playlist(name, content) {
+        return format!("Unable to save playlist: {}", e);
+    }
+    let _ = playlist::write_mode(name, &queue.mode());
+    info(
+        "Shell Save",
+        &format!("Queue is saved as playlist '{}'.", name),
+    );
+    format!("Saved {} audio(s) as playlist '{}'.", queue.len(), name)
+}
+
+/// Returns the volume which is set by the value: the given value (`50`) or the current volume
+/// Changed by the given amount (`+5`, `-5`), between 0 and 100. Returns `None` if it is invalid.
+fn new_volume(current: u64, value: &str) -> Option<u64> {
+    let volume: Option<u64> = match (value.strip_prefix('+'), value.strip_prefix('-')) {
+        (Some(amount), _) => amount
+            .parse::<u64>()
+            .ok()
+            .map(|amount| current.saturating_add(amount)),
+        (_, Some(amount)) => amount
+            .parse::<u64>()
+            .ok()
+            .map(|amount| current.saturating_sub(amount)),
+        _ => value.parse::<u64>().ok(),
+    };
+    volume.map(|volume| volume.min(100))
+}
+
+/// Show the volume, or change it to the given value (`50`) or by the given amount (`+5`, `-5`).
+fn volume(player: &Player, value: &str) -> String {
+    let current: u64 = player.volume().unwrap_or(100);
+    if value.is_empty() {
+        return format!("Volume: {}", current);
+    }
+    let Some(volume) = new_volume(current, value) else {
+        return "Usage: volume [0-100|+N|-N]".to_string();
+    };
+    match player.set_volume(volume) {
+        Ok(_) => format!("Volume: {}", volume),
+        Err(e) => format!("Unable to change volume: {}", e),
+    }
+}
+
+/// List chapters of the playing audio as sub-tracks. The playing chapter is marked with `>`.
+fn chapters(player: &Player) -> String {
+    let status = player.status();
+    let Some(video) = status.playing.filter(|video| !video.chapters.is_empty()) else {
+        return "The playing audio has no chapters.".to_string();
+    };
+    let mut lines: Vec<String> = vec![video.title.clone()];
+    for (i, chapter) in video.chapters.iter().enumerate() {
+        lines.push(format!(
+            "{} {:>3}. {}  {}",
+            if status.chapter == Some(i) { ">" } else { " " },
+            i + 1,
+            format_duration(chapter.start_time as u64),
+            chapter.title
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Run a line of the shell on the player. Lines which are not commands are queries
+/// (refer to `src/query.rs`) and they are added to the queue.
+pub fn execute(player: &Player, line: &str) -> Reply {
+    let line: &str = line.trim();
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args: &str = args.trim();
+    debug("Shell Execute", &format!("Received line: '{}'.", line));
+
+    let mut mode: PlayMode = player.queue().mode();
+    let message: String = match command {
+        "" => String::new(),
+        "quit" | "q" | "exit" => return Reply::Quit,
+        "help" | "?" => HELP.to_string(),
+        "add" if args.is_empty() => "Usage: add <query>".to_string(),
+        "add" => match query::parse(args) {
+            Ok(items) => add_items(player, items),
+            Err(e) => format!("Invalid query: {}", e),
+        },
+        "playlist" => match playlist::read_items(args) {
+            Ok(items) => add_items(player, items),
+            Err(_) => format!("Playlist '{}' is not found.", args),
+        },
+        "skip" => match player.skip() {
+            Ok(_) => "Skipped to the next audio.".to_string(),
+            Err(e) => format!("Unable to skip: {}", e),
+        },
+        "list" | "queue" | "l" => daemon::list_queue(&player.status()),
+        "save" => save(player, args),
+        "volume" | "vol" => volume(player, args),
+        "chapters" | "c" => chapters(player),
+        "n" | "p" => match player.seek_chapter(if command == "n" { 1 } else { -1 }) {
+            Ok(title) => format!("Chapter: {}", title),
+            Err(e) => e,
+        },
+        "shuffle" | "s" => {
+            mode.shuffle = !mode.shuffle;
+            player.set_mode(mode);
+            format!("Play mode: {}", mode.describe())
+        }
+        "repeat" | "r" => {
+            mode.repeat = mode.repeat.cycle();
+            player.set_mode(mode);
+            format!("Play mode: {}", mode.describe())
+        }
+        _ => match query::parse(line) {
+            Ok(items) => add_items(player, items),
+            Err(e) => format!("Invalid query: {}. Type 'help' for commands.", e),
+        },
+    };
+    Reply::Message(message)
+}
+
+/// Number of lyrics lines shown before and after the current line.
+const LYRICS_CONTEXT: usize = 4;
+
+/// Show lyrics of the playing audio while it plays (refer to `src/lyrics.rs`).
+/// With a printer (the prompt is on the screen), every new line is printed above the prompt.
+/// Otherwise the screen is redrawn when the current line changes, and it is highlighted.
+fn show_lyrics(player: Arc<Player>, mut printer: Option<Box<dyn ExternalPrinter + Send>>) {
+    let mut lines: Vec<lyrics::Line> = vec![];
+    let mut id: String = String::new(); // Video ID of the lyrics
+    let mut shown: Option<Option<usize>> = None; // Current line on the screen
+    loop {
+        thread::sleep(Duration::from_millis(250));
+        let Some(video) = player.now_playing() else {
+            continue;
+        };
+        // Lyrics are read once for every audio, they are stored before it starts playing
+        if video.id != id {
+            lines = lyrics::get(&video.id);
+            id = video.id.clone();
+            shown = None;
+        }
+        if lines.is_empty() {
+            continue;
+        }
+        let current: Option<usize> =
+            ipc::position().and_then(|position| lyrics::line_at(&lines, position));
+        if shown == Some(current) {
+            continue;
+        }
+        let first: bool = shown.is_none();
+        shown = Some(current);
+        let Some(printer) = printer.as_mut() else {
+            crate::clear_console();
+            println!("{}\n", video.title);
+            println!(
+                "{}",
+                lyrics::render(&lines, current, Some(LYRICS_CONTEXT), true)
+            );
+            continue;
+        };
+        if first {
+            let _ = printer.print(format!("\n{}", video.title));
+        }
+        if let Some(current) = current {
+            let _ = printer.print(lyrics::render(&lines, Some(current), Some(0), true));
+        }
+    }
+}
+
+/// Read commands from the terminal while audios are playing, until user quits.
+/// If `interactive` is true (the player waits for new audios at the end of the queue),
+/// The player is stopped when the input is closed too. Otherwise (like when the input is
+/// A pipe) the queue plays until it ends. If `lyrics` is true, lyrics are shown too.
+pub fn run(player: Arc<Player>, interactive: bool, lyrics: bool) {
+    let mut prompt: Prompt = match Prompt::new(&COMMANDS) {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            error("Shell Run", &format!("Unable to create prompt: {}", e));
+            return;
+        }
+    };
+    if lyrics {
+        // The full screen lyrics would clear the prompt, so they are printed above it
+        let printer = prompt.printer();
+        let display = Arc::clone(&player);
+        thread::spawn(move || show_lyrics(display, printer));
+    }
+    println!("Type 'help' for commands, 'quit' to exit.");
+    loop {
+        let Some(line) = prompt.read("mpvy> ") else {
+            // The input is closed (Ctrl-D)
+            if interactive {
+                player.stop();
+            }
+            return;
+        };
+        match execute(&player, &line) {
+            Reply::Message(message) if message.is_empty() => {}
+            Reply::Message(message) => println!("{}", message),
+            Reply::Quit => {
+                info("Shell Run", "User quit the shell.");
+                player.stop();
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::sandbox;
+
+    fn message(reply: Reply) -> String {
+        match reply {
+            Reply::Message(message) => message,
+            Reply::Quit => "quit".to_string(),
+        }
+    }
+
+    #[test]
+    fn adds_and_saves_queue() {
+        sandbox();
+        let player: Player = Player::new(Queue::new(vec![], PlayMode::default()), None);
+        message(execute(&player, "add \"Hello, Goodbye\" @0:30"));
+        message(execute(&player, "song two x2"));
+        let queries: Vec<String> = player.status().queue;
+        assert_eq!(
+            queries,
+            ["\"Hello, Goodbye\" @00:30", "song two", "song two"]
+        );
+        assert_eq!(
+            message(execute(&player, "save shell-test")),
+            "Saved 3 audio(s) as playlist 'shell-test'."
+        );
+        let items: Vec<QueueItem> = playlist::read_items("shell-test").unwrap();
+        assert_eq!(items, player.queue().items());
+        assert!(matches!(execute(&player, "quit"), Reply::Quit));
+    }
+
+    #[test]
+    fn changes_volume_in_range() {
+        assert_eq!(new_volume(50, "70"), Some(70));
+        assert_eq!(new_volume(50, "+5"), Some(55));
+        assert_eq!(new_volume(50, "-60"), Some(0));
+        assert_eq!(new_volume(50, "+18446744073709551615"), Some(100));
+        assert_eq!(new_volume(50, "loud"), None);
+    }
+
+    #[test]
+    fn checks_command_arguments() {
+        sandbox();
+        let player: Player = Player::new(Queue::new(vec![], PlayMode::default()), None);
+        assert_eq!(message(execute(&player, "add")), "Usage: add <query>");
+        assert_eq!(message(execute(&player, "save")), "Usage: save <name>");
+        assert_eq!(
+            message(execute(&player, "volume loud")),
+            "Usage: volume [0-100|+N|-N]"
+        );
+        assert!(player.queue().is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::config;
+use crate::ipc;
+use crate::log::*;
+use serde_json::{json, Value};
+
+/// Prefix of chapter titles which are created by `yt-dlp` for SponsorBlock segments,
+/// Like `[SponsorBlock]: Intermission/Intro Animation`.
+const CHAPTER_PREFIX: &str = "[SponsorBlock]";
+
+/// SponsorBlock options from the `[sponsorblock]` section of configuration.
+/// Values:
+///
+/// ```txt
+/// remove:       Categories which are cut from the audio at download time
+/// mark:         Categories which are marked as chapters and skipped live while playing
+/// api:          SponsorBlock API URL (a local stand-in server can be used for testing)
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub remove: Vec<String>,
+    pub mark: Vec<String>,
+    pub api: Option<String>,
+}
+
+/// Read SponsorBlock options from configuration.
+pub fn options() -> Options {
+    Options {
+        remove: config::get_array(config::SPONSORBLOCK_REMOVE),
+        mark: config::get_array(config::SPONSORBLOCK_MARK),
+        api: config::get_string(config::SPONSORBLOCK_API),
+    }
+}
+
+/// Returns the `yt-dlp` arguments for downloading with SponsorBlock options.
+/// Nothing is returned if there are no categories, so SponsorBlock API is not used.
+pub fn download_args(options: &Options) -> Vec<String> {
+    let mut args: Vec<String> = vec![];
+    if !options.remove.is_empty() {
+        args.push("--sponsorblock-remove".to_string());
+        args.push(options.remove.join(","));
+    }
+    if !options.mark.is_empty() {
+        args.push("--sponsorblock-mark".to_string());
+        args.push(options.mark.join(","));
+    }
+    if let Some(api) = options.api.as_ref().filter(|_| !args.is_empty()) {
+        args.push("--sponsorblock-api".to_string());
+        args.push(api.to_string());
+    }
+    args
+}
+
+/// Get the marked segments (start and end in seconds) of the playing audio from its
+/// Chapters over IPC. Returns `None` if `mpv` is not ready yet.
+pub fn segments() -> Option<Vec<(f64, f64)>> {
+    let chapters: Value = ipc::get_property("chapter-list").ok()?;
+    let chapters: &Vec<Value> = chapters.as_array()?;
+    let duration: f64 = ipc::get_property("duration").ok()?.as_f64()?;
+    let segments: Vec<(f64, f64)> = chapters
+        .iter()
+        .enumerate()
+        .filter(|(_, chapter)| {
+            chapter["title"]
+                .as_str()
+                .is_some_and(|title| title.starts_with(CHAPTER_PREFIX))
+        })
+        .filter_map(|(i, chapter)| {
+            let start: f64 = chapter["time"].as_f64()?;
+            let end: f64 = chapters
+                .get(i + 1)
+                .and_then(|next| next["time"].as_f64())
+                .unwrap_or(duration);
+            Some((start, end))
+        })
+        .collect();
+    Some(segments)
+}
+
+/// Skip the segment which contains the position by seeking to its end.
+/// Returns `true` if a segment is skipped.
+pub fn skip(segments: &[(f64, f64)], position: f64) -> bool {
+    // Don't seek again when the position is already at the end of segment
+    let Some((start, end)) = segments
+        .iter()
+        .find(|(start, end)| position >= *start && position < end - 0.5)
+    else {
+        return false;
+    };
+    info(
+        "SponsorBlock Skip",
+        &format!("Skipping segment from {:.1} to {:.1}.", start, end),
+    );
+    ipc::command(&[json!("seek"), json!(end), json!("absolute")]).is_ok()
+}
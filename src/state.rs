@@ -0,0 +1,175 @@
+use crate::log::*;
+use crate::queue::Queue;
+use crate::time::now;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// How often the session state is written to the disk while playing.
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// State of the current session. It is written to `$XDG_CONFIG_HOME/mpvy/state.json`
+/// While playing, so `mpvy --resume` can continue exactly where it stopped.
+/// Values:
+///
+/// ```txt
+/// queue:        The queue with its play mode and current audio
+/// playlist:     Name of the playing playlist (if any)
+/// title:        Title of the current audio
+/// position:     Playback position of the current audio in seconds (read over IPC)
+/// updated:      When the state is updated (seconds since Unix epoch)
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct State {
+    pub queue: Queue,
+    pub playlist: Option<String>,
+    pub title: String,
+    pub position: f64,
+    pub updated: u64,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Returns the path of session state file (`$XDG_CONFIG_HOME/mpvy/state.json`)
+pub fn state_path() -> String {
+    format!(
+        "{}/mpvy/state.json",
+        dirs::config_dir()
+            .expect("Unexpected Error: Unable to get config directory for state.")
+            .display()
+    )
+}
+
+/// Write content to a temporary file and rename it to the given path.
+/// The rename is atomic, so the file is never half-written even if **mpvy** is killed.
+pub fn write_atomic(path: &str, content: &str) -> Result<(), String> {
+    let temp: String = format!("{}.tmp", path);
+    fs::write(&temp, content).map_err(|e| e.to_string())?;
+    fs::rename(&temp, path).map_err(|e| e.to_string())
+}
+
+/// Update the session with the queue when a new audio starts playing.
+/// The state is saved immediately, because the queue doesn't change often.
+pub fn set_queue(queue: &Queue, playlist: Option<String>, title: &str) {
+    *STATE.lock().unwrap() = Some(State {
+        queue: queue.clone(),
+        playlist,
+        title: title.to_string(),
+        position: 0.0,
+        updated: now(),
+    });
+    DIRTY.store(true, Ordering::SeqCst);
+    save();
+}
+
+/// Update the playback position of the current audio. It is called by the poller of the playing
+/// Audio (refer to `src/service.rs`), also while the next audio is fetched.
+pub fn set_position(position: f64) {
+    if let Some(state) = STATE.lock().unwrap().as_mut() {
+        state.position = position;
+        state.updated = now();
+        DIRTY.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Write the session state to the disk if it is changed since the last save.
+pub fn save() {
+    if !DIRTY.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    // Don't block the player if the lock is poisoned by a panic, use the last state anyway.
+    let state = match STATE.lock() {
+        Ok(state) => state.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    let Some(state) = state else {
+        return;
+    };
+    let content: String = match serde_json::to_string_pretty(&state) {
+        Ok(content) => content,
+        Err(e) => {
+            error("State Save", &format!("Unable to serialize state: {}", e));
+            return;
+        }
+    };
+    if let Err(e) = write_atomic(&state_path(), &content) {
+        error("State Save", &format!("Unable to write state file: {}", e));
+    }
+}
+
+/// Read the saved session state. Returns `None` if there is no session to resume.
+pub fn load() -> Option<State> {
+    parse(&fs::read_to_string(state_path()).ok()?)
+}
+
+/// Parse the session state. The file can be edited or truncated, so the queue is checked
+/// Too. Returns `None` if the state is invalid.
+fn parse(content: &str) -> Option<State> {
+    let result: Result<State, String> = serde_json::from_str::<State>(content)
+        .map_err(|e| e.to_string())
+        .and_then(|state| state.queue.check().map(|_| state));
+    match result {
+        Ok(state) => Some(state),
+        Err(e) => {
+            error("State Load", &format!("State file is invalid: {}", e));
+            None
+        }
+    }
+}
+
+/// Remove the session state, when the queue ends there is nothing to resume.
+pub fn clear() {
+    *STATE.lock().unwrap() = None;
+    DIRTY.store(false, Ordering::SeqCst);
+    if fs::exists(state_path()).unwrap_or(false) {
+        let _ = fs::remove_file(state_path());
+    }
+    info("State Clear", "Session state cleared.");
+}
+
+/// Start writing the session state periodically in background.
+/// Also saves the state if **mpvy** panics, so the session is never lost.
+pub fn start_autosave() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic| {
+        DIRTY.store(true, Ordering::SeqCst);
+        save();
+        default_hook(panic);
+    }));
+
+    thread::spawn(|| loop {
+        thread::sleep(AUTOSAVE_INTERVAL);
+        save();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::sandbox;
+
+    /// A saved state with the given queue values.
+    fn content(items: &str, current: &str, played: &str) -> String {
+        format!(
+            r#"{{"queue": {{"items": {}, "mode": {{"shuffle": false, "repeat": "off"}}, "current": {}, "played": {}}}, "playlist": null, "title": "Song", "position": 12.0, "updated": 0}}"#,
+            items, current, played
+        )
+    }
+
+    #[test]
+    fn rejects_corrupt_state() {
+        sandbox();
+        let state: State = parse(&content(r#"["a", "b"]"#, "1", "[true, true]")).unwrap();
+        assert_eq!(state.queue.current(), Some(1));
+        // Played marks don't match the items
+        assert!(parse(&content(r#"["a", "b"]"#, "1", "[true]")).is_none());
+        // Current audio is not in the queue
+        assert!(parse(&content(r#"["a", "b"]"#, "2", "[true, true]")).is_none());
+        // Truncated file
+        assert!(parse(&content(r#"["a", "b"]"#, "1", "[true, true]")[..40]).is_none());
+    }
+}
@@ -0,0 +1,339 @@
+use crate::cache;
+use crate::config::Program;
+use crate::ipc;
+use crate::log::*;
+use crate::runner::Process;
+use crate::service;
+use crate::state;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fs;
+use std::io::{self, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long children are given to exit after `SIGTERM`, then they are killed.
+const GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// A child process which is started by **mpvy**.
+/// Values:
+///
+/// ```txt
+/// pid:          Process ID
+/// pidfd:        Process file descriptor, if the kernel supports it (refer to `open_pidfd`)
+/// program:      Path or name of the program (like `mpv`)
+/// rank:         Children with lower rank are terminated first (refer to `rank`)
+/// ```
+#[derive(Debug, Clone)]
+struct Tracked {
+    pid: u32,
+    pidfd: Option<Arc<OwnedFd>>,
+    program: String,
+    rank: u8,
+}
+
+/// Running children, they are terminated when **mpvy** exits.
+static CHILDREN: Mutex<Vec<Tracked>> = Mutex::new(Vec::new());
+
+/// Files which are removed when **mpvy** exits (like the daemon socket).
+static CLEANUP: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// True after shutdown starts, new children are not started anymore.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Held while shutting down, so another thread waits for the cleanup to finish before exiting.
+/// The value is `true` after the shutdown is done.
+static SHUTDOWN: Mutex<bool> = Mutex::new(false);
+
+/// Terminal settings at start, they are restored on exit (the prompt changes them).
+static TERMINAL: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// Returns the order of terminating the program: `mpv` first, so the audio stops immediately,
+/// Then `yt-dlp` and `ffmpeg`, and `cava` last, because it shows the audio of `mpv`.
+/// Commands which are not a `Program` (like `ffmpeg`) are terminated with `yt-dlp`.
+fn rank(program: Option<Program>) -> u8 {
+    match program {
+        Some(Program::Mpv) => 0,
+        Some(Program::Cava) => 2,
+        Some(Program::YtDlp | Program::Ffprobe) | None => 1,
+    }
+}
+
+/// Open a file descriptor which refers to the process. Unlike the pid, it is never reused by
+/// Another process, so the child can be signaled and checked while its owner waits for it.
+/// Returns `None` if the kernel doesn't support it (before Linux 5.3).
+fn open_pidfd(pid: u32) -> Option<Arc<OwnedFd>> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        debug(
+            "Supervisor OpenPidfd",
+            &format!("Unable to open pidfd: {}", io::Error::last_os_error()),
+        );
+        return None;
+    }
+    Some(Arc::new(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) }))
+}
+
+/// A child which is removed from the supervisor when it exits. If it is dropped while it is
+/// Running, it is killed, so no `mpv` keeps playing without **mpvy**.
+pub struct Supervised {
+    child: Child,
+}
+
+impl Process for Supervised {
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        let status: Option<ExitStatus> = self.child.try_wait()?;
+        if status.is_some() {
+            release(self.child.id());
+        }
+        Ok(status)
+    }
+
+    fn kill(&mut self) -> Result<()> {
+        self.child.kill()
+    }
+
+    fn wait(&mut self) -> Result<ExitStatus> {
+        let status: ExitStatus = self.child.wait()?;
+        release(self.child.id());
+        Ok(status)
+    }
+}
+
+impl Drop for Supervised {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            warning(
+                "Supervisor Drop",
+                &format!("Killing unwaited child (pid {}).", self.child.id()),
+            );
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        release(self.child.id());
+    }
+}
+
+/// Start the command and track its process. `program` is the program of the command (if it is
+/// One of them), it decides when the process is terminated. Returns an error if **mpvy** is exiting.
+pub fn spawn(program: Option<Program>, command: &mut Command) -> Result<Child> {
+    if is_shutting_down() {
+        return Err(io::Error::other("mpvy is exiting"));
+    }
+    let child: Child = command.spawn()?;
+    let name: String = command.get_program().to_string_lossy().to_string();
+    debug(
+        "Supervisor Spawn",
+        &format!("Started '{}' (pid {}).", name, child.id()),
+    );
+    // The child is not waited yet, so its pid can't belong to another process here
+    CHILDREN.lock().unwrap().push(Tracked {
+        pid: child.id(),
+        pidfd: open_pidfd(child.id()),
+        rank: rank(program),
+        program: name,
+    });
+    Ok(child)
+}
+
+/// Start the command like `spawn`, and kill it when the returned process is dropped.
+pub fn spawn_supervised(program: Program, command: &mut Command) -> Result<Supervised> {
+    spawn(Some(program), command).map(|child| Supervised { child })
+}
+
+/// Stop tracking the process, after it exits.
+pub fn release(pid: u32) {
+    CHILDREN.lock().unwrap().retain(|child| child.pid != pid);
+}
+
+/// Remove the file when **mpvy** exits.
+pub fn remove_on_exit(path: &str) {
+    CLEANUP.lock().unwrap().push(path.to_string());
+}
+
+/// Returns `true` if **mpvy** is exiting.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Returns `true` if the child is tracked (its owner didn't wait for it yet).
+fn tracked(pid: u32) -> bool {
+    CHILDREN
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|child| child.pid == pid)
+}
+
+/// Returns `true` if the child is running. Children are never reaped here, their owners wait
+/// For them. Without a pidfd, the child is counted as running until its owner releases it.
+fn running(child: &Tracked) -> bool {
+    let Some(pidfd) = &child.pidfd else {
+        return tracked(child.pid);
+    };
+    // A pidfd is readable after the process exits
+    let mut poll: libc::pollfd = libc::pollfd {
+        fd: pidfd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut poll, 1, 0) == 0 }
+}
+
+/// Send the signal to the child. Without a pidfd, the pid is only signaled while the child is
+/// Tracked, so a process which reuses the pid later is not signaled.
+fn signal(child: &Tracked, signal: libc::c_int) {
+    match &child.pidfd {
+        Some(pidfd) => unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                pidfd.as_raw_fd(),
+                signal,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            );
+        },
+        None if tracked(child.pid) => unsafe {
+            libc::kill(child.pid as libc::pid_t, signal);
+        },
+        None => {}
+    }
+}
+
+/// Terminate the children rank by rank. Every rank gets `SIGTERM` and `GRACE_PERIOD` to exit,
+/// Then the remaining ones get `SIGKILL`.
+fn terminate(children: &[Tracked]) {
+    for rank in 0..=2 {
+        let group: Vec<&Tracked> = children.iter().filter(|c| c.rank == rank).collect();
+        for child in &group {
+            info(
+                "Supervisor Terminate",
+                &format!("Terminating '{}' (pid {}).", child.program, child.pid),
+            );
+            signal(child, libc::SIGTERM);
+        }
+        let deadline: Instant = Instant::now() + GRACE_PERIOD;
+        while group.iter().any(|child| running(child)) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        for child in group.iter().filter(|child| running(child)) {
+            warning(
+                "Supervisor Terminate",
+                &format!("Killing '{}' (pid {}).", child.program, child.pid),
+            );
+            signal(child, libc::SIGKILL);
+        }
+    }
+}
+
+/// Stop everything which is started by **mpvy**, before exiting. In order:
+/// The playback position is saved to the session state (so `--resume` works), children are
+/// Terminated, the sockets and partial downloads are removed and the terminal is restored.
+/// It is done once, other callers wait until it is done.
+pub fn shutdown(reason: &str) {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    let mut done = SHUTDOWN
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if *done {
+        return;
+    }
+    info("Supervisor Shutdown", reason);
+
+    if let Some(position) = ipc::position() {
+        state::set_position(position);
+    }
+    state::save();
+
+    let children: Vec<Tracked> = CHILDREN.lock().unwrap().clone();
+    terminate(&children);
+
+    let mut paths: Vec<String> = CLEANUP.lock().unwrap().clone();
+    paths.push(service::ipc_path());
+    for path in paths {
+        if fs::exists(&path).unwrap_or(false) {
+            let _ = fs::remove_file(&path);
+        }
+    }
+    cache::remove_staging(); // Unfinished downloads
+
+    if let Some(terminal) = TERMINAL.lock().unwrap().as_ref() {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, terminal) };
+    }
+    *done = true;
+}
+
+/// Handle `SIGINT`, `SIGTERM` and `SIGHUP`: shut down (refer to `shutdown`) and exit with
+/// `128 + signal`, like shells do. Also remembers the terminal settings to restore them.
+pub fn install() {
+    let mut terminal: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut terminal) } == 0 {
+        *TERMINAL.lock().unwrap() = Some(terminal);
+    }
+    let mut signals: Signals = match Signals::new([SIGINT, SIGTERM, SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error(
+                "Supervisor Install",
+                &format!("Unable to handle signals: {}", e),
+            );
+            return;
+        }
+    };
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            shutdown(&format!("Received signal {}. Exiting.", signal));
+            std::process::exit(128 + signal);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::sandbox;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn ranks_programs_not_paths() {
+        sandbox();
+        // Like `mpv_path = "/opt/mpv.AppImage"`, the rank comes from the program, not the path
+        let mut child: Child = spawn(Some(Program::Mpv), Command::new("sleep").arg("30")).unwrap();
+        let rank_of_child: Option<u8> = CHILDREN
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|tracked| tracked.pid == child.id())
+            .map(|tracked| tracked.rank);
+        let _ = child.kill();
+        let _ = child.wait();
+        release(child.id());
+        assert_eq!(rank_of_child, Some(0));
+        assert_eq!(rank(None), 1);
+        assert_eq!(rank(Some(Program::Cava)), 2);
+    }
+
+    #[test]
+    fn terminates_children_without_reaping() {
+        sandbox();
+        let mut child: Child = spawn(None, Command::new("sleep").arg("30")).unwrap();
+        let tracked: Vec<Tracked> = CHILDREN
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|tracked| tracked.pid == child.id())
+            .cloned()
+            .collect();
+        assert!(running(&tracked[0]));
+        terminate(&tracked);
+        // Without a pidfd (old kernels), the child is running until it is released
+        assert_eq!(running(&tracked[0]), tracked[0].pidfd.is_none());
+        // The owner still gets the exit status, the supervisor didn't reap the child
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+        release(child.id());
+    }
+}
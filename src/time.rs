@@ -0,0 +1,78 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Returns current time as seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Format Unix timestamp as `YYYY-MM-DD HH:MM:SS` (UTC).
+/// We don't need a full date-time library for this, days are converted to civil date
+/// With the algorithm from http://howardhinnant.github.io/date_algorithms.html
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days: i64 = (timestamp / 86400) as i64;
+    let seconds: u64 = timestamp % 86400;
+
+    let z: i64 = days + 719468;
+    let era: i64 = z.div_euclid(146097);
+    let doe: i64 = z - era * 146097;
+    let yoe: i64 = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy: i64 = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp: i64 = (5 * doy + 2) / 153;
+    let day: i64 = doy - (153 * mp + 2) / 5 + 1;
+    let month: i64 = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year: i64 = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
+
+/// Format seconds as `MM:SS` or `HH:MM:SS` for showing durations to user.
+pub fn format_duration(seconds: u64) -> String {
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            (seconds % 3600) / 60,
+            seconds % 60
+        )
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Convert `yt-dlp` video duration from string to seconds.
+/// Supports both of **MM:SS** and **HH:MM:SS** formats.
+/// If an unsupported format given, returns 0.
+pub fn parse_duration(duration: &str) -> u64 {
+    let parts: Vec<u64> = duration
+        .trim()
+        .split(':')
+        .map(|part| part.parse::<u64>().unwrap_or(0))
+        .collect();
+    match parts.as_slice() {
+        [minutes, seconds] => minutes * 60 + seconds,
+        [hours, minutes, seconds] => hours * 3600 + minutes * 60 + seconds,
+        _ => 0,
+    }
+}
+
+/// Convert civil date to days since the Unix epoch (inverse of the conversion in `format_timestamp`).
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year: i64 = if month <= 2 { year - 1 } else { year };
+    let era: i64 = year.div_euclid(400);
+    let yoe: i64 = year - era * 400;
+    let mp: i64 = if month > 2 { month - 3 } else { month + 9 };
+    let doy: i64 = (153 * mp + 2) / 5 + day - 1;
+    let doe: i64 = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
@@ -1,6 +1,11 @@
-use crate::config;
-use crate::log::*;
+use crate::cache;
+use crate::config::{self, Program};
+use crate::events::{self, Event};
+use crate::log::{self, *};
+use crate::runner::CommandRunner;
+use crate::sponsorblock;
 use dirs;
+use serde::{Deserialize, Serialize};
 use std::process::Command;
 use std::str::Lines;
 
@@ -12,13 +17,35 @@ use std::str::Lines;
 /// title:        Video title
 /// url:          Video webpage url (https://youtube.com/watch?v={id})
 /// id:           Video spesific ID
+/// channel:      Video channel (uploader)
+/// chapters:     Chapters of video (like songs of a full album upload), can be empty
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VideoInfo {
     pub duration: String, // Video duration
     pub title: String,    // Video title
     pub url: String,      // Video URL
     pub id: String,       // Video ID
+    pub channel: String,  // Video channel
+    #[serde(default)]
+    pub chapters: Vec<Chapter>, // Video chapters
+}
+
+/// A chapter of video. Field names are same as the `chapters` field of `yt-dlp` JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chapter {
+    pub title: String,
+    pub start_time: f64, // In seconds
+    pub end_time: f64,   // In seconds
+}
+
+impl VideoInfo {
+    /// Returns the index of the chapter which contains the given position (in seconds).
+    pub fn chapter_at(&self, position: f64) -> Option<usize> {
+        self.chapters
+            .iter()
+            .rposition(|chapter| position >= chapter.start_time)
+    }
 }
 
 /// Returns the path of downloaded files. (`$XDG_CONFIG_HOME/mpvy/mp3`)
@@ -46,34 +73,54 @@ pub fn get_download_path() -> String {
             )
             .display()
     );
-    return path;
+    path
 }
 
 /// Get information about video with an query.
 /// Returns VideoInfo struct with given values.
-/// Uses `ytsearch:` schema for finding videos.
-pub fn get_info(query: &str) -> Result<VideoInfo, String> {
-    let output = Command::new("yt-dlp")
-        .arg(format!("ytsearch:{}", query))
+/// Uses `ytsearch:` schema for finding videos. If the query is an URL, it is used directly.
+/// The command is run with the given runner (refer to `src/runner.rs`).
+pub fn get_info(runner: &dyn CommandRunner, query: &str) -> Result<VideoInfo, String> {
+    let target: String = if query.starts_with("https://") || query.starts_with("http://") {
+        query.to_string()
+    } else {
+        format!("ytsearch:{}", query)
+    };
+    let mut command: Command = Program::YtDlp.command();
+    command
+        .arg(target)
+        .arg("--no-playlist")
         .arg("--print")
-        .arg("%(duration>%H:%M:%S)s\n%(title)s\n%(id)s\n%(webpage_url)s")
-        .output();
+        .arg(
+            "%(duration>%H:%M:%S)s\n%(title)s\n%(id)s\n%(webpage_url)s\n%(uploader)s\n%(chapters)j",
+        )
+        .args(Program::YtDlp.extra_args());
+    log::command("YoutubeDLP Info", &command);
+    let output = runner.output(&mut command);
 
     let output = match output {
         Ok(o) => o,
         Err(_) => {
             error(
                 "YoutubeDLP Info",
-                "An error occurred while executing 'yt-dlp'.",
+                "Unable to execute 'yt-dlp'. Run 'mpvy doctor' to check dependencies.",
             );
             return Err("Failed to execute 'yt-dlp'.".to_string());
         }
     };
+    log::command_exit(
+        "YoutubeDLP Info",
+        Some(output.status),
+        &String::from_utf8_lossy(&output.stderr),
+    );
 
-    if output.status.success() == false {
+    if !output.status.success() {
         error(
             "YoutubeDLP Info",
-            "Unable to get successful output. Maybe 'yt-dlp' is not installed?",
+            &format!(
+                "Unable to get successful output. Run 'mpvy doctor' to check dependencies. Command: {}",
+                log::command_line(&command)
+            ),
         );
         return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
@@ -90,54 +137,179 @@ pub fn get_info(query: &str) -> Result<VideoInfo, String> {
     let mut lines: Lines<'_> = result.lines();
     let duration: String = lines.next().unwrap_or_default().to_string();
     let title: String = lines.next().unwrap_or_default().to_string();
+    let id: String = lines.next().unwrap_or_default().to_string();
     let url: String = lines.next().unwrap_or_default().to_string();
-    let id: String = lines
-        .next()
-        .unwrap_or_default()
-        .rsplit('=')
+    let channel: String = lines.next().unwrap_or_default().to_string();
+    // Chapters are printed as JSON in one line (`NA` or `null` if there are no chapters)
+    let chapters: Vec<Chapter> = lines
         .next()
-        .unwrap_or_default()
-        .to_string();
+        .and_then(|line| serde_json::from_str::<Option<Vec<Chapter>>>(line).ok())
+        .flatten()
+        .unwrap_or_default();
     Ok(VideoInfo {
         duration,
         title,
         url,
         id,
+        channel,
+        chapters,
     })
 }
 
-/// Downloads the video with given url. Nothing more to say.
-pub fn download(url: &String) -> Result<(), String> {
+/// Get download percent from a `yt-dlp` progress line.
+/// Like: `[download]  42.3% of    3.45MiB at    1.20MiB/s ETA 00:02`
+fn parse_progress(line: &str) -> Option<f64> {
+    if !line.starts_with("[download]") {
+        return None;
+    }
+    line.split_whitespace()
+        .find_map(|word| word.strip_suffix('%'))
+        .and_then(|percent| percent.parse::<f64>().ok())
+}
+
+/// Words in brackets which are not a part of the track name, like `(Official Video)`.
+const TITLE_NOISE: [&str; 10] = [
+    "official",
+    "video",
+    "audio",
+    "lyric",
+    "visualizer",
+    "visualiser",
+    "music video",
+    "hd",
+    "4k",
+    "mv",
+];
+
+/// Guess artist and track name of the video.
+/// Titles like `Artist - Track (Official Video)` are split into `Artist` and `Track`.
+/// Otherwise the channel is used as artist (without ` - Topic` and `VEVO` suffixes).
+pub fn artist_and_track(video: &VideoInfo) -> (String, String) {
+    // Remove bracketed noise like `(Official Video)` or `[HD]`
+    let is_noise = |group: &str| {
+        let group: String = group.to_lowercase();
+        TITLE_NOISE.iter().any(|word| {
+            group
+                .split(|c: char| !c.is_alphanumeric())
+                .any(|part| part == *word)
+                || (word.contains(' ') && group.contains(word))
+        })
+    };
+    let mut cleaned: String = String::new();
+    let mut rest: &str = &video.title;
+    while let Some(start) = rest.find(['(', '[']) {
+        let close: char = if rest[start..].starts_with('(') {
+            ')'
+        } else {
+            ']'
+        };
+        let Some(length) = rest[start..].find(close) else {
+            break;
+        };
+        let end: usize = start + length + 1;
+        cleaned.push_str(&rest[..start]);
+        if !is_noise(&rest[start..end]) {
+            cleaned.push_str(&rest[start..end]);
+        }
+        rest = &rest[end..];
+    }
+    cleaned.push_str(rest);
+    let title: String = cleaned.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let title: &str = &title;
+
+    for separator in [" - ", " – ", " — "] {
+        if let Some((artist, track)) = title.split_once(separator) {
+            if !artist.trim().is_empty() && !track.trim().is_empty() {
+                return (artist.trim().to_string(), track.trim().to_string());
+            }
+        }
+    }
+    let artist: &str = video
+        .channel
+        .trim_end_matches(" - Topic")
+        .trim_end_matches("VEVO")
+        .trim();
+    (artist.to_string(), title.to_string())
+}
+
+/// Escape a literal text for the FROM part of `--parse-metadata FROM:TO`.
+/// yt-dlp only unescapes `\:`, so other backslashes are kept as they are.
+fn literal(text: &str) -> String {
+    text.replace('%', "%%").replace(':', "\\:")
+}
+
+/// Returns the `--parse-metadata` value which sets the field to the text.
+/// A FROM like `Adele` would be read as a field name, so the text is wrapped in
+/// `<field>=…;` and taken back out by the TO regex. The `;` also keeps a trailing
+/// Backslash from escaping the separator.
+fn set_field(field: &str, text: &str) -> String {
+    format!(
+        "{}={};:(?s)^{}=(?P<{}>.*);$",
+        field,
+        literal(text),
+        field,
+        field
+    )
+}
+
+/// Returns the `yt-dlp` arguments to embed metadata and thumbnail into the audio file.
+/// Title, artist, date and source URL are written as tags.
+fn metadata_args(video: &VideoInfo) -> Vec<String> {
+    let (artist, track) = artist_and_track(video);
+    vec![
+        "--embed-metadata".to_string(),
+        "--embed-thumbnail".to_string(),
+        "--convert-thumbnails".to_string(), // mp3 files can't have webp thumbnails
+        "jpg".to_string(),
+        "--parse-metadata".to_string(),
+        set_field("artist", &artist),
+        "--parse-metadata".to_string(),
+        set_field("title", &track),
+        "--parse-metadata".to_string(),
+        "webpage_url:%(meta_comment)s".to_string(), // Source URL
+    ]
+}
+
+/// Returns the directory of audio files which are split by chapters (`mp3/chapters/<id>`).
+pub fn get_chapters_path(id: &str) -> String {
+    format!("{}/chapters/{}", get_download_path(), id)
+}
+
+/// Downloads the video. Nothing more to say.
+/// The progress is sent as `download-progress` events (refer to `src/events.rs`).
+/// With `split_chapters` config, every chapter is also saved as a file to `get_chapters_path`.
+/// With `embed_metadata` config, tags and thumbnail are embedded into the file.
+pub fn download(runner: &dyn CommandRunner, video: &VideoInfo) -> Result<(), String> {
     let config = config::get_config();
     let mut quality: String = "0".to_string();
     let mut concurrent_fragments: String = "4".to_string();
+    let mut split_chapters: bool = false;
+    let mut embed_metadata: bool = false;
 
-    if config.is_ok() {
-        if config.as_ref().unwrap().contains_key(config::AUDIO_QUALITY) {
-            quality = config.clone().unwrap()[config::AUDIO_QUALITY]
-                .parse::<String>()
-                .unwrap();
+    if let Ok(config) = &config {
+        if let Some(value) = config.get(config::AUDIO_QUALITY) {
+            quality = value.to_string();
         }
 
-        if config
-            .as_ref()
-            .unwrap()
-            .contains_key(config::CONCURRENT_FRAGMENTS)
-        {
-            concurrent_fragments = config.clone().unwrap()[config::CONCURRENT_FRAGMENTS]
-                .parse::<String>()
-                .unwrap();
+        if let Some(value) = config.get(config::CONCURRENT_FRAGMENTS) {
+            concurrent_fragments = value.to_string();
         }
+
+        split_chapters = config.get(config::SPLIT_CHAPTERS).map(|v| v.as_str()) == Some("true");
+        embed_metadata = config.get(config::EMBED_METADATA).map(|v| v.as_str()) == Some("true");
     };
+    let url: &str = &video.id;
 
     info(
         "YoutubeDLP Download",
         &format!("Downloading audio: '{}'.", &url),
     );
 
-    let path: String = get_download_path();
+    // Downloaded into the staging directory, then moved to the cache (refer to `cache::commit`)
+    let path: String = cache::staging_path();
 
-    let output = Command::new("yt-dlp")
+    let mut command: Command = Program::YtDlp.command();
+    command
         .arg(url) // The URL of the video to download
         .arg("-x") // Extract audio only
         .arg("--audio-format") // Set the audio format to mp3
@@ -146,32 +318,296 @@ pub fn download(url: &String) -> Result<(), String> {
         .arg(quality)
         .arg("--no-playlist") // Disable playlist downloading, only download a single video
         .arg("--output") // Specify the output file path
-        .arg(format!("{}/%(title)s_%(id)s.%(ext)s", path)) // Path where to save the file
+        .arg(format!("{}/%(id)s.%(ext)s", path)) // Path where to save the file, refer to `cache::staging_path`
         .arg("--concurrent-fragments") // Download video fragments concurrently
         .arg(concurrent_fragments) // Number of concurrent fragments (adjust based on internet speed)
         .arg("--postprocessor-args") // Pass additional arguments to ffmpeg for processing
         .arg("ffmpeg:-preset ultrafast") // Set ffmpeg to use the ultrafast preset for faster processing
-        .output();
+        .arg("--newline") // Print progress on new lines, so we can read it line by line
+        .args(sponsorblock::download_args(&sponsorblock::options())); // Cut or mark segments
+    if split_chapters && !video.chapters.is_empty() {
+        command
+            .arg("--split-chapters") // Save chapters as separate files too
+            .arg("--output")
+            .arg(format!(
+                "chapter:{}/%(section_number)02d %(section_title)s.%(ext)s",
+                get_chapters_path("%(id)s")
+            ));
+    }
+    if embed_metadata {
+        command.args(metadata_args(video)); // Tags and thumbnail
+    }
+    command.args(Program::YtDlp.extra_args()); // Extra arguments from configuration
+    log::command("YoutubeDLP Download", &command);
 
-    let output = match output {
-        Ok(o) => o,
+    // Read the progress from output while downloading
+    let result = runner.stream(&mut command, &mut |line: &str| {
+        if let Some(percent) = parse_progress(line) {
+            events::emit(Event::DownloadProgress {
+                id: url.to_string(),
+                percent,
+            });
+        }
+    });
+    let (status, stderr) = match result {
+        Ok(result) => result,
         Err(_) => {
             error(
                 "YoutubeDLP Download",
-                "An error occurred while executing 'yt-dlp'.",
+                "Unable to execute 'yt-dlp'. Run 'mpvy doctor' to check dependencies.",
             );
             return Err("Failed to execute 'yt-dlp'.".to_string());
         }
     };
+    log::command_exit("YoutubeDLP Download", Some(status), &stderr);
 
-    if output.status.success() == false {
+    if !status.success() {
         error(
             "YoutubeDLP Download",
-            "Unable to download audio successfully. Maybe 'yt-dlp' is not installed?",
+            &format!(
+                "Unable to download audio successfully. Run 'mpvy doctor' to check dependencies. Command: {}",
+                log::command_line(&command)
+            ),
         );
-        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+        return Err(stderr);
     }
 
+    if let Err(e) = cache::commit(&video.id) {
+        error("YoutubeDLP Download", &e);
+        return Err(e);
+    }
     info("YoutubeDLP Download", "Audio downloaded successfully.");
-    return Ok(());
+    Ok(())
+}
+
+/// An entry of a playlist, channel or search, which is listed without resolving the video.
+/// Values:
+///
+/// ```txt
+/// id:           Video (or playlist) ID
+/// title:        Title of the entry
+/// duration:     Duration in seconds, `None` if it is not listed (like playlists)
+/// url:          URL of the entry
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistEntry {
+    pub id: String,
+    pub title: String,
+    pub duration: Option<f64>,
+    pub url: String,
+}
+
+/// List the entries of a playlist, channel or search (`target`) with `--flat-playlist`,
+/// So only one request is made instead of one for every video. At most `limit` entries are listed.
+pub fn list(
+    runner: &dyn CommandRunner,
+    target: &str,
+    limit: usize,
+) -> Result<Vec<PlaylistEntry>, String> {
+    let mut command: Command = Program::YtDlp.command();
+    command
+        .arg(target)
+        .arg("--flat-playlist") // Only list entries, don't resolve formats
+        .arg("--playlist-end")
+        .arg(limit.to_string())
+        .arg("--print")
+        .arg("%(id)s\t%(duration)s\t%(url)s\t%(title)s")
+        .args(Program::YtDlp.extra_args()); // Extra arguments from configuration
+    log::command("YoutubeDLP List", &command);
+    let output = runner
+        .output(&mut command)
+        .map_err(|e| format!("Unable to execute 'yt-dlp': {}", e))?;
+    if !output.status.success() {
+        let stderr: String = String::from_utf8_lossy(&output.stderr).to_string();
+        log::command_exit("YoutubeDLP List", Some(output.status), &stderr);
+        return Err(format!("'yt-dlp' is unable to list '{}'.", target));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let id: &str = fields.next()?.trim();
+            let duration: Option<f64> = fields.next()?.trim().parse::<f64>().ok();
+            let url: &str = fields.next()?.trim();
+            let title: &str = fields.next()?.trim();
+            (!id.is_empty() && id != "NA").then(|| PlaylistEntry {
+                id: id.to_string(),
+                title: title.to_string(),
+                duration,
+                url: url.to_string(),
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::{sandbox, Canned, MockRunner};
+    use std::fs;
+
+    fn video(title: &str, channel: &str) -> VideoInfo {
+        VideoInfo {
+            duration: "03:25".to_string(),
+            title: title.to_string(),
+            url: "https://www.youtube.com/watch?v=abc123".to_string(),
+            id: "abc123".to_string(),
+            channel: channel.to_string(),
+            chapters: vec![],
+        }
+    }
+
+    const PRINT: &str =
+        "%(duration>%H:%M:%S)s\n%(title)s\n%(id)s\n%(webpage_url)s\n%(uploader)s\n%(chapters)j";
+
+    #[test]
+    fn get_info_parses_output() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::ok(
+            "00:03:25\nSome Song\nabc123\nhttps://www.youtube.com/watch?v=abc123\nSome Channel\nNA\n",
+        )]);
+        let video: VideoInfo = get_info(&runner, "some song").unwrap();
+        assert_eq!(
+            runner.calls(),
+            [[
+                "yt-dlp",
+                "ytsearch:some song",
+                "--no-playlist",
+                "--print",
+                PRINT
+            ]]
+        );
+        assert_eq!(video.duration, "00:03:25");
+        assert_eq!(video.title, "Some Song");
+        assert_eq!(video.id, "abc123");
+        assert_eq!(video.url, "https://www.youtube.com/watch?v=abc123");
+        assert_eq!(video.channel, "Some Channel");
+        assert!(video.chapters.is_empty());
+    }
+
+    #[test]
+    fn get_info_parses_chapters() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::ok(concat!(
+            "01:00:00\nAlbum\nabc123\nhttps://www.youtube.com/watch?v=abc123\nBand\n",
+            r#"[{"start_time": 0.0, "title": "Intro", "end_time": 95.0}, {"start_time": 95.0, "title": "Song", "end_time": 3600.0}]"#,
+            "\n"
+        ))]);
+        let video: VideoInfo = get_info(&runner, "album").unwrap();
+        assert_eq!(video.chapters.len(), 2);
+        assert_eq!(video.chapters[1].title, "Song");
+        assert_eq!(video.chapter_at(10.0), Some(0));
+        assert_eq!(video.chapter_at(95.0), Some(1));
+    }
+
+    #[test]
+    fn get_info_uses_url_directly() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::ok("00:01\nA\nid\nurl\nB")]);
+        let _ = get_info(&runner, "https://youtu.be/abc123");
+        assert_eq!(runner.calls()[0][1], "https://youtu.be/abc123");
+    }
+
+    #[test]
+    fn get_info_fails_without_results() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::ok("\n")]);
+        assert!(get_info(&runner, "nothing").is_err());
+
+        let runner: MockRunner = MockRunner::new(vec![Canned::fail(1, "ERROR: network")]);
+        assert_eq!(get_info(&runner, "nothing").unwrap_err(), "ERROR: network");
+    }
+
+    #[test]
+    fn download_builds_command() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::ok(
+            "[download]  50.0% of 3.45MiB at 1.20MiB/s ETA 00:02\n",
+        )]);
+        // The audio which is written by `yt-dlp` is moved from the staging directory
+        fs::create_dir_all(cache::staging_path()).unwrap();
+        fs::write(format!("{}/abc123.mp3", cache::staging_path()), "").unwrap();
+        assert!(download(&runner, &video("Some Song", "Some Channel")).is_ok());
+        assert!(fs::exists(cache::audio_path("abc123")).unwrap());
+        let output: String = format!("{}/%(id)s.%(ext)s", cache::staging_path());
+        assert_eq!(
+            runner.calls(),
+            [[
+                "yt-dlp",
+                "abc123",
+                "-x",
+                "--audio-format",
+                "mp3",
+                "--audio-quality",
+                "0",
+                "--no-playlist",
+                "--output",
+                &output,
+                "--concurrent-fragments",
+                "4",
+                "--postprocessor-args",
+                "ffmpeg:-preset ultrafast",
+                "--newline",
+            ]]
+        );
+    }
+
+    #[test]
+    fn download_returns_stderr_on_failure() {
+        sandbox();
+        let runner: MockRunner = MockRunner::new(vec![Canned::fail(1, "ERROR: Video unavailable")]);
+        assert_eq!(
+            download(&runner, &video("Some Song", "Some Channel")).unwrap_err(),
+            "ERROR: Video unavailable"
+        );
+    }
+
+    #[test]
+    fn guesses_artist_and_track() {
+        let guess = |title: &str, channel: &str| artist_and_track(&video(title, channel));
+        assert_eq!(
+            guess("Daft Punk - One More Time (Official Video)", "Daft Punk"),
+            ("Daft Punk".to_string(), "One More Time".to_string())
+        );
+        assert_eq!(
+            guess("Kraftwerk – Computer Love [HD] (feat. Nobody)", "x"),
+            (
+                "Kraftwerk".to_string(),
+                "Computer Love (feat. Nobody)".to_string()
+            )
+        );
+        assert_eq!(
+            guess("Around the World", "Daft Punk - Topic"),
+            ("Daft Punk".to_string(), "Around the World".to_string())
+        );
+        assert_eq!(literal("AC/DC: 100%"), "AC/DC\\: 100%%");
+    }
+
+    #[test]
+    fn sets_single_word_fields() {
+        let video = VideoInfo {
+            duration: "3:00".to_string(),
+            title: "Hello".to_string(),
+            url: String::new(),
+            id: "abc".to_string(),
+            channel: "Adele".to_string(),
+            chapters: vec![],
+        };
+        let args = metadata_args(&video);
+        assert!(args.contains(&"artist=Adele;:(?s)^artist=(?P<artist>.*);$".to_string()));
+        assert!(args.contains(&"title=Hello;:(?s)^title=(?P<title>.*);$".to_string()));
+        assert_eq!(
+            set_field("title", "a\\: 5%\\"),
+            "title=a\\\\: 5%%\\;:(?s)^title=(?P<title>.*);$"
+        );
+    }
+
+    #[test]
+    fn parses_progress() {
+        assert_eq!(
+            parse_progress("[download]  42.3% of    3.45MiB at    1.20MiB/s ETA 00:02"),
+            Some(42.3)
+        );
+        assert_eq!(parse_progress("[ExtractAudio] Destination: a.mp3"), None);
+    }
 }
@@ -0,0 +1,1165 @@
+//! End-to-end scenarios which run the `mpvy` binary against fake `yt-dlp` and `mpv`
+//! (refer to `tests/fake/mod.rs`). Everything runs in a temporary sandbox which is used as
+//! `$XDG_CONFIG_HOME`, so no network or installed tools are needed.
+//!
+//! This test has its own harness (`harness = false` in `Cargo.toml`), because the same
+//! Binary is also used as the fake programs:
+//!
+//! ```txt
+//! cargo test --test e2e             Run all scenarios
+//! cargo test --test e2e cache       Run scenarios which have `cache` in their names
+//! ```
+
+mod fake;
+
+use serde_json::Value;
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a scenario can run before `mpvy` is killed.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A scenario with its name. Scenarios return an error message when they fail.
+type Scenario = (&'static str, fn() -> Result<(), String>);
+
+/// A call to one of the fake programs.
+struct Call {
+    program: String,
+    args: Vec<String>,
+}
+
+/// Result of running `mpvy` in a sandbox.
+struct Run {
+    status: ExitStatus,
+    stdout: String,
+}
+
+/// A temporary `$XDG_CONFIG_HOME` with fake programs configured.
+struct Sandbox {
+    dir: PathBuf,
+}
+
+impl Sandbox {
+    fn new(name: &str) -> Sandbox {
+        let dir: PathBuf =
+            env::temp_dir().join(format!("mpvy-e2e-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("mpvy/mp3")).unwrap();
+        fs::create_dir_all(dir.join("mpvy/playlist")).unwrap();
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        let exe: PathBuf = env::current_exe().unwrap();
+        symlink(&exe, dir.join("bin/yt-dlp")).unwrap();
+        symlink(&exe, dir.join("bin/mpv")).unwrap();
+        symlink(&exe, dir.join("bin/ffmpeg")).unwrap();
+        symlink(&exe, dir.join("bin/ffprobe")).unwrap();
+        let sandbox: Sandbox = Sandbox { dir };
+        sandbox.config("");
+        sandbox
+    }
+
+    /// Write the configuration file with fake program paths and the given extra lines.
+    fn config(&self, extra: &str) {
+        let content: String = format!(
+            "ytdlp_path = \"{}\"\nmpv_path = \"{}\"\n{}",
+            self.dir.join("bin/yt-dlp").display(),
+            self.dir.join("bin/mpv").display(),
+            extra
+        );
+        fs::write(self.dir.join("mpvy/config.toml"), content).unwrap();
+    }
+
+    fn mp3_dir(&self) -> PathBuf {
+        self.dir.join("mpvy/mp3")
+    }
+
+    /// Create an audio file in the mp3 directory with the given age in seconds.
+    fn add_mp3(&self, name: &str, age: u64) {
+        let file: File = File::create(self.mp3_dir().join(name)).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    /// Names of files in the mp3 directory, sorted.
+    fn mp3_files(&self) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(self.mp3_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Run `mpvy` with the given arguments, standard input and environment values.
+    fn run(&self, args: &[&str], input: &str, envs: &[(&str, &str)]) -> Run {
+        Sandbox::wait(self.start(args, input, envs))
+    }
+
+    /// Start `mpvy` like `run`, without waiting for it.
+    fn start(&self, args: &[&str], input: &str, envs: &[(&str, &str)]) -> Child {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_mpvy"))
+            .args(args)
+            .env("XDG_CONFIG_HOME", &self.dir)
+            .env("HOME", &self.dir)
+            .env("FAKE_LOG", self.dir.join("calls.jsonl"))
+            // `ffmpeg` has no path option, so it is found in `$PATH`
+            .env(
+                "PATH",
+                format!(
+                    "{}:{}",
+                    self.dir.join("bin").display(),
+                    env::var("PATH").unwrap_or_default()
+                ),
+            )
+            .envs(envs.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child
+    }
+
+    /// Wait for `mpvy` to exit (or kill it after `TIMEOUT`).
+    fn wait(mut child: Child) -> Run {
+        let started: Instant = Instant::now();
+        let status: ExitStatus = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            if started.elapsed() > TIMEOUT {
+                let _ = child.kill();
+                panic!("mpvy did not exit in {} seconds", TIMEOUT.as_secs());
+            }
+            sleep(Duration::from_millis(50));
+        };
+        let stdout: String = child
+            .wait_with_output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+            .unwrap_or_default();
+        Run { status, stdout }
+    }
+
+    /// Calls to the fake programs, in order.
+    fn calls(&self, program: &str) -> Vec<Call> {
+        self.all_calls()
+            .into_iter()
+            .filter(|call| call.program == program)
+            .collect()
+    }
+
+    /// Calls to all fake programs, in order.
+    fn all_calls(&self) -> Vec<Call> {
+        fs::read_to_string(self.dir.join("calls.jsonl"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .map(|call| Call {
+                program: call["program"].as_str().unwrap_or_default().to_string(),
+                args: call["args"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|arg| arg.as_str().unwrap_or_default().to_string())
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Downloads are `yt-dlp` calls which extract audio.
+    fn downloads(&self) -> Vec<Call> {
+        self.calls("yt-dlp")
+            .into_iter()
+            .filter(|call| call.args.iter().any(|arg| arg == "-x"))
+            .collect()
+    }
+
+    /// Audio files which are played by the fake `mpv`.
+    fn played(&self) -> Vec<String> {
+        self.calls("mpv")
+            .iter()
+            .filter_map(|call| call.args.iter().find(|arg| arg.ends_with(".mp3")))
+            .map(|path| {
+                Path::new(path)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    fn history(&self) -> Vec<Value> {
+        fs::read_to_string(self.dir.join("mpvy/history.jsonl"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .collect()
+    }
+
+    fn index(&self) -> Value {
+        fs::read_to_string(self.mp3_dir().join("index.json"))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or(Value::Null)
+    }
+
+    fn log(&self) -> String {
+        fs::read_to_string(self.dir.join("mpvy/log/mpvy.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Return an error with the message if the condition is false.
+macro_rules! check {
+    ($condition:expr, $($message:tt)+) => {
+        if !$condition {
+            return Err(format!($($message)+));
+        }
+    };
+}
+
+/// The audio is not in the cache, so it is downloaded and then played.
+fn cache_miss() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("cache-miss");
+    let run: Run = sandbox.run(&[], "song one\n", &[]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+
+    let infos: Vec<Call> = sandbox.calls("yt-dlp");
+    check!(
+        infos.first().map(|call| call.args[0].as_str()) == Some("ytsearch:song one"),
+        "yt-dlp is not called with the search query"
+    );
+    let downloads: Vec<Call> = sandbox.downloads();
+    check!(
+        downloads.len() == 1,
+        "expected 1 download, got {}",
+        downloads.len()
+    );
+    check!(
+        downloads[0].args[0] == "songone",
+        "download is called with '{}' instead of the video ID",
+        downloads[0].args[0]
+    );
+    check!(
+        sandbox.mp3_files() == ["index.json", "songone.mp3"],
+        "unexpected mp3 files: {:?}",
+        sandbox.mp3_files()
+    );
+    check!(
+        sandbox.index()["songone"]["title"] == "Song songone",
+        "title is not stored in the index: {}",
+        sandbox.index()
+    );
+    check!(
+        sandbox.played() == ["songone.mp3"],
+        "unexpected played files: {:?}",
+        sandbox.played()
+    );
+    check!(
+        sandbox
+            .calls("mpv-ipc")
+            .iter()
+            .any(|call| call.args == ["get_property", "time-pos"]),
+        "playback position is not read over IPC"
+    );
+
+    let history: Vec<Value> = sandbox.history();
+    check!(
+        history.len() == 1,
+        "expected 1 history entry, got {}",
+        history.len()
+    );
+    check!(
+        history[0]["id"] == "songone",
+        "wrong ID in history: {}",
+        history[0]["id"]
+    );
+    check!(
+        history[0]["query"] == "song one",
+        "wrong query in history: {}",
+        history[0]["query"]
+    );
+    check!(
+        history[0]["channel"] == "Channel songone",
+        "wrong channel in history: {}",
+        history[0]["channel"]
+    );
+    Ok(())
+}
+
+/// The audio is already in the cache, so it is played without downloading.
+fn cache_hit() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("cache-hit");
+    sandbox.add_mp3("songone.mp3", 0);
+    let run: Run = sandbox.run(&[], "song one\n", &[]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox.downloads().is_empty(),
+        "cached audio is downloaded again"
+    );
+    check!(
+        sandbox.played() == ["songone.mp3"],
+        "unexpected played files: {:?}",
+        sandbox.played()
+    );
+    let history: String =
+        fs::read_to_string(sandbox.dir.join("mpvy/prompt_history")).unwrap_or_default();
+    check!(
+        history.lines().last() == Some("song one"),
+        "input is not saved to the prompt history: {:?}",
+        history
+    );
+    Ok(())
+}
+
+/// `yt-dlp` fails to download, so nothing is played and the error is logged.
+fn failed_download() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("failed-download");
+    let run: Run = sandbox.run(&[], "song one\n", &[("FAKE_YTDLP_MODE", "fail-download")]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(sandbox.downloads().len() == 1, "download is not tried");
+    check!(
+        sandbox.played().is_empty(),
+        "mpv is started for a failed download"
+    );
+    check!(
+        sandbox.history().is_empty(),
+        "failed download is recorded to history"
+    );
+    check!(
+        sandbox.log().contains("Video unavailable"),
+        "yt-dlp error is not logged"
+    );
+    Ok(())
+}
+
+/// The played time in the history is counted from the start of the audio, even if the next
+/// Audio is downloaded while it is playing.
+fn history_prefetch() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("history-prefetch");
+    sandbox.add_mp3("songone.mp3", 0);
+    let run: Run = sandbox.run(
+        &[],
+        "song one, song two\n",
+        &[
+            ("FAKE_MPV_DURATION_MS", "3000"),
+            ("FAKE_YTDLP_DELAY_MS", "4000"),
+        ],
+    );
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    let history: Vec<Value> = sandbox.history();
+    check!(
+        history.len() == 2,
+        "expected 2 history entries, got {}",
+        history.len()
+    );
+    check!(
+        history[0]["played"]
+            .as_u64()
+            .is_some_and(|played| played >= 2),
+        "played time is undercounted: {}",
+        history[0]
+    );
+    Ok(())
+}
+
+/// The search has no results, so nothing is downloaded or played.
+fn empty_results() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("empty-results");
+    let run: Run = sandbox.run(&[], "nothing here\n", &[("FAKE_YTDLP_MODE", "empty")]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox.calls("yt-dlp").len() == 1,
+        "expected only the search call"
+    );
+    check!(
+        sandbox.downloads().is_empty(),
+        "download is tried without results"
+    );
+    check!(
+        sandbox.played().is_empty(),
+        "mpv is started without results"
+    );
+    check!(
+        sandbox.log().contains("Unable to find audio on YouTube."),
+        "empty result is not logged"
+    );
+    Ok(())
+}
+
+/// A saved playlist is played in order.
+fn playlist_replay() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("playlist-replay");
+    fs::write(
+        sandbox.dir.join("mpvy/playlist/mix.txt"),
+        "first song, second song",
+    )
+    .unwrap();
+    let run: Run = sandbox.run(&[], "!playlist mix\n", &[("FAKE_MPV_DURATION_MS", "600")]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(run.stdout.contains("mix"), "playlist is not listed");
+    check!(
+        sandbox.played() == ["firstsong.mp3", "secondsong.mp3"],
+        "unexpected played files: {:?}",
+        sandbox.played()
+    );
+    let ids: Vec<String> = sandbox
+        .history()
+        .iter()
+        .map(|entry| entry["id"].as_str().unwrap_or_default().to_string())
+        .collect();
+    check!(
+        ids == ["firstsong", "secondsong"],
+        "unexpected history: {:?}",
+        ids
+    );
+    Ok(())
+}
+
+/// Loudness is measured after download and the gain is applied with `mpv`'s audio filter.
+fn normalize_gain() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("normalize-gain");
+    sandbox.config("normalize = \"gain\"\n");
+    let run: Run = sandbox.run(&[], "song one\n", &[]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox.calls("ffmpeg").len() == 1,
+        "loudness is not measured"
+    );
+    check!(
+        sandbox.index()["songone"]["loudness"] == -8.0,
+        "loudness is not stored in cache index: {}",
+        sandbox.index()
+    );
+    let mpv: Vec<Call> = sandbox.calls("mpv");
+    check!(
+        mpv.first().is_some_and(|call| call
+            .args
+            .iter()
+            .any(|arg| arg == "--af=lavfi=[volume=-6.00dB]")),
+        "gain is not applied to mpv"
+    );
+    Ok(())
+}
+
+/// SponsorBlock categories are passed to `yt-dlp`, and marked segments are skipped over IPC.
+fn sponsorblock() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("sponsorblock");
+    sandbox.config(
+        "[sponsorblock]\nremove = [\"sponsor\", \"music_offtopic\"]\nmark = [\"intro\"]\napi = \"http://127.0.0.1:8080\"\n",
+    );
+    let chapters: &str = r#"[{"title": "[SponsorBlock]: Intermission/Intro Animation", "time": 0.0}, {"title": "Song", "time": 12.5}]"#;
+    let run: Run = sandbox.run(&[], "song one\n", &[("FAKE_MPV_CHAPTERS", chapters)]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+
+    let downloads: Vec<Call> = sandbox.downloads();
+    let args: &[String] = downloads
+        .first()
+        .map(|call| call.args.as_slice())
+        .unwrap_or(&[]);
+    for expected in [
+        ["--sponsorblock-remove", "sponsor,music_offtopic"],
+        ["--sponsorblock-mark", "intro"],
+        ["--sponsorblock-api", "http://127.0.0.1:8080"],
+    ] {
+        check!(
+            args.windows(2).any(|pair| pair == expected),
+            "{:?} is not passed to yt-dlp: {:?}",
+            expected,
+            args
+        );
+    }
+    check!(
+        sandbox
+            .calls("mpv-ipc")
+            .iter()
+            .any(|call| call.args == ["seek", "12.5", "absolute"]),
+        "marked segment is not skipped"
+    );
+
+    // Segments are skipped while the next audio is downloaded, which takes longer than the
+    // Playing audio
+    let sandbox: Sandbox = Sandbox::new("sponsorblock-prefetch");
+    sandbox.config("[sponsorblock]\nmark = [\"intro\"]\n");
+    sandbox.add_mp3("songone.mp3", 0);
+    let run: Run = sandbox.run(
+        &[],
+        "song one, song two\n",
+        &[
+            ("FAKE_MPV_CHAPTERS", chapters),
+            ("FAKE_YTDLP_DELAY_MS", "2000"),
+        ],
+    );
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    let calls: Vec<Call> = sandbox.all_calls();
+    let second: usize = calls
+        .iter()
+        .enumerate()
+        .filter(|(_, call)| call.program == "mpv")
+        .nth(1)
+        .map(|(i, _)| i)
+        .unwrap_or(calls.len());
+    check!(
+        calls[..second]
+            .iter()
+            .any(|call| call.program == "mpv-ipc" && call.args == ["seek", "12.5", "absolute"]),
+        "marked segment is not skipped while the next audio is downloaded"
+    );
+    Ok(())
+}
+
+/// Audios with chapters are split into chapter files with `split_chapters`.
+fn split_chapters() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("split-chapters");
+    sandbox.config("split_chapters = true\n");
+    let chapters: &str = r#"[{"start_time": 0.0, "title": "Intro", "end_time": 95.0}, {"start_time": 95.0, "title": "Song", "end_time": 205.0}]"#;
+    let run: Run = sandbox.run(&[], "album\n", &[("FAKE_YTDLP_CHAPTERS", chapters)]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    let downloads: Vec<Call> = sandbox.downloads();
+    check!(
+        downloads
+            .first()
+            .is_some_and(|call| call.args.iter().any(|arg| arg == "--split-chapters")),
+        "chapters are not split"
+    );
+
+    // Audios without chapters are not split
+    let sandbox: Sandbox = Sandbox::new("split-no-chapters");
+    sandbox.config("split_chapters = true\n");
+    sandbox.run(&[], "single\n", &[]);
+    check!(
+        sandbox
+            .downloads()
+            .first()
+            .is_some_and(|call| !call.args.iter().any(|arg| arg == "--split-chapters")),
+        "audio without chapters is split"
+    );
+    Ok(())
+}
+
+/// Old `<title>_<id>.mp3` files are renamed to `<id>.mp3` and titles are kept in the index.
+fn migration() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("migration");
+    sandbox.add_mp3("AC⧸DC: Back in Black?_pAgnJDJN4VA.mp3", 0);
+    sandbox.add_mp3("Some Title_dQw4w9WgXcQ.mp3", 0);
+    sandbox.add_mp3("dQw4w9WgXcQ.mp3", 0);
+    sandbox.add_mp3("short_id.mp3", 0);
+    let run: Run = sandbox.run(&[], "\n", &[]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox.mp3_files()
+            == [
+                "dQw4w9WgXcQ.mp3",
+                "index.json",
+                "pAgnJDJN4VA.mp3",
+                "short_id.mp3"
+            ],
+        "unexpected mp3 files after migration: {:?}",
+        sandbox.mp3_files()
+    );
+    check!(
+        sandbox.index()["pAgnJDJN4VA"]["title"] == "AC⧸DC: Back in Black?",
+        "title is not kept in the index: {}",
+        sandbox.index()
+    );
+
+    // Migrated audios are found in the cache
+    sandbox.run(&[], "pAgnJDJN4VA\n", &[]);
+    check!(
+        sandbox.downloads().is_empty(),
+        "migrated audio is downloaded again"
+    );
+    check!(
+        sandbox.played() == ["pAgnJDJN4VA.mp3"],
+        "unexpected played files: {:?}",
+        sandbox.played()
+    );
+    Ok(())
+}
+
+/// Corrupt and truncated audios, stale staging directories and leftover partial files are
+/// Removed at startup. The removed audio is downloaded again when it is played.
+fn cache_verify() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("cache-verify");
+    sandbox.add_mp3("songone.mp3", 0);
+    sandbox.add_mp3("songtwo.mp3", 0);
+    sandbox.add_mp3("songthree.mp3", 0);
+    fs::write(sandbox.mp3_dir().join("songone.mp3"), "corrupt audio").unwrap();
+    fs::write(sandbox.mp3_dir().join("songtwo.mp3"), "short audio").unwrap();
+    fs::write(
+        sandbox.mp3_dir().join("index.json"),
+        r#"{"songtwo": {"duration": "00:03:25"}, "songthree": {"duration": "00:03:25"}}"#,
+    )
+    .unwrap();
+    fs::write(sandbox.mp3_dir().join("songfour.webm.part"), "partial").unwrap();
+    let stale: PathBuf = sandbox.mp3_dir().join(".staging/999999");
+    fs::create_dir_all(&stale).unwrap();
+    fs::write(stale.join("songfive.mp3.part"), "partial").unwrap();
+
+    let run: Run = sandbox.run(&[], "song one\n", &[]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox.mp3_files() == ["index.json", "songone.mp3", "songthree.mp3"],
+        "unexpected mp3 files after verification: {:?}",
+        sandbox.mp3_files()
+    );
+    check!(
+        sandbox.downloads().len() == 1 && sandbox.played() == ["songone.mp3"],
+        "corrupt audio is not downloaded again: {:?}",
+        sandbox.played()
+    );
+    let index: Value = sandbox.index();
+    check!(
+        index.get("songtwo").is_none() && index["songthree"]["verified"].is_u64(),
+        "unexpected cache index after verification: {}",
+        index
+    );
+    Ok(())
+}
+
+/// Oldest audio files are deleted when there are more than `max_file_count`.
+fn eviction() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("eviction");
+    sandbox.config("max_file_count = 2\n");
+    sandbox.add_mp3("a_oldest.mp3", 400);
+    sandbox.add_mp3("b_old.mp3", 300);
+    sandbox.add_mp3("c_new.mp3", 200);
+    sandbox.add_mp3("d_newest.mp3", 100);
+    // The index is not an audio, so it is not counted or deleted
+    fs::write(
+        sandbox.mp3_dir().join("index.json"),
+        r#"{"a_oldest": {"loudness": -9.0}, "d_newest": {"loudness": -10.0}}"#,
+    )
+    .unwrap();
+    let run: Run = sandbox.run(&[], "\n", &[]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox.mp3_files() == ["c_new.mp3", "d_newest.mp3", "index.json"],
+        "unexpected mp3 files after eviction: {:?}",
+        sandbox.mp3_files()
+    );
+    let index: Value = sandbox.index();
+    check!(
+        index.get("a_oldest").is_none() && index.get("d_newest").is_some(),
+        "unexpected cache index after eviction: {}",
+        index
+    );
+    Ok(())
+}
+
+/// Lyrics are made from YouTube subtitles, stored in the index and shown while playing.
+fn lyrics_subtitles() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("lyrics-subtitles");
+    sandbox.config("lyrics = \"auto\"\n");
+    let subtitles: &str = "WEBVTT\n\n00:00:00.000 --> 00:00:05.000\nFirst line of song\n\n00:00:05.000 --> 00:00:09.000\nSecond line\n";
+    let run: Run = sandbox.run(&[], "song one\n", &[("FAKE_YTDLP_SUBS", subtitles)]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox
+            .calls("yt-dlp")
+            .iter()
+            .any(|call| call.args.iter().any(|arg| arg == "--write-auto-subs")),
+        "subtitles are not downloaded"
+    );
+    check!(
+        sandbox.index()["songone"]["lyrics"][1]["text"] == "Second line",
+        "lyrics are not stored in the index: {}",
+        sandbox.index()
+    );
+    check!(
+        run.stdout.contains("> First line of song"),
+        "current line is not shown"
+    );
+    check!(
+        sandbox.mp3_files() == ["index.json", "lyrics", "songone.mp3"],
+        "unexpected mp3 files: {:?}",
+        sandbox.mp3_files()
+    );
+    check!(
+        fs::read_dir(sandbox.mp3_dir().join("lyrics")).is_ok_and(|mut dir| dir.next().is_none()),
+        "subtitle files are not removed"
+    );
+    Ok(())
+}
+
+/// A `.lrc` file next to the cached audio is used without asking YouTube.
+fn lyrics_lrc() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("lyrics-lrc");
+    sandbox.config("lyrics = \"local\"\n");
+    sandbox.add_mp3("songone.mp3", 0);
+    fs::write(
+        sandbox.mp3_dir().join("songone.lrc"),
+        "[ti:Song]\n[00:00.00]Local first line\n[00:30.00]Local second line\n",
+    )
+    .unwrap();
+    let run: Run = sandbox.run(&[], "song one\n", &[]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox.calls("yt-dlp").len() == 1,
+        "expected only the search call"
+    );
+    check!(
+        sandbox.index()["songone"]["lyrics"][0]["text"] == "Local first line",
+        "lyrics are not stored in the index: {}",
+        sandbox.index()
+    );
+    check!(
+        run.stdout.contains("> Local first line"),
+        "current line is not shown"
+    );
+    Ok(())
+}
+
+/// With autoplay, related tracks are added before the queue ends, without repeating
+/// Played tracks. It stops when there are no new related tracks.
+fn autoplay() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("autoplay");
+    sandbox.config("autoplay = true\n");
+    let run: Run = sandbox.run(&[], "song one\n", &[("FAKE_MPV_DURATION_MS", "600")]);
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox
+            .calls("yt-dlp")
+            .iter()
+            .any(|call| call.args[0] == "https://www.youtube.com/watch?v=songone&list=RDsongone"),
+        "mix playlist of the first audio is not used"
+    );
+    check!(
+        sandbox.played() == ["songone.mp3", "radioone.mp3", "radiotwo.mp3"],
+        "unexpected played files: {:?}",
+        sandbox.played()
+    );
+    Ok(())
+}
+
+/// Prefixed queries are expanded into the videos of the artist or album, and filtered
+/// By the `[search]` configuration.
+fn search_prefixes() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("search-prefixes");
+    sandbox.config("[search]\nexclude = [\"TWO\"]\n");
+    let run: Run = sandbox.run(
+        &[],
+        "artist:some band, album:first record\n",
+        &[("FAKE_MPV_DURATION_MS", "600")],
+    );
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    let targets: Vec<String> = sandbox
+        .calls("yt-dlp")
+        .into_iter()
+        .filter(|call| call.args.iter().any(|arg| arg == "--flat-playlist"))
+        .map(|call| call.args[0].clone())
+        .collect();
+    check!(
+        targets
+            == [
+                "https://music.youtube.com/search?q=some+band#songs",
+                "https://music.youtube.com/search?q=first+record#albums",
+                "https://www.youtube.com/playlist?list=albumone"
+            ],
+        "unexpected listed targets: {:?}",
+        targets
+    );
+    check!(
+        sandbox.played() == ["radioone.mp3", "radioone.mp3"],
+        "unexpected played files: {:?}",
+        sandbox.played()
+    );
+    Ok(())
+}
+
+/// Quoted commas stay in the query, and modifiers change how items are played.
+fn query_syntax() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("query-syntax");
+    sandbox.add_mp3("songtwo.mp3", 0);
+    fs::write(
+        sandbox.mp3_dir().join("index.json"),
+        r#"{"songtwo": {"title": "Song Two (Official Audio)", "channel": "Channel songtwo"}}"#,
+    )
+    .unwrap();
+    let run: Run = sandbox.run(
+        &[],
+        "\"Hello, Goodbye\" @0:30 x2, song two !local\n",
+        &[("FAKE_MPV_DURATION_MS", "600")],
+    );
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    let searches: Vec<String> = sandbox
+        .calls("yt-dlp")
+        .into_iter()
+        .filter(|call| call.args.iter().any(|arg| arg == "--print"))
+        .map(|call| call.args[0].clone())
+        .collect();
+    check!(
+        searches == ["ytsearch:Hello, Goodbye", "ytsearch:Hello, Goodbye"],
+        "unexpected searches (a '!local' item is searched online?): {:?}",
+        searches
+    );
+    check!(
+        sandbox.played() == ["HelloGoodbye.mp3", "HelloGoodbye.mp3", "songtwo.mp3"],
+        "unexpected played files: {:?}",
+        sandbox.played()
+    );
+    let starts: Vec<bool> = sandbox
+        .calls("mpv")
+        .iter()
+        .map(|call| call.args.iter().any(|arg| arg == "--start=30"))
+        .collect();
+    check!(
+        starts == [true, true, false],
+        "start position is not applied: {:?}",
+        starts
+    );
+    let history: Vec<Value> = sandbox.history();
+    check!(
+        history
+            .last()
+            .is_some_and(|entry| entry["channel"] == "Channel songtwo"),
+        "cached information is not used for the '!local' item: {:?}",
+        history.last()
+    );
+    Ok(())
+}
+
+/// Commands of the shell are read while the queue is playing.
+fn shell() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("shell");
+    let run: Run = sandbox.run(
+        &[],
+        "song one\nadd song two\nsave mix\nvolume 50\n",
+        &[("FAKE_MPV_DURATION_MS", "600")],
+    );
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        sandbox.played() == ["songone.mp3", "songtwo.mp3"],
+        "audio added from the shell is not played: {:?}",
+        sandbox.played()
+    );
+    let playlist: String =
+        fs::read_to_string(sandbox.dir.join("mpvy/playlist/mix.txt")).unwrap_or_default();
+    check!(
+        playlist == "song one\nsong two",
+        "unexpected saved playlist: {:?}",
+        playlist
+    );
+    check!(
+        run.stdout.contains("Saved 2 audio(s) as playlist 'mix'."),
+        "save command is not answered: {}",
+        run.stdout
+    );
+    let volumes: Vec<bool> = sandbox
+        .calls("mpv")
+        .iter()
+        .map(|call| call.args.iter().any(|arg| arg == "--volume=50"))
+        .collect();
+    check!(
+        volumes.last() == Some(&true),
+        "volume is not given to the next audio: {:?}",
+        volumes
+    );
+    Ok(())
+}
+
+/// `quit` stops the playing audio and exits without playing the rest of the queue.
+fn shell_quit() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("shell-quit");
+    let started: Instant = Instant::now();
+    let run: Run = sandbox.run(
+        &[],
+        "song one, song two\nquit\n",
+        &[("FAKE_MPV_DURATION_MS", "10000")],
+    );
+    check!(run.status.success(), "mpvy exited with {}", run.status);
+    check!(
+        started.elapsed() < Duration::from_secs(5),
+        "mpvy waited for the audio after quit ({:?})",
+        started.elapsed()
+    );
+    check!(
+        !sandbox.played().contains(&"songtwo.mp3".to_string()),
+        "the queue is played after quit: {:?}",
+        sandbox.played()
+    );
+    Ok(())
+}
+
+/// Returns `true` if a process which has the given text in its command line is running.
+fn process_running(text: &str) -> bool {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        fs::read(entry.path().join("cmdline"))
+            .map(|cmdline| String::from_utf8_lossy(&cmdline).contains(text))
+            .unwrap_or(false)
+    })
+}
+
+/// `mpvy daemon` doesn't start while a foreground `mpvy` listens on the daemon socket, and
+/// Says so instead of reporting a running daemon. Another foreground doesn't start either,
+/// And `mpvy stop` doesn't stop the foreground.
+fn daemon_conflict() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("daemon-conflict");
+    sandbox.add_mp3("songone.mp3", 0);
+    let started: Instant = Instant::now();
+    let child: Child = sandbox.start(&[], "song one\n", &[("FAKE_MPV_DURATION_MS", "20000")]);
+    while !sandbox.dir.join("mpvy/mpvyd.socket").exists() && started.elapsed() < TIMEOUT {
+        sleep(Duration::from_millis(50));
+    }
+    let run: Run = sandbox.run(&["daemon"], "", &[]);
+    let second: Run = sandbox.run(&[], "song one\n", &[]);
+    let stop: Run = sandbox.run(&["stop"], "", &[]);
+    sleep(Duration::from_millis(200));
+    let alive: bool = sandbox.dir.join("mpvy/mpvyd.socket").exists();
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    Sandbox::wait(child);
+    check!(
+        !second.status.success() && second.stdout.contains("Another mpvy is playing"),
+        "second foreground started: {}",
+        second.stdout
+    );
+    check!(
+        !stop.status.success() && alive,
+        "'mpvy stop' stopped the foreground: {}",
+        stop.stdout
+    );
+    check!(
+        !run.status.success(),
+        "mpvyd started next to the foreground"
+    );
+    check!(
+        run.stdout.contains("A foreground mpvy is playing"),
+        "unexpected output: {}",
+        run.stdout
+    );
+    Ok(())
+}
+
+/// `mpvy status --json` prints the playing audio, the queue and the play mode of the running
+/// Player for status bar scripts.
+fn status_json() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("status-json");
+    sandbox.add_mp3("songone.mp3", 0);
+    let started: Instant = Instant::now();
+    let child: Child = sandbox.start(&[], "song one\n", &[("FAKE_MPV_DURATION_MS", "20000")]);
+    while sandbox.played().is_empty() && started.elapsed() < TIMEOUT {
+        sleep(Duration::from_millis(50));
+    }
+    let run: Run = sandbox.run(&["status", "--json"], "", &[]);
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    Sandbox::wait(child);
+    let status: Value = serde_json::from_str(run.stdout.trim()).unwrap_or_default();
+    check!(
+        status["playing"]["id"].is_string() && status["playing"]["title"].is_string(),
+        "no playing audio in status: {}",
+        run.stdout
+    );
+    check!(
+        status["queue"] == serde_json::json!(["song one"]) && status["index"] == 0,
+        "unexpected queue in status: {}",
+        run.stdout
+    );
+    check!(
+        status["mode"] == serde_json::json!({"shuffle": false, "repeat": "off"}),
+        "unexpected play mode in status: {}",
+        run.stdout
+    );
+    Ok(())
+}
+
+/// Kill processes which have the given text in their command line, like fake programs which
+/// Are left running after `mpvy` is killed.
+fn kill_processes(text: &str) {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(pid) = entry.file_name().to_string_lossy().parse::<libc::pid_t>() else {
+            continue;
+        };
+        if fs::read(entry.path().join("cmdline"))
+            .is_ok_and(|cmdline| String::from_utf8_lossy(&cmdline).contains(text))
+        {
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+    }
+}
+
+/// If `mpvy` is killed while the next audio is downloaded, the saved session state has the
+/// Position of the playing audio, so `--resume` continues close to it.
+fn crash_resume() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("crash-resume");
+    sandbox.add_mp3("songone.mp3", 0);
+    let started: Instant = Instant::now();
+    let child: Child = sandbox.start(
+        &[],
+        "song one, song two\n",
+        &[
+            ("FAKE_MPV_DURATION_MS", "15000"),
+            ("FAKE_YTDLP_DELAY_MS", "15000"),
+        ],
+    );
+    while sandbox.played().is_empty() && started.elapsed() < TIMEOUT {
+        sleep(Duration::from_millis(50));
+    }
+    // The state is written every 5 seconds
+    sleep(Duration::from_millis(6500));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGKILL) };
+    Sandbox::wait(child);
+    kill_processes(&sandbox.dir.display().to_string());
+    let state: Value = fs::read_to_string(sandbox.dir.join("mpvy/state.json"))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    check!(
+        state["position"]
+            .as_f64()
+            .is_some_and(|position| position >= 3.0),
+        "position is stale in the saved state: {}",
+        state
+    );
+    Ok(())
+}
+
+/// `SIGTERM` stops `mpv`, keeps the session for `--resume` and removes the sockets.
+fn signal_shutdown() -> Result<(), String> {
+    let sandbox: Sandbox = Sandbox::new("signal-shutdown");
+    sandbox.add_mp3("songone.mp3", 0);
+    let started: Instant = Instant::now();
+    let child: Child = sandbox.start(
+        &[],
+        "song one, song two\n",
+        &[("FAKE_MPV_DURATION_MS", "20000")],
+    );
+    while sandbox.played().is_empty() && started.elapsed() < TIMEOUT {
+        sleep(Duration::from_millis(50));
+    }
+    // Let the session state get the position
+    sleep(Duration::from_millis(1000));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    let run: Run = Sandbox::wait(child);
+    check!(
+        run.status.code() == Some(143),
+        "mpvy exited with {}",
+        run.status
+    );
+    check!(
+        started.elapsed() < Duration::from_secs(10),
+        "mpvy waited for the audio after SIGTERM ({:?})",
+        started.elapsed()
+    );
+    let audio: String = sandbox.mp3_dir().join("songone.mp3").display().to_string();
+    check!(!process_running(&audio), "mpv is still playing after exit");
+    check!(
+        !sandbox.dir.join("mpv/socket").exists() && !sandbox.dir.join("mpvy/mpvyd.socket").exists(),
+        "sockets are not removed"
+    );
+    let state: Value = fs::read_to_string(sandbox.dir.join("mpvy/state.json"))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    check!(
+        state["position"]
+            .as_f64()
+            .is_some_and(|position| position > 0.0),
+        "position is not saved to the state: {}",
+        state
+    );
+    check!(
+        !sandbox.played().contains(&"songtwo.mp3".to_string()),
+        "the queue is played after SIGTERM: {:?}",
+        sandbox.played()
+    );
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    // The binary is also the fake programs, refer to `tests/fake/mod.rs`.
+    let program: Option<String> = args
+        .first()
+        .and_then(|arg| Path::new(arg).file_name())
+        .map(|name| name.to_string_lossy().to_string());
+    match program.as_deref() {
+        Some("yt-dlp") => std::process::exit(fake::yt_dlp(&args[1..])),
+        Some("mpv") => std::process::exit(fake::mpv(&args[1..])),
+        Some("ffmpeg") => std::process::exit(fake::ffmpeg(&args[1..])),
+        Some("ffprobe") => std::process::exit(fake::ffprobe(&args[1..])),
+        _ => {}
+    }
+
+    let scenarios: [Scenario; 23] = [
+        ("cache_miss", cache_miss),
+        ("cache_hit", cache_hit),
+        ("failed_download", failed_download),
+        ("history_prefetch", history_prefetch),
+        ("empty_results", empty_results),
+        ("playlist_replay", playlist_replay),
+        ("normalize_gain", normalize_gain),
+        ("sponsorblock", sponsorblock),
+        ("split_chapters", split_chapters),
+        ("migration", migration),
+        ("eviction", eviction),
+        ("cache_verify", cache_verify),
+        ("lyrics_subtitles", lyrics_subtitles),
+        ("lyrics_lrc", lyrics_lrc),
+        ("autoplay", autoplay),
+        ("search_prefixes", search_prefixes),
+        ("query_syntax", query_syntax),
+        ("shell", shell),
+        ("shell_quit", shell_quit),
+        ("signal_shutdown", signal_shutdown),
+        ("daemon_conflict", daemon_conflict),
+        ("status_json", status_json),
+        ("crash_resume", crash_resume),
+    ];
+    // Arguments which are not flags (like `--nocapture`) filter scenarios by name.
+    let filters: Vec<&String> = args[1..]
+        .iter()
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+    let selected: Vec<&Scenario> = scenarios
+        .iter()
+        .filter(|(name, _)| filters.is_empty() || filters.iter().any(|f| name.contains(f.as_str())))
+        .collect();
+
+    println!("\nrunning {} tests", selected.len());
+    let mut failed: Vec<(&str, String)> = vec![];
+    for (name, scenario) in &selected {
+        match scenario() {
+            Ok(()) => println!("test {} ... ok", name),
+            Err(e) => {
+                println!("test {} ... FAILED", name);
+                failed.push((name, e));
+            }
+        }
+    }
+    for (name, e) in &failed {
+        println!("\n---- {} ----\n{}", name, e);
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed\n",
+        if failed.is_empty() { "ok" } else { "FAILED" },
+        selected.len() - failed.len(),
+        failed.len()
+    );
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,278 @@
+//! Scripted stand-ins for `yt-dlp`, `mpv`, `ffmpeg` and `ffprobe`. The test binary symlinks itself as
+//! `bin/yt-dlp`, `bin/mpv`, `bin/ffmpeg` and `bin/ffprobe` in the sandbox, and `main` dispatches here on the
+//! Program name.
+//! Every call is appended to `$FAKE_LOG` as a JSON line, so scenarios can assert them.
+//!
+//! Environment values:
+//!
+//! ```txt
+//! FAKE_LOG:               Path of the calls log (JSON lines)
+//! FAKE_YTDLP_MODE:        `ok` (default), `empty` (no search results) or `fail-download`
+//! FAKE_YTDLP_CHAPTERS:    Chapters of videos as `yt-dlp` JSON. Default: `NA` (no chapters)
+//! FAKE_YTDLP_SUBS:        WebVTT subtitles which are written for `--skip-download`. Default: no subtitles
+//! FAKE_YTDLP_DELAY_MS:    How long a download takes. Default: `0`
+//! FAKE_MPV_DURATION_MS:   How long the fake mpv "plays" an audio. Default: `1200`
+//! FAKE_MPV_CHAPTERS:      Chapters of audios as JSON (`chapter-list` property). Default: `[]`
+//! ```
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Append a call to `$FAKE_LOG`.
+fn record(program: &str, args: &[String]) {
+    let Ok(path) = env::var("FAKE_LOG") else {
+        return;
+    };
+    let line: String = json!({ "program": program, "args": args }).to_string();
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = file.write_all(format!("{}\n", line).as_bytes());
+    }
+}
+
+/// Video ID of a query, like `song one` -> `songone`. URLs use their `v=` parameter.
+fn video_id(target: &str) -> String {
+    if let Some((_, id)) = target.split_once("v=") {
+        return id.split('&').next().unwrap_or_default().to_string();
+    }
+    target
+        .trim_start_matches("ytsearch:")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+}
+
+/// Title of a video. It is derived from ID, so searches and downloads agree on it.
+fn video_title(id: &str) -> String {
+    format!("Song {}", id)
+}
+
+/// Fake `yt-dlp`: prints video information for `--print`, related videos for `--flat-playlist`, writes an empty mp3 for downloads
+/// And subtitles for `--skip-download`.
+pub fn yt_dlp(args: &[String]) -> i32 {
+    record("yt-dlp", args);
+    let mode: String = env::var("FAKE_YTDLP_MODE").unwrap_or("ok".to_string());
+    let target: &str = args.first().map(|arg| arg.as_str()).unwrap_or_default();
+    let id: String = video_id(target);
+
+    // Mixes (which start with the seed video), channels and searches always list the same
+    // Related videos. Album searches list one album, which is a playlist of them.
+    if args.iter().any(|arg| arg == "--flat-playlist") {
+        let template: &str = args
+            .iter()
+            .position(|arg| arg == "--print")
+            .and_then(|i| args.get(i + 1))
+            .map(|arg| arg.as_str())
+            .unwrap_or("%(id)s");
+        let entry = |id: &str, url: &str| {
+            println!(
+                "{}",
+                template
+                    .replace("%(id)s", id)
+                    .replace("%(duration)s", "205.0")
+                    .replace("%(url)s", url)
+                    .replace("%(title)s", &video_title(id))
+            );
+        };
+        if target.ends_with("#albums") {
+            entry("albumone", "https://www.youtube.com/playlist?list=albumone");
+            return 0;
+        }
+        if target.contains("list=RD") {
+            entry(&id, &format!("https://www.youtube.com/watch?v={}", id));
+        }
+        for related in ["radioone", "radiotwo"] {
+            entry(
+                related,
+                &format!("https://www.youtube.com/watch?v={}", related),
+            );
+        }
+        return 0;
+    }
+
+    if args.iter().any(|arg| arg == "--print") {
+        if mode == "empty" {
+            return 0;
+        }
+        println!("00:03:25");
+        println!("{}", video_title(&id));
+        println!("{}", id);
+        println!("https://www.youtube.com/watch?v={}", id);
+        println!("Channel {}", id);
+        println!(
+            "{}",
+            env::var("FAKE_YTDLP_CHAPTERS").unwrap_or("NA".to_string())
+        );
+        return 0;
+    }
+
+    let Some(template) = args
+        .iter()
+        .position(|arg| arg == "--output")
+        .and_then(|i| args.get(i + 1))
+    else {
+        eprintln!("ERROR: no --output given");
+        return 2;
+    };
+    let output = |ext: &str| -> String {
+        template
+            .replace("%(title)s", &video_title(&id))
+            .replace("%(id)s", &id)
+            .replace("%(ext)s", ext)
+    };
+
+    if args.iter().any(|arg| arg == "--skip-download") {
+        let Ok(subtitles) = env::var("FAKE_YTDLP_SUBS") else {
+            return 0; // The video has no subtitles
+        };
+        let path: String = output("en.vtt");
+        if let Some(parent) = Path::new(&path).parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        return if fs::write(&path, subtitles).is_ok() {
+            0
+        } else {
+            1
+        };
+    }
+
+    if mode == "fail-download" {
+        eprintln!("ERROR: [youtube] {}: Video unavailable", id);
+        return 1;
+    }
+    let delay: u64 = env::var("FAKE_YTDLP_DELAY_MS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(0);
+    thread::sleep(Duration::from_millis(delay));
+    let path: String = output("mp3");
+    for percent in ["0.0", "50.0", "100.0"] {
+        println!(
+            "[download]  {}% of    3.45MiB at    1.20MiB/s ETA 00:01",
+            percent
+        );
+    }
+    if let Some(parent) = Path::new(&path).parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    match fs::write(&path, "fake audio") {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("ERROR: unable to write '{}': {}", path, e);
+            1
+        }
+    }
+}
+
+/// Reply to a single JSON IPC request of the fake `mpv`.
+fn reply(request: &Value, started: Instant) -> Value {
+    let command: Vec<Value> = request["command"].as_array().cloned().unwrap_or_default();
+    let args: Vec<String> = command
+        .iter()
+        .map(|arg| arg.as_str().map(String::from).unwrap_or(arg.to_string()))
+        .collect();
+    record("mpv-ipc", &args);
+    let data: Value = match args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>()[..] {
+        ["get_property", "time-pos"] => json!(started.elapsed().as_secs_f64()),
+        ["get_property", "pause"] => json!(false),
+        ["get_property", "duration"] => json!(205.0),
+        ["get_property", "chapter-list"] => env::var("FAKE_MPV_CHAPTERS")
+            .ok()
+            .and_then(|chapters| serde_json::from_str(&chapters).ok())
+            .unwrap_or(json!([])),
+        ["set_property", ..] | ["seek", ..] => Value::Null,
+        ["quit"] => Value::Null,
+        _ => return json!({ "error": "invalid parameter", "request_id": request["request_id"] }),
+    };
+    json!({ "data": data, "error": "success", "request_id": request["request_id"] })
+}
+
+/// Serve a JSON IPC connection. Events are sent before replies, like real `mpv` does.
+fn serve(stream: UnixStream, started: Instant) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    let _ = writeln!(writer, "{}", json!({ "event": "playback-restart" }));
+    for line in BufReader::new(stream).lines().map_while(Result::ok) {
+        let Ok(request) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        let _ = writeln!(writer, "{}", reply(&request, started));
+        if request["command"][0] == "quit" {
+            std::process::exit(0);
+        }
+    }
+}
+
+/// Fake `mpv`: "plays" the audio for a while and serves JSON IPC on `--input-ipc-server`.
+pub fn mpv(args: &[String]) -> i32 {
+    record("mpv", args);
+    let duration: u64 = env::var("FAKE_MPV_DURATION_MS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(1200);
+    let started: Instant = Instant::now();
+    let socket: Option<&str> = args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--input-ipc-server="));
+
+    let listener: Option<UnixListener> = socket.and_then(|socket| {
+        let _ = fs::remove_file(socket);
+        if let Some(parent) = Path::new(socket).parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let listener = UnixListener::bind(socket).ok()?;
+        listener.set_nonblocking(true).ok()?;
+        Some(listener)
+    });
+
+    while started.elapsed() < Duration::from_millis(duration) {
+        if let Some(listener) = &listener {
+            if let Ok((stream, _)) = listener.accept() {
+                let _ = stream.set_nonblocking(false);
+                thread::spawn(move || serve(stream, started));
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    if let Some(socket) = socket {
+        let _ = fs::remove_file(socket);
+    }
+    0
+}
+
+/// Fake `ffmpeg`: prints a `loudnorm` measurement of -8 LUFS to stderr.
+pub fn ffmpeg(args: &[String]) -> i32 {
+    record("ffmpeg", args);
+    eprintln!("[Parsed_loudnorm_0 @ 0x5581]");
+    eprintln!("{}", json!({ "input_i": "-8.00", "input_tp": "0.42" }));
+    0
+}
+
+/// Fake `ffprobe`: prints a duration of 205 seconds. Audios whose content starts with `corrupt`
+/// Can't be decoded, and the ones which start with `short` are 10 seconds long.
+pub fn ffprobe(args: &[String]) -> i32 {
+    record("ffprobe", args);
+    let content: String = args
+        .last()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .unwrap_or_default();
+    if content.starts_with("corrupt") {
+        eprintln!("Invalid data found when processing input");
+        return 1;
+    }
+    println!(
+        "{}",
+        if content.starts_with("short") {
+            "10.000000"
+        } else {
+            "205.000000"
+        }
+    );
+    0
+}